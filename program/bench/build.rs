@@ -0,0 +1,15 @@
+use sp1_build::{build_program_with_args, BuildArgs};
+
+fn main() {
+    println!("Building ZK program with cycle tracking...");
+
+    // not a docker build: cycle counts don't depend on the build being reproducible,
+    // and the profiling ELF is never proven
+    let args = BuildArgs {
+        features: vec!["profiling".to_string()],
+        // relative to the program. not program/elf, which is the ELF the relayer proves
+        output_directory: "./bench/elf".to_string(),
+        ..Default::default()
+    };
+    build_program_with_args("..", args);
+}
@@ -0,0 +1,340 @@
+use serde::{Deserialize, Serialize};
+use sp1_sdk::{ProverClient, SP1Stdin};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use untron_program::fixtures::Fixture;
+use untron_program::migration::encode_state;
+use untron_program::testing::*;
+use untron_program::{Action, RawBlock, State, BLOCK_TIME, ORDER_TTL};
+
+// RATIONALE:
+// proving cost is linear in zkVM cycles, so this executes the program (built with the "profiling" feature)
+// in SP1's executor over synthetic workloads with one kind of transaction each, and over the recorded
+// mainnet fixtures, and reports cycles per block, per transaction of each kind, and per phase of stf.
+// a run can be saved as a baseline, and later runs compared against it to catch regressions.
+
+const ELF: &[u8] = include_bytes!("../elf/riscv32im-succinct-zkvm-elf");
+
+// the smallest execution stf accepts
+const BLOCKS: u64 = ORDER_TTL + 20;
+
+// transactions in every block of a synthetic workload
+const TXS_PER_BLOCK: usize = 50;
+
+// an action far in the future, see program/src/stf_tests.rs
+const FUTURE: u64 = TEST_START_TIMESTAMP + 1_000_000_000;
+
+// receiver of the order that's active during synthetic workloads
+const RECEIVER: [u8; 20] = [0xaa; 20];
+
+// baseline the runs are compared against, committed to the repo
+const BASELINE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/baseline.json");
+
+// default allowed growth of cycles per block over the baseline, in percent
+const DEFAULT_TOLERANCE: f64 = 2.0;
+
+struct Workload {
+    name: String,
+    state: State,
+    actions: Vec<Action>,
+    blocks: Vec<RawBlock>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Measurement {
+    blocks: u64,
+    txs: u64,
+    cycles: u64,
+    cycles_per_block: u64,
+    // cycles per transaction on top of the cycles of an empty block
+    cycles_per_tx: u64,
+    // cycles per block spent in each cycle-tracked phase (see program/src/lib.rs)
+    phases: BTreeMap<String, u64>,
+}
+
+// synthetic builds a workload of BLOCKS blocks with `txs_per_block` transactions from `tx` each,
+// with an order for RECEIVER active throughout it
+fn synthetic(name: &str, txs_per_block: usize, tx: impl Fn(usize) -> Vec<u8>) -> Workload {
+    let mut chain = TestChain::new(TEST_START_BLOCK);
+    let state = chain.state();
+    let mut actions = ActionChain::new(state.action_chain);
+
+    let (open, _) = actions.action(TEST_START_TIMESTAMP, RECEIVER, 0, u64::MAX);
+    let (future, _) = actions.action(FUTURE, [0xff; 20], 0, 0);
+
+    let blocks = (0..BLOCKS)
+        .map(|_| chain.block((0..txs_per_block).map(&tx).collect()))
+        .collect();
+
+    Workload {
+        name: name.to_string(),
+        state,
+        actions: vec![open, future],
+        blocks,
+    }
+}
+
+// sender returns a distinct address for the i-th transaction in a block
+fn sender(i: usize) -> [u8; 20] {
+    let mut address = [0x11; 20];
+    address[..8].copy_from_slice(&(i as u64).to_be_bytes());
+    address
+}
+
+// trx_transfer builds a TransferContract transaction, the most common transaction on Tron
+fn trx_transfer(from: [u8; 20], to: [u8; 20]) -> Vec<u8> {
+    let mut transfer = len_field(1, &tron_address(from));
+    transfer.extend(len_field(2, &tron_address(to)));
+    transfer.extend(varint_field(3, 1_000_000));
+    transaction(
+        1,
+        "type.googleapis.com/protocol.TransferContract",
+        &transfer,
+        CONTRACT_SUCCESS,
+    )
+}
+
+fn synthetic_workloads() -> Vec<Workload> {
+    vec![
+        synthetic("empty blocks", 0, |_| unreachable!()),
+        synthetic("trx transfers", TXS_PER_BLOCK, |i| {
+            trx_transfer(sender(i), [0x22; 20])
+        }),
+        synthetic("usdt transfers", TXS_PER_BLOCK, |i| {
+            usdt_transfer(sender(i), [0x22; 20], 1_000_000)
+        }),
+        synthetic("usdt transfers to orders", TXS_PER_BLOCK, |i| {
+            usdt_transfer(sender(i), RECEIVER, 1_000_000)
+        }),
+        synthetic("usdt transferFroms", TXS_PER_BLOCK, |i| {
+            usdt_transfer_from(sender(i), [0x33; 20], [0x22; 20], 1_000_000)
+        }),
+        synthetic("failed usdt transfers", TXS_PER_BLOCK, |i| {
+            failed(usdt_transfer(sender(i), RECEIVER, 1_000_000))
+        }),
+        synthetic("votes", TXS_PER_BLOCK, |i| {
+            vote_tx(sender(i), &[([0x44; 20], 1000), ([0x55; 20], 2000)])
+        }),
+    ]
+}
+
+// fixture_workloads builds workloads from the mainnet fixtures that can be run through stf
+// (see program/tests/mainnet_fixtures.rs)
+fn fixture_workloads() -> Result<Vec<Workload>, Box<dyn Error>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../fixtures");
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "json"));
+    paths.sort();
+
+    let mut workloads = vec![];
+    for path in paths {
+        let fixture: Fixture = serde_json::from_slice(&fs::read(&path)?)?;
+        if !fixture.replay_stf {
+            continue;
+        }
+
+        let first = &fixture.blocks[0];
+        let producers: BTreeSet<[u8; 20]> = fixture
+            .blocks
+            .iter()
+            .map(|block| block.witness_address)
+            .collect();
+
+        let mut state = State {
+            latest_block_id: first.parent_id,
+            latest_timestamp: first.timestamp - BLOCK_TIME,
+            ..Default::default()
+        };
+        for (sr, producer) in state.srs.iter_mut().zip(producers) {
+            *sr = producer;
+        }
+
+        let mut actions = ActionChain::new(state.action_chain);
+        let (future, _) = actions.action(u64::MAX, [0xff; 20], 0, 0);
+
+        workloads.push(Workload {
+            name: format!("mainnet: {}", fixture.description),
+            state,
+            actions: vec![future],
+            blocks: fixture
+                .blocks
+                .iter()
+                .map(|block| RawBlock {
+                    raw_data: block.raw_data.clone(),
+                    signature: block.witness_signature.clone(),
+                    txs: block.txs.iter().map(|tx| tx.raw.clone()).collect(),
+                })
+                .collect(),
+        });
+    }
+
+    Ok(workloads)
+}
+
+// measure executes the profiling ELF over the workload and returns its cycle counts.
+// `empty_block_cycles` is what an empty block costs, to get the cycles per transaction
+fn measure(
+    client: &ProverClient,
+    workload: &Workload,
+    empty_block_cycles: u64,
+) -> Result<Measurement, Box<dyn Error>> {
+    let serialized_state = encode_state(&workload.state);
+    let serialized_actions = bincode::serialize(&workload.actions)?;
+    let serialized_blocks = bincode::serialize(&workload.blocks)?;
+
+    let mut stdin = SP1Stdin::new();
+    stdin.write_vec(serialized_state.clone());
+    stdin.write_vec(serialized_actions.clone());
+    stdin.write_vec(serialized_blocks.clone());
+    let (public_values, report) = client.execute(ELF, stdin).run()?;
+
+    // make sure we measured a valid execution
+    let expected =
+        untron_program::execute(&serialized_state, &serialized_actions, &serialized_blocks);
+    if public_values.as_slice() != expected.as_slice() {
+        return Err(format!("{}: zkVM and native public values differ", workload.name).into());
+    }
+
+    let blocks = workload.blocks.len() as u64;
+    let txs = workload
+        .blocks
+        .iter()
+        .map(|block| block.txs.len() as u64)
+        .sum();
+    let cycles = report.total_instruction_count();
+
+    Ok(Measurement {
+        blocks,
+        txs,
+        cycles,
+        cycles_per_block: cycles / blocks,
+        cycles_per_tx: cycles.saturating_sub(empty_block_cycles * blocks) / txs.max(1),
+        phases: report
+            .cycle_tracker
+            .iter()
+            .map(|(phase, cycles)| (phase.clone(), cycles / blocks))
+            .collect(),
+    })
+}
+
+fn print_measurement(name: &str, measurement: &Measurement) {
+    println!(
+        "{:<40} {:>6} {:>8} {:>14} {:>10}",
+        name,
+        measurement.blocks,
+        measurement.txs,
+        measurement.cycles_per_block,
+        measurement.cycles_per_tx
+    );
+    for (phase, cycles) in &measurement.phases {
+        println!("    {:<36} {:>30}", phase, cycles);
+    }
+}
+
+// compare prints the change against the baseline and returns the workloads that got slower than the tolerance,
+// or that aren't in the baseline at all, since nothing would catch their regressions
+fn compare(
+    baseline: &BTreeMap<String, Measurement>,
+    measurements: &BTreeMap<String, Measurement>,
+    tolerance: f64,
+) -> Vec<String> {
+    let mut regressions = vec![];
+
+    println!();
+    println!(
+        "{:<40} {:>14} {:>14} {:>8}",
+        "vs baseline", "before", "after", "change"
+    );
+    for (name, measurement) in measurements {
+        let Some(before) = baseline.get(name) else {
+            println!(
+                "{:<40} {:>14} {:>14}",
+                name, "-", measurement.cycles_per_block
+            );
+            regressions.push(name.clone());
+            continue;
+        };
+
+        let change =
+            (measurement.cycles_per_block as f64 / before.cycles_per_block as f64 - 1.0) * 100.0;
+        println!(
+            "{:<40} {:>14} {:>14} {:>+7.2}%",
+            name, before.cycles_per_block, measurement.cycles_per_block, change
+        );
+
+        if change > tolerance {
+            regressions.push(name.clone());
+        }
+    }
+
+    regressions
+}
+
+// usage: untron-program-bench [--save-baseline] [--tolerance <percent>]
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let save_baseline = args.iter().any(|arg| arg == "--save-baseline");
+    let tolerance = match args.iter().position(|arg| arg == "--tolerance") {
+        Some(i) => args
+            .get(i + 1)
+            .ok_or("--tolerance needs a value")?
+            .parse()?,
+        None => DEFAULT_TOLERANCE,
+    };
+
+    let client = ProverClient::new();
+
+    let mut workloads = synthetic_workloads();
+    workloads.extend(fixture_workloads()?);
+
+    println!(
+        "{:<40} {:>6} {:>8} {:>14} {:>10}",
+        "workload", "blocks", "txs", "cycles/block", "cycles/tx"
+    );
+
+    // the first workload is the empty one, all the others are measured against it
+    let mut measurements = BTreeMap::new();
+    let mut empty_block_cycles = 0;
+    for workload in &workloads {
+        let measurement = measure(&client, workload, empty_block_cycles)?;
+        if measurements.is_empty() {
+            empty_block_cycles = measurement.cycles_per_block;
+        }
+
+        print_measurement(&workload.name, &measurement);
+        measurements.insert(workload.name.clone(), measurement);
+    }
+
+    if save_baseline {
+        fs::write(BASELINE, serde_json::to_string_pretty(&measurements)?)?;
+        println!("\nSaved baseline to {}", BASELINE);
+        return Ok(());
+    }
+
+    let baseline = fs::read(BASELINE).map_err(|e| {
+        format!(
+            "can't read the baseline {}: {}, save one with --save-baseline and commit it",
+            BASELINE, e
+        )
+    })?;
+
+    let regressions = compare(
+        &serde_json::from_slice(&baseline)?,
+        &measurements,
+        tolerance,
+    );
+    if !regressions.is_empty() {
+        return Err(format!(
+            "cycles per block grew by more than {}% (or there's no baseline) in: {}",
+            tolerance,
+            regressions.join(", ")
+        )
+        .into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,17 @@
+// compiles the same java-tron protos as the relayer (see relayer/build.rs),
+// but only the messages the program parses, so that googleapis isn't needed
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!(
+        "Building Tron protocol... If it fails, make sure you initialized submodules in this repo."
+    );
+
+    prost_build::compile_protos(
+        &[
+            "../../lib/java-tron/protocol/src/main/protos/core/Tron.proto",
+            "../../lib/java-tron/protocol/src/main/protos/core/contract/smart_contract.proto",
+            "../../lib/java-tron/protocol/src/main/protos/core/contract/witness_contract.proto",
+        ],
+        &["../../lib/java-tron/protocol/src/main/protos"],
+    )?;
+    Ok(())
+}
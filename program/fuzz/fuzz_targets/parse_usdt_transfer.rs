@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// transactions come from blocks signed by the SRs, but the parser must not panic on anything,
+// or a single weird transaction would make every proof containing its block fail
+fuzz_target!(|data: &[u8]| {
+    let _ = untron_program::protobuf::parse_usdt_transfer(data);
+});
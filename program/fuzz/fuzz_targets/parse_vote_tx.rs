@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// see parse_usdt_transfer.rs
+fuzz_target!(|data: &[u8]| {
+    let _ = untron_program::protobuf::parse_vote_tx(data);
+});
@@ -0,0 +1,230 @@
+use prost::Message;
+use untron_program::{crypto, protobuf};
+
+// RATIONALE:
+// the program parses Tron protobufs by hand (see program/src/protobuf.rs),
+// so here we check it against prost, decoding the same bytes with the java-tron protos.
+// both get the bytes as they are. protobuf takes the last value of a singular field that's there
+// more than once, while the hand-written parsers ignore such transactions (java-tron never encodes
+// one, see find_field), so whatever they read must be what prost reads, and they must read
+// everything prost reads from a transaction that's encoded like java-tron does it.
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/protocol.rs"));
+}
+
+use proto::{
+    block_header, transaction::contract::ContractType, Transaction, TriggerSmartContract,
+    VoteWitnessContract,
+};
+
+// USDT smart contract TR7NHqjeKQxGTCi8q8ZY4pL8otSzgjLj6t
+const USDT_CONTRACT: [u8; 21] = [
+    0x41, 0xa6, 0x14, 0xf8, 0x03, 0xb6, 0xfd, 0x78, 0x09, 0x86, 0xa4, 0x2c, 0x78, 0xec, 0x9c, 0x7f,
+    0x77, 0xe6, 0xde, 0xd1, 0x3c,
+];
+
+// transfer(address,uint256)
+const TRANSFER_SELECTOR: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+
+// Transaction.Result.contractResult.SUCCESS
+const CONTRACT_SUCCESS: i32 = 1;
+
+// check_varint checks try_read_varint against prost on the varint at the start of data
+pub fn check_varint(data: &[u8]) {
+    // must not panic on anything
+    let read = protobuf::try_read_varint(data);
+
+    let mut buf = data;
+    match prost::encoding::decode_varint(&mut buf) {
+        Ok(expected) => assert_eq!(read, Some((expected, data.len() - buf.len()))),
+        // prost also rejects varints with bits past 64, which we discard like protobuf-java
+        Err(_) => {
+            if let Some((_, length)) = read {
+                assert!(length >= 10);
+            }
+        }
+    }
+}
+
+// check_block_header checks parse_block_header against prost on a BlockHeader.raw.
+// parse_block_header asserts that the header has all the fields a mainnet header has
+// (a panic there only fails the proof), so headers without them are skipped
+pub fn check_block_header(data: &[u8]) {
+    let Ok(raw) = block_header::Raw::decode(data) else {
+        return;
+    };
+    if raw.timestamp == 0
+        || raw.number == 0
+        || raw.tx_trie_root.len() != 32
+        || raw.parent_hash.len() != 32
+    {
+        return;
+    }
+
+    let raw_data = raw.encode_to_vec();
+    let hash = crypto::hash(&raw_data);
+    let prev_block_id = raw.parent_hash.as_slice().try_into().unwrap();
+
+    let header = protobuf::parse_block_header(prev_block_id, &raw_data, hash);
+    assert_eq!(header.timestamp, raw.timestamp as u64);
+    assert_eq!(header.tx_root.as_slice(), raw.tx_trie_root.as_slice());
+    assert_eq!(header.new_block_id[..8], (raw.number as u64).to_be_bytes());
+    assert_eq!(header.new_block_id[8..], hash[8..]);
+}
+
+// check_transaction checks parse_usdt_transfer and parse_vote_tx against prost on a Transaction
+pub fn check_transaction(data: &[u8]) {
+    // must not panic either way
+    let transfer = protobuf::parse_usdt_transfer(data).map(|t| (t.to, t.value));
+    let vote = protobuf::parse_vote_tx(data).map(|vote_tx| {
+        let votes: Vec<_> = vote_tx
+            .votes
+            .iter()
+            .map(|vote| (vote.witness_address, vote.votes_count))
+            .collect();
+        (vote_tx.voter, votes)
+    });
+
+    let Ok(tx) = Transaction::decode(data) else {
+        return;
+    };
+    // prost can't decode the contract, so there's nothing to compare with
+    // (java-tron would never accept such a transaction)
+    let Some(parameter) = decode_parameter(&tx) else {
+        return;
+    };
+    let expected_transfer = expected_usdt_transfer(&tx, &parameter);
+    let expected_vote = expected_vote(&tx, &parameter);
+
+    // what the parsers read is what java-tron reads
+    if transfer.is_some() {
+        assert_eq!(transfer, expected_transfer);
+    }
+    if vote.is_some() {
+        assert_eq!(vote, expected_vote);
+    }
+
+    // and they read all of it from a transaction encoded like java-tron does it.
+    // prost encodes fields in order, each at most once, and drops unknown ones
+    if is_canonical(data, &tx, &parameter) {
+        assert_eq!(transfer, expected_transfer);
+        assert_eq!(vote, expected_vote);
+    }
+}
+
+// Parameter is the decoded contract parameter of a transfer or vote transaction,
+// which prost keeps as opaque bytes in google.protobuf.Any
+enum Parameter {
+    Trigger(TriggerSmartContract),
+    VoteWitness(VoteWitnessContract),
+    Other,
+}
+
+// decode_parameter decodes the contract parameter of the transaction.
+// None if it can't be decoded
+fn decode_parameter(tx: &Transaction) -> Option<Parameter> {
+    let Some(contract) = tx
+        .raw_data
+        .as_ref()
+        .and_then(|raw_data| raw_data.contract.first())
+    else {
+        return Some(Parameter::Other);
+    };
+    let Some(parameter) = contract.parameter.as_ref() else {
+        return Some(Parameter::Other);
+    };
+
+    let value = parameter.value.as_slice();
+    if contract.r#type == ContractType::TriggerSmartContract as i32 {
+        TriggerSmartContract::decode(value).ok().map(Parameter::Trigger)
+    } else if contract.r#type == ContractType::VoteWitnessContract as i32 {
+        VoteWitnessContract::decode(value).ok().map(Parameter::VoteWitness)
+    } else {
+        Some(Parameter::Other)
+    }
+}
+
+// is_canonical tells whether the transaction is encoded like prost encodes it, parameter included
+fn is_canonical(data: &[u8], tx: &Transaction, parameter: &Parameter) -> bool {
+    if tx.encode_to_vec() != data {
+        return false;
+    }
+    let value = tx
+        .raw_data
+        .as_ref()
+        .and_then(|raw_data| raw_data.contract.first())
+        .and_then(|contract| contract.parameter.as_ref())
+        .map(|parameter| parameter.value.as_slice());
+    match (parameter, value) {
+        (Parameter::Trigger(trigger), Some(value)) => trigger.encode_to_vec() == value,
+        (Parameter::VoteWitness(vote_witness), Some(value)) => {
+            vote_witness.encode_to_vec() == value
+        }
+        _ => true,
+    }
+}
+
+// successful tells whether the transaction succeeded and its contract has a parameter
+fn successful(tx: &Transaction) -> Option<()> {
+    if tx.ret.first()?.contract_ret != CONTRACT_SUCCESS {
+        return None;
+    }
+    tx.raw_data.as_ref()?.contract.first()?.parameter.as_ref()?;
+    Some(())
+}
+
+// address strips the 0x41 prefix from a Tron address
+fn address(tron_address: &[u8]) -> Option<[u8; 20]> {
+    if tron_address.len() != 21 {
+        return None;
+    }
+    tron_address[1..].try_into().ok()
+}
+
+fn expected_usdt_transfer(tx: &Transaction, parameter: &Parameter) -> Option<([u8; 20], u64)> {
+    successful(tx)?;
+    let Parameter::Trigger(trigger) = parameter else {
+        return None;
+    };
+    if trigger.contract_address != USDT_CONTRACT {
+        return None;
+    }
+
+    // the EVM reads missing calldata as zeros
+    let mut calldata = [0u8; 68];
+    let length = trigger.data.len().min(68);
+    calldata[..length].copy_from_slice(&trigger.data[..length]);
+    if calldata[..4] != TRANSFER_SELECTOR {
+        return None;
+    }
+
+    let to = calldata[16..36].try_into().unwrap();
+    let value = if calldata[36..60].iter().any(|byte| *byte != 0) {
+        u64::MAX
+    } else {
+        u64::from_be_bytes(calldata[60..68].try_into().unwrap())
+    };
+
+    Some((to, value))
+}
+
+#[allow(clippy::type_complexity)]
+fn expected_vote(
+    tx: &Transaction,
+    parameter: &Parameter,
+) -> Option<([u8; 20], Vec<([u8; 20], u64)>)> {
+    successful(tx)?;
+    let Parameter::VoteWitness(vote_witness) = parameter else {
+        return None;
+    };
+
+    let voter = address(&vote_witness.owner_address)?;
+    let votes = vote_witness
+        .votes
+        .iter()
+        .map(|vote| Some((address(&vote.vote_address)?, vote.vote_count as u64)))
+        .collect::<Option<Vec<_>>>()?;
+
+    Some((voter, votes))
+}
@@ -36,39 +36,47 @@ pub fn recover_public_key(sig: &[u8], msg_hash: [u8; 32]) -> Vec<u8> {
 
 // combine two hashes into one
 fn combine_hashes(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
-    let mut combined = [0u8; 64];
-    combined[..32].copy_from_slice(&left);
-    combined[32..].copy_from_slice(&right);
-    hash(&combined)
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+
+    let mut result = [0u8; 32];
+    result.copy_from_slice(&hasher.finalize());
+    result
 }
 
-// construct a merkle tree from a list of hashes and return the root hash
-pub fn create_merkle_tree(leaves: &[[u8; 32]]) -> [u8; 32] {
+// compute the root of a merkle tree from a list of hashes.
+// every level is written over the previous one, so that the leaves
+// (e.g. tx hashes stf computed anyway) are reused and no memory is allocated
+pub fn merkle_root(mut hashes: Vec<[u8; 32]>) -> [u8; 32] {
     // it's like this in Tron source code
-    if leaves.is_empty() {
+    if hashes.is_empty() {
         return [0u8; 32];
     }
 
     // it's a binary merkle tree, so we can just keep combining the hashes in pairs
-    let mut current_level = leaves.to_vec();
+    let mut level_len = hashes.len();
 
     // keep combining the hashes in pairs until we get the root hash
-    while current_level.len() > 1 {
-        let mut next_level = Vec::new();
-
-        for chunk in current_level.chunks(2) {
-            if chunk.len() == 2 {
-                next_level.push(combine_hashes(chunk[0], chunk[1]));
-            } else {
-                next_level.push(chunk[0]);
-            }
+    while level_len > 1 {
+        for i in 0..level_len / 2 {
+            hashes[i] = combine_hashes(hashes[2 * i], hashes[2 * i + 1]);
+        }
+        // the odd hash out goes to the next level as is
+        if level_len % 2 == 1 {
+            hashes[level_len / 2] = hashes[level_len - 1];
         }
 
-        current_level = next_level;
+        level_len = level_len.div_ceil(2);
     }
 
     // the root hash is the only hash left in the current level
-    current_level[0]
+    hashes[0]
+}
+
+// construct a merkle tree from a list of hashes and return the root hash
+pub fn create_merkle_tree(leaves: &[[u8; 32]]) -> [u8; 32] {
+    merkle_root(leaves.to_vec())
 }
 
 // convert a public key to a Tron address
@@ -82,3 +90,41 @@ pub fn public_key_to_address(public_key: &[u8]) -> [u8; 20] {
     address.copy_from_slice(&hasher.finalize()[12..]);
     address
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the tree the way Tron builds it, one level after another
+    fn naive_merkle_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+        if leaves.is_empty() {
+            return [0u8; 32];
+        }
+
+        let mut level = leaves.to_vec();
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|chunk| match chunk {
+                    [left, right] => hash(&[*left, *right].concat()),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+        level[0]
+    }
+
+    #[test]
+    fn merkle_root_matches_level_by_level_tree() {
+        for count in 0..40u8 {
+            let leaves: Vec<[u8; 32]> = (0..count).map(|i| hash(&[i])).collect();
+            assert_eq!(
+                merkle_root(leaves.clone()),
+                naive_merkle_root(&leaves),
+                "{}",
+                count
+            );
+        }
+    }
+}
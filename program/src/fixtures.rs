@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+
+// RATIONALE:
+// the hand-written parsers in protobuf.rs can only be really tested against real Tron data,
+// so we keep a corpus of recorded mainnet blocks in program/fixtures (one Fixture per JSON file)
+// and replay them in program/tests/mainnet_fixtures.rs.
+// fixtures are recorded by the relayer (`untron-relayer record-fixtures`),
+// which decodes the blocks with prost from the java-tron protos,
+// so the expected values are independent from our parser.
+
+// Fixture is a range of consecutive mainnet blocks
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Fixture {
+    // what's interesting about these blocks (e.g. "maintenance period boundary")
+    pub description: String,
+    // whether the blocks can be run through stf as a single execution:
+    // there are more than ORDER_TTL + 19 of them, they're produced by exactly 27 SRs,
+    // and there's no maintenance period in them (the program only knows the votes it has seen itself)
+    #[serde(default)]
+    pub replay_stf: bool,
+    pub blocks: Vec<FixtureBlock>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FixtureBlock {
+    pub number: u64,
+    #[serde(with = "hex")]
+    pub id: [u8; 32],
+    #[serde(with = "hex")]
+    pub parent_id: [u8; 32],
+    pub timestamp: u64,
+    #[serde(with = "hex")]
+    pub tx_root: [u8; 32],
+    // without 0x41 prefix
+    #[serde(with = "hex")]
+    pub witness_address: [u8; 20],
+    #[serde(with = "hex")]
+    pub raw_data: Vec<u8>,
+    #[serde(with = "hex")]
+    pub witness_signature: Vec<u8>,
+    pub txs: Vec<FixtureTx>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FixtureTx {
+    // the whole Transaction message, as it's hashed into the block's tx root
+    #[serde(with = "hex")]
+    pub raw: Vec<u8>,
+    // set only for successful USDT transfer() calls
+    #[serde(default)]
+    pub usdt_transfer: Option<FixtureTransfer>,
+    // set only for successful VoteWitnessContract transactions
+    #[serde(default)]
+    pub vote: Option<FixtureVote>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FixtureTransfer {
+    #[serde(with = "hex")]
+    pub to: [u8; 20],
+    pub value: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FixtureVote {
+    #[serde(with = "hex")]
+    pub voter: [u8; 20],
+    pub votes: Vec<FixtureVoteCount>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FixtureVoteCount {
+    #[serde(with = "hex")]
+    pub witness_address: [u8; 20],
+    pub votes_count: u64,
+}
@@ -1,8 +1,46 @@
+// cycle_tracker_start and cycle_tracker_end mark a span of the program for SP1's cycle tracker.
+// spans with the same name are summed up in the execution report (see bench/).
+// printing costs cycles too, so they're only compiled with the "profiling" feature
+#[cfg(feature = "profiling")]
+macro_rules! cycle_tracker_start {
+    ($name:literal) => {
+        println!(concat!("cycle-tracker-report-start: ", $name))
+    };
+}
+
+#[cfg(feature = "profiling")]
+macro_rules! cycle_tracker_end {
+    ($name:literal) => {
+        println!(concat!("cycle-tracker-report-end: ", $name))
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+macro_rules! cycle_tracker_start {
+    ($name:literal) => {};
+}
+
+#[cfg(not(feature = "profiling"))]
+macro_rules! cycle_tracker_end {
+    ($name:literal) => {};
+}
+
 pub mod crypto;
+#[cfg(feature = "test-utils")]
+pub mod fixtures;
+pub mod migration;
 pub mod protobuf;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
+
+#[cfg(test)]
+mod stf_tests;
+
+use std::collections::{BTreeMap, HashMap};
 
-use std::collections::HashMap;
+use protobuf::RelevantTx;
 
+use alloy_sol_types::{sol, SolType};
 use serde::{Deserialize, Serialize};
 
 // how long the program will look for order's receiver address in the transactions of a block
@@ -18,10 +56,34 @@ pub const BLOCK_TIME: u64 = 3000; // milliseconds
 // this number is guaranteed to work for the last few months
 pub const MAINTENANCE_PERIOD_BLOCK_OFFSET: u32 = 1387;
 
+// maximum number of actions that can wait in the state for their Tron block to be proven.
+// actions only wait for the finality delay (19 blocks) plus the relayer's lag,
+// so hitting this means that the relayer feeds actions way ahead of the blocks.
+// the state is re-hashed and passed into every proof, so stf doesn't take more actions than that,
+// and the relayer feeds the rest again with later blocks
+pub const MAX_PENDING_ACTIONS: usize = 1000;
+
 // how often maintenance period happens.
 // in docs it's 7200, but actually it's 7198 blocks because maintenance window skips two blocks
 pub const MAINTENANCE_PERIOD_INTERVAL: u32 = 7198;
 
+// UntronPublicValues are the public input (output) of the Untron program.
+// Must be encoded as defined in the smart contracts.
+// Format:
+// - old_block_id: [u8; 32] (block id of the previous latest zk proven block in the Tron blockchain)
+// - new_block_id: [u8; 32] (block id of the latest zk proven block in the Tron blockchain after applying the execution)
+
+// - old_action_chain: [u8; 32] (chained hash of all performed actions in the Untron contract before applying the execution)
+// - new_action_chain: [u8; 32] (chained hash of all performed actions in the Untron contract after applying the execution)
+
+// - old_state_hash: [u8; 32] (hash of the previous state of the Untron program)
+// - new_state_hash: [u8; 32] (hash of the new state of the Untron program after applying the execution)
+
+// - closed_orders: Vec<(bytes32, uint64)> (list of all orders that must be closed in the Untron contract after applying the execution)
+pub type UntronPublicValues = sol! {
+    tuple(bytes32,bytes32,bytes32,bytes32,bytes32,bytes32,(bytes32,uint64)[])
+};
+
 // Action is the format of the action data that's needed for the program, chained with the previous action
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Action {
@@ -33,7 +95,8 @@ pub struct Action {
 }
 // sol! can't work with Serialize and Deserialize
 impl Action {
-    fn abi_encode(&self) -> Vec<u8> {
+    // abi_encode encodes the action the way the contract hashes it into the action chain
+    pub fn abi_encode(&self) -> Vec<u8> {
         let mut encoded = Vec::new();
         encoded.extend_from_slice(&self.prev);
         encoded.extend_from_slice(&[0u8; 24]);
@@ -55,7 +118,8 @@ pub struct OrderState {
     pub address: [u8; 20],
     // timestamp when the order was created in Tron format (not unix timestamp)
     pub timestamp: u64,
-    // how much USDT was deposited to the Tron address above
+    // how much USDT was deposited to the Tron address above.
+    // saturates at u64::MAX, see add_inflow
     pub inflow: u64,
     // minimum amount of USDT transfer for it to be accepted
     // (e.g. if it's 1 USDT, the program won't count 0.5 USDT transfers to the address above)
@@ -64,7 +128,48 @@ pub struct OrderState {
     pub size: u64,
 }
 
-// State is the state of the Untron program
+impl OrderState {
+    // add_inflow adds a USDT transfer to the order's inflow and returns whether the order is now filled.
+    // inflow is passed to the smart contract as the amount to pay out for the order,
+    // so it must never wrap: a wrapped inflow would close the order for a fraction of what was sent.
+    // instead, it saturates at u64::MAX. this is safe because the contract never pays out more than the order size,
+    // and a saturated inflow is >= any size, so the order is closed right away.
+    pub fn add_inflow(&mut self, value: u64) -> bool {
+        self.inflow = self.inflow.saturating_add(value);
+        self.inflow >= self.size
+    }
+}
+
+// add_votes adds votes for the SR candidate to the vote tally.
+// tallies saturate at u64::MAX instead of wrapping, so that nobody can "overflow" a candidate out of the top 27.
+// all TRX in existence can't get close to that, so saturation can only be hit by a broken (or malicious) input
+pub fn add_votes(votes: &mut BTreeMap<[u8; 20], u64>, witness_address: [u8; 20], count: u64) {
+    // zero votes don't change the tally, so we don't waste an entry (and state space) on them
+    if count == 0 {
+        return;
+    }
+
+    let tally = votes.entry(witness_address).or_insert(0);
+    *tally = tally.saturating_add(count);
+}
+
+// State is the state of the Untron program.
+// its hash is stored in the smart contract, so it must always serialize into the same bytes:
+// that's why we use BTreeMap instead of HashMap here.
+// it's stored in a versioned envelope, so every change to it needs a migration (see migration.rs)
+//
+// RATIONALE:
+// the whole state is passed into every proof, so every part of it that grows with usage needs a bound:
+// - pending_actions is capped at MAX_PENDING_ACTIONS by stf itself, since only the relayer decides
+//   how far ahead of the blocks it feeds actions.
+// - orders hold at most one order per receiver: a new action for a receiver closes its old order
+//   (see apply_action), and so do a filled order and a freed receiver. receivers are registered
+//   by providers in the contract, which is where their number is limited.
+//   the program can't drop an order on its own, since the deposits to its receiver would be lost.
+// - votes only count successful VoteWitnessContract txs, and java-tron only accepts votes for
+//   SR candidates, so there's at most one tally per candidate, and each one costs its owner TRX to register.
+//   zero votes aren't tallied, and all tallies are reset at every maintenance period.
+// orders and votes are reported in the relayer's logs (see parts_size) instead of being capped.
 #[derive(Default, Serialize, Deserialize, Clone, Debug)]
 pub struct State {
     // id of the latest zk proven block in the Tron blockchain
@@ -76,15 +181,40 @@ pub struct State {
     // list of all SRs (super representatives) in the Tron blockchain
     pub srs: [[u8; 20]; 27],
     // votes for SRs
-    pub votes: HashMap<[u8; 20], u64>,
+    pub votes: BTreeMap<[u8; 20], u64>,
     // all actions that are not yet executed (because their mapped Tron block was not executed yet)
     pub pending_actions: Vec<(Action, [u8; 32])>,
     // all currently active orders in the Untron protocol
-    pub orders: HashMap<[u8; 32], OrderState>,
+    pub orders: BTreeMap<[u8; 32], OrderState>,
     // chained hash of all actions in the Untron protocol
     pub action_chain: [u8; 32],
 }
 
+impl State {
+    // serialized_size returns the size of the encoded state (see migration.rs) in bytes.
+    // the whole state is passed into every proof, so this is what the proof pays for it
+    pub fn serialized_size(&self) -> u64 {
+        migration::STATE_HEADER_LEN as u64 + bincode::serialized_size(self).unwrap()
+    }
+
+    // parts_size returns how many of those bytes the parts that grow with usage take
+    pub fn parts_size(&self) -> StateSize {
+        StateSize {
+            orders: bincode::serialized_size(&self.orders).unwrap(),
+            votes: bincode::serialized_size(&self.votes).unwrap(),
+            pending_actions: bincode::serialized_size(&self.pending_actions).unwrap(),
+        }
+    }
+}
+
+// StateSize is the size of the parts of the encoded state that grow with usage, in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StateSize {
+    pub orders: u64,
+    pub votes: u64,
+    pub pending_actions: u64,
+}
+
 // Order is the data of a new order in the Untron protocol.
 // Created in the smart contract and only contains order fields that are needed for the program.
 // All other fields are kept in the smart contract, because the program doesn't need them.
@@ -101,7 +231,7 @@ pub struct Order {
 
 // RawBlock is the data of a block in the Tron blockchain.
 // It's needed for the program to check the block contents.
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RawBlock {
     // raw data of the block. contains its timestamp, tx root, etc.
     // it's encoded in protobuf, but we use our own makeshift-but-efficient deserialization (see protobuf.rs)
@@ -132,23 +262,78 @@ pub fn block_id_to_number(block_id: [u8; 32]) -> u32 {
     u32::from_be_bytes(block_number)
 }
 
+// apply_action applies an action from the smart contract to the orders in the state.
+// the contract emits two kinds of actions:
+// - new order (size > 0): opens an order for the receiver;
+// - receiver freed (size == 0): the order was stopped, expired or closed, and the receiver can be reused.
+// both of them close the receiver's active order, if there's any.
+// normally the contract frees the receiver before reusing it, but if the receiver is reused anyway,
+// the old order is stale and must not stay in the state forever.
+fn apply_action(
+    state: &mut State,
+    active_addresses: &mut HashMap<[u8; 20], [u8; 32]>,
+    closed_orders: &mut Vec<([u8; 32], OrderState)>,
+    action: Action,
+    action_id: [u8; 32],
+) {
+    if let Some(old_action_id) = active_addresses.remove(&action.address) {
+        // If the address is already active, close the existing order
+        if let Some(order) = state.orders.remove(&old_action_id) {
+            // Add the closed order to the list
+            closed_orders.push((old_action_id, order));
+        }
+        // else is unreachable
+    }
+
+    // freeing the receiver doesn't open a new order.
+    // otherwise we'd keep an order nobody can fill in the state
+    if action.size == 0 {
+        return;
+    }
+
+    // create a new order
+    state.orders.insert(
+        action_id,
+        OrderState {
+            address: action.address,
+            timestamp: action.timestamp,
+            inflow: 0,
+            min_deposit: action.min_deposit,
+            size: action.size,
+        },
+    );
+    // Mark the address as active with the new action_id
+    active_addresses.insert(action.address, action_id);
+}
+
+// take_action chains a new action from the smart contract and adds it to the pending actions
+fn take_action(state: &mut State, action: Action) {
+    // hash the chained order and insert it into the state
+    state.action_chain = crypto::hash(&action.abi_encode());
+
+    state.pending_actions.push((action, state.action_chain));
+}
+
 // stf is the state transition function for the Untron program.
 // it takes the current state and an execution
 // and returns the new state and the closed orders, then passed to the smart contract.
 pub fn stf(state: &mut State, execution: Execution) -> Vec<([u8; 32], OrderState)> {
-    // iterate over all new order actions to form the new action chain and add them to the pending actions
-    for action in execution.actions {
-        // hash the chained order and insert it into the state
-        state.action_chain = crypto::hash(&action.abi_encode());
-
-        state.pending_actions.push((action, state.action_chain));
-    }
+    // RATIONALE:
+    // new actions are only taken when the blocks need them, after the ones already pending.
+    // that applies them exactly as if they were all taken upfront, but only the ones left after
+    // the blocks count towards MAX_PENDING_ACTIONS. so a proof of several executions gives
+    // the same state as the executions one by one, however many actions they have in total.
+    let mut actions = execution.actions.into_iter();
 
     // this vector will store the closed orders
     let mut closed_orders = Vec::new();
     // this hashmap will store the receiver addresses of the active orders
-    // and their order ids
-    let mut active_addresses: HashMap<[u8; 20], [u8; 32]> = HashMap::new();
+    // and their order ids. orders stay active across executions, so we build it from the state
+    let mut active_addresses: HashMap<[u8; 20], [u8; 32]> = state
+        .orders
+        .iter()
+        .map(|(order_id, order)| (order.address, *order_id))
+        .collect();
     // count of the blocks to process (needed to skip the contents of the last 19 blocks to ensure finality of the chain)
     let block_count = execution.blocks.len();
 
@@ -157,9 +342,13 @@ pub fn stf(state: &mut State, execution: Execution) -> Vec<([u8; 32], OrderState
 
     // iterate over all new blocks
     let mut latest_block_id = state.latest_block_id;
+    // same for the cycle: the unfinalized blocks will be verified again in the next execution,
+    // so their proposers must not get into the state
+    let mut cycle = state.cycle.clone();
     for (i, block) in execution.blocks.into_iter().enumerate() {
         // consensus checks (pka zktron)
 
+        cycle_tracker_start!("block header");
         // hash the raw_data from the block header
         let raw_data_hash = crypto::hash(&block.raw_data);
 
@@ -167,22 +356,25 @@ pub fn stf(state: &mut State, execution: Execution) -> Vec<([u8; 32], OrderState
         // it also validates raw_data by comparing latest_block_id with the prev one specified in the raw_data
         let block_header =
             protobuf::parse_block_header(latest_block_id, &block.raw_data, raw_data_hash);
+        cycle_tracker_end!("block header");
 
+        cycle_tracker_start!("signature recovery");
         // recover the proposer's public key from the raw_data hash and proposer signature
         let public_key = crypto::recover_public_key(&block.signature, raw_data_hash);
         // convert the proposer's public key into their address
         let sr = crypto::public_key_to_address(&public_key);
+        cycle_tracker_end!("signature recovery");
         // verify that the proposer is in the SR set
         assert!(state.srs.contains(&sr));
 
         // move the cycle forward
-        if state.cycle.len() == 19 {
-            state.cycle.remove(0);
+        if cycle.len() == 19 {
+            cycle.remove(0);
         }
         // verify that the proposer is not in the cycle (has not proposed the last 19 blocks)
-        assert!(!state.cycle.contains(&sr));
+        assert!(!cycle.contains(&sr));
         // add the proposer to the cycle
-        state.cycle.push(sr);
+        cycle.push(sr);
 
         // RATIONALE:
         // we don't want to update latest_block_id in the state
@@ -196,13 +388,20 @@ pub fn stf(state: &mut State, execution: Execution) -> Vec<([u8; 32], OrderState
             continue;
         }
 
-        // update the latest block id and timestamp
+        // update the latest block id, timestamp and cycle
         state.latest_block_id = latest_block_id;
         state.latest_timestamp = block_header.timestamp;
+        state.cycle.clone_from(&cycle);
 
         // content checks (pka walkthrough)
 
+        cycle_tracker_start!("actions");
         loop {
+            if state.pending_actions.is_empty() {
+                if let Some(action) = actions.next() {
+                    take_action(state, action);
+                }
+            }
             match state.pending_actions.first().cloned() {
                 Some((action, action_id)) => {
                     if action.timestamp > block_header.timestamp {
@@ -212,81 +411,68 @@ pub fn stf(state: &mut State, execution: Execution) -> Vec<([u8; 32], OrderState
                     // remove the action from the pending actions
                     state.pending_actions.remove(0);
 
-                    match active_addresses.remove(&action.address) {
-                        Some(old_action_id) => {
-                            // If the address is already active, close the existing order
-                            if let Some(order) = state.orders.remove(&old_action_id) {
-                                // Add the closed order to the list
-                                closed_orders.push((old_action_id, order));
-                            }
-                            // else is unreachable
-                        }
-                        None => {
-                            // If the address is not active, create a new order
-                            state.orders.insert(
-                                action_id,
-                                OrderState {
-                                    address: action.address,
-                                    timestamp: action.timestamp,
-                                    inflow: 0,
-                                    min_deposit: action.min_deposit,
-                                    size: action.size,
-                                },
-                            );
-                            // Mark the address as active with the new action_id
-                            active_addresses.insert(action.address, action_id);
-                        }
-                    }
+                    apply_action(
+                        state,
+                        &mut active_addresses,
+                        &mut closed_orders,
+                        action,
+                        action_id,
+                    );
                 }
                 None => panic!("the proof must contain at least one pending action at the end"),
             }
         }
+        cycle_tracker_end!("actions");
 
+        cycle_tracker_start!("tx hashing");
         // hash all transactions in the block
         let tx_hashes: Vec<[u8; 32]> = block.txs.iter().map(|tx| crypto::hash(tx)).collect();
+        cycle_tracker_end!("tx hashing");
 
+        cycle_tracker_start!("merkle root");
         // create the merkle tree of the transactions in the block and get its root,
-        // then compare the root with the one in the block header
-        assert_eq!(crypto::create_merkle_tree(&tx_hashes), block_header.tx_root);
+        // then compare the root with the one in the block header.
+        // the tree is built in place of the tx hashes, we don't need them afterwards
+        assert_eq!(crypto::merkle_root(tx_hashes), block_header.tx_root);
+        cycle_tracker_end!("merkle root");
 
+        cycle_tracker_start!("tx parsing");
         // iterate over all transactions in the block
         for tx in block.txs.iter() {
             // we only check for USDT transfer (TriggerSmartContract) or VoteWitnessContract
-
-            match protobuf::parse_usdt_transfer(tx) {
+            match protobuf::parse_tx(tx) {
                 // if it's a USDT transfer, we check if its recipient is in the active addresses
-                Some(transfer) => {
+                Some(RelevantTx::UsdtTransfer(transfer)) => {
                     let Some(order_id) = active_addresses.get(&transfer.to) else {
-                        // >99% of Tron txs will actually not be related to any orders or votes
-                        // so we spend vast amounts of computation on nothing lmao
                         continue;
                     };
 
                     // if they are, we add the transfer value to their order's inflow
                     let order = state.orders.get_mut(order_id).unwrap();
-                    order.inflow += transfer.value;
                     // if the inflow is greater than or equal to the size, the order is closed
-                    if order.inflow >= order.size {
-                        if let Some(order) = state.orders.remove(order_id) {
-                            closed_orders.push((*order_id, order));
+                    if order.add_inflow(transfer.value) {
+                        // the receiver is not active anymore, so later transfers to it are ignored
+                        let order_id = active_addresses.remove(&transfer.to).unwrap();
+                        if let Some(order) = state.orders.remove(&order_id) {
+                            closed_orders.push((order_id, order));
                         }
                         // else is unreachable
                     }
                 }
-                // if it's not a USDT transfer, we check if it's a vote transaction
-                None => {
-                    let Some(vote_tx) = protobuf::parse_vote_tx(tx) else {
-                        continue;
-                    };
-
+                // if it's a vote transaction, we count its votes for the next maintenance period
+                Some(RelevantTx::Vote(vote_tx)) => {
                     // iterate over all votes in the transaction
                     for vote in vote_tx.votes {
                         // add the vote count to the vote count of the witness address
-                        *state.votes.entry(vote.witness_address).or_insert(0) += vote.votes_count;
+                        add_votes(&mut state.votes, vote.witness_address, vote.votes_count);
                     }
                 }
+                // >99% of Tron txs will actually not be related to any orders or votes,
+                // parse_tx rejects them as early as it can
+                None => {}
             }
         }
+        cycle_tracker_end!("tx parsing");
 
         // maintenance period logic
 
@@ -306,12 +492,235 @@ pub fn stf(state: &mut State, execution: Execution) -> Vec<([u8; 32], OrderState
                 .srs
                 .copy_from_slice(&candidates[candidates.len() - 27..]);
             // clear the old cycle
+            cycle.clear();
             state.cycle.clear();
             // clear the old votes
-            state.votes = HashMap::new();
+            state.votes = BTreeMap::new();
         }
     }
 
+    // the state is passed into every proof, so we don't let it grow indefinitely:
+    // the actions over the limit aren't taken, and the action chain ends before them
+    cycle_tracker_start!("actions");
+    for action in actions {
+        if state.pending_actions.len() >= MAX_PENDING_ACTIONS {
+            break;
+        }
+        take_action(state, action);
+    }
+    cycle_tracker_end!("actions");
+
     // return the closed orders
     closed_orders
 }
+
+// execute is the whole Untron program: it takes the serialized inputs,
+// runs them through the state transition function and returns the abi-encoded public values.
+// zkVM entrypoints (main.rs, risc0.rs) are thin wrappers around it,
+// and it can be run natively (e.g. by the relayer or in tests) to get the same public values.
+pub fn execute(
+    serialized_state: &[u8],
+    serialized_actions: &[u8],
+    serialized_blocks: &[u8],
+) -> Vec<u8> {
+    cycle_tracker_start!("input decoding");
+    // compute the old state hash
+    let old_state_hash = crypto::hash(serialized_state);
+    // decode the state, migrating it from its version to the current one (see migration.rs)
+    let mut state = migration::decode_state(serialized_state).unwrap();
+
+    // deserialize the execution payload thru bincode
+    let execution = Execution {
+        actions: bincode::deserialize(serialized_actions).unwrap(),
+        blocks: bincode::deserialize(serialized_blocks).unwrap(),
+    };
+    cycle_tracker_end!("input decoding");
+
+    // get the latest zk proven Tron blockchain's block id and Untron's action chain (chained hash of all actions)
+    let old_block_id = state.latest_block_id;
+    let old_action_chain = state.action_chain;
+
+    // an execution without actions and blocks is a state upgrade:
+    // it only re-encodes the state in the current version, so that new programs can be proven on top of it.
+    // otherwise, perform execution over the state through the state transition function
+    // and format closed_orders as (order_id, amount)
+    let closed_orders: Vec<([u8; 32], u64)> =
+        if execution.actions.is_empty() && execution.blocks.is_empty() {
+            vec![]
+        } else {
+            stf(&mut state, execution)
+                .into_iter()
+                .map(|(order_id, order_state)| (order_id, order_state.inflow))
+                .collect()
+        };
+
+    cycle_tracker_start!("state encoding");
+    // compute the new state hash. the new state is always encoded in the current version
+    let new_state_hash = crypto::hash(&migration::encode_state(&state));
+    cycle_tracker_end!("state encoding");
+
+    UntronPublicValues::abi_encode(&(
+        old_block_id,
+        state.latest_block_id,
+        old_action_chain,
+        state.action_chain,
+        old_state_hash,
+        new_state_hash,
+        closed_orders,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(size: u64) -> OrderState {
+        OrderState {
+            address: [1; 20],
+            timestamp: 0,
+            inflow: 0,
+            min_deposit: 0,
+            size,
+        }
+    }
+
+    #[test]
+    fn inflow_accumulates_until_size() {
+        let mut order = order(100);
+        assert!(!order.add_inflow(40));
+        assert!(!order.add_inflow(59));
+        assert!(order.add_inflow(1));
+        assert_eq!(order.inflow, 100);
+    }
+
+    #[test]
+    fn inflow_saturates_instead_of_wrapping() {
+        let mut order = order(u64::MAX);
+        assert!(!order.add_inflow(u64::MAX - 1));
+        assert!(order.add_inflow(2));
+        assert_eq!(order.inflow, u64::MAX);
+        assert!(order.add_inflow(u64::MAX));
+        assert_eq!(order.inflow, u64::MAX);
+    }
+
+    #[test]
+    fn votes_saturate_instead_of_wrapping() {
+        let mut votes = BTreeMap::new();
+        add_votes(&mut votes, [1; 20], u64::MAX - 1);
+        add_votes(&mut votes, [1; 20], 2);
+        add_votes(&mut votes, [2; 20], 3);
+        add_votes(&mut votes, [2; 20], 4);
+        assert_eq!(votes[&[1; 20]], u64::MAX);
+        assert_eq!(votes[&[2; 20]], 7);
+    }
+
+    #[test]
+    fn zero_votes_are_not_tallied() {
+        let mut votes = BTreeMap::new();
+        add_votes(&mut votes, [1; 20], 0);
+        assert!(votes.is_empty());
+    }
+
+    fn action(address: [u8; 20], size: u64) -> Action {
+        Action {
+            prev: [0; 32],
+            timestamp: 0,
+            address,
+            min_deposit: 0,
+            size,
+        }
+    }
+
+    #[test]
+    fn freeing_receiver_closes_order_without_opening_one() {
+        let mut state = State::default();
+        let mut active_addresses = HashMap::new();
+        let mut closed_orders = vec![];
+
+        apply_action(
+            &mut state,
+            &mut active_addresses,
+            &mut closed_orders,
+            action([1; 20], 100),
+            [1; 32],
+        );
+        assert_eq!(state.orders.len(), 1);
+
+        apply_action(
+            &mut state,
+            &mut active_addresses,
+            &mut closed_orders,
+            action([1; 20], 0),
+            [2; 32],
+        );
+        assert!(state.orders.is_empty());
+        assert!(active_addresses.is_empty());
+        assert_eq!(closed_orders.len(), 1);
+        assert_eq!(closed_orders[0].0, [1; 32]);
+    }
+
+    #[test]
+    fn freeing_inactive_receiver_is_noop() {
+        let mut state = State::default();
+        let mut active_addresses = HashMap::new();
+        let mut closed_orders = vec![];
+
+        apply_action(
+            &mut state,
+            &mut active_addresses,
+            &mut closed_orders,
+            action([1; 20], 0),
+            [1; 32],
+        );
+        assert!(state.orders.is_empty());
+        assert!(active_addresses.is_empty());
+        assert!(closed_orders.is_empty());
+    }
+
+    #[test]
+    fn reused_receiver_prunes_stale_order() {
+        let mut state = State::default();
+        let mut active_addresses = HashMap::new();
+        let mut closed_orders = vec![];
+
+        apply_action(
+            &mut state,
+            &mut active_addresses,
+            &mut closed_orders,
+            action([1; 20], 100),
+            [1; 32],
+        );
+        apply_action(
+            &mut state,
+            &mut active_addresses,
+            &mut closed_orders,
+            action([1; 20], 200),
+            [2; 32],
+        );
+        assert_eq!(state.orders.len(), 1);
+        assert_eq!(state.orders[&[2; 32]].size, 200);
+        assert_eq!(active_addresses[&[1; 20]], [2; 32]);
+        assert_eq!(closed_orders.len(), 1);
+        assert_eq!(closed_orders[0].0, [1; 32]);
+    }
+
+    #[test]
+    fn parts_size_adds_up_to_the_state_size() {
+        let mut state = State::default();
+        let empty = state.serialized_size();
+        state.orders.insert([1; 32], order(100));
+        add_votes(&mut state.votes, [1; 20], 10);
+        state.pending_actions.push((action([1; 20], 0), [2; 32]));
+
+        let size = state.parts_size();
+        let empty_parts = State::default().parts_size();
+        assert_eq!(size.orders - empty_parts.orders, 32 + 20 + 8 * 4);
+        assert_eq!(size.votes - empty_parts.votes, 20 + 8);
+        assert_eq!(
+            state.serialized_size() - empty,
+            (size.orders - empty_parts.orders)
+                + (size.votes - empty_parts.votes)
+                + (size.pending_actions - empty_parts.pending_actions)
+        );
+    }
+}
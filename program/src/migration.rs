@@ -0,0 +1,146 @@
+use crate::State;
+
+// RATIONALE:
+// the state is bincode-serialized, and bincode doesn't know anything about field names or versions.
+// so if we change State in any way, old states (including the one whose hash is in the smart contract)
+// become undeserializable. to avoid this, the state is stored in an envelope:
+// - magic: [u8; 4] (STATE_MAGIC)
+// - version: u32 (little endian)
+// - state: bincode serialized State of that version
+//
+// states from before the envelope was introduced (version 0) are plain bincode serialized States.
+// they start with the latest block id, whose first 4 bytes are always zero
+// (block number is u64 big endian and stays below 2^32 for the next ~400 years),
+// so they can never be confused with the magic.
+//
+// HOW TO CHANGE THE STATE:
+// 1. copy the current State struct into this file as StateV{STATE_VERSION} (with all nested types that change)
+// 2. change State and bump STATE_VERSION
+// 3. add a migrate_v{old} function converting the old struct into the new one and call it in decode_state
+// the relayer decodes (and thus migrates) states on load,
+// and the program re-encodes them in the current version after every run (see execute in lib.rs).
+
+// magic bytes at the start of every versioned state
+pub const STATE_MAGIC: [u8; 4] = *b"UNTR";
+
+// current version of the state format
+pub const STATE_VERSION: u32 = 1;
+
+// length of the envelope header (magic + version)
+pub const STATE_HEADER_LEN: usize = 8;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    // the state is from a newer program than this one
+    UnknownVersion(u32),
+    // the state doesn't deserialize into the struct of its version
+    Malformed(bincode::Error),
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::UnknownVersion(version) => {
+                write!(f, "unknown state version {}", version)
+            }
+            MigrationError::Malformed(e) => write!(f, "malformed state: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<bincode::Error> for MigrationError {
+    fn from(e: bincode::Error) -> Self {
+        MigrationError::Malformed(e)
+    }
+}
+
+// state_version returns the version of the encoded state without decoding it
+pub fn state_version(bytes: &[u8]) -> u32 {
+    if bytes.len() < STATE_HEADER_LEN || bytes[..4] != STATE_MAGIC {
+        return 0;
+    }
+
+    u32::from_le_bytes(bytes[4..8].try_into().unwrap())
+}
+
+// encode_state encodes the state in the current version
+pub fn encode_state(state: &State) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(state.serialized_size() as usize);
+    encoded.extend_from_slice(&STATE_MAGIC);
+    encoded.extend_from_slice(&STATE_VERSION.to_le_bytes());
+    encoded.extend_from_slice(&bincode::serialize(state).unwrap());
+    encoded
+}
+
+// decode_state decodes the state of any known version, migrating it to the current one
+pub fn decode_state(bytes: &[u8]) -> Result<State, MigrationError> {
+    match state_version(bytes) {
+        0 => migrate_v0(bytes),
+        STATE_VERSION => Ok(bincode::deserialize(&bytes[STATE_HEADER_LEN..])?),
+        version => Err(MigrationError::UnknownVersion(version)),
+    }
+}
+
+// version 0 is the same State as version 1, just without the envelope
+fn migrate_v0(bytes: &[u8]) -> Result<State, MigrationError> {
+    Ok(bincode::deserialize(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        let mut state = State {
+            latest_timestamp: 1727600000000,
+            ..Default::default()
+        };
+        state.latest_block_id[4..8].copy_from_slice(&65_000_000u32.to_be_bytes());
+        state.votes.insert([1; 20], 100);
+        state
+    }
+
+    #[test]
+    fn current_version_roundtrips() {
+        let encoded = encode_state(&state());
+        assert_eq!(state_version(&encoded), STATE_VERSION);
+        assert_eq!(encoded.len() as u64, state().serialized_size());
+
+        let decoded = decode_state(&encoded).unwrap();
+        assert_eq!(encode_state(&decoded), encoded);
+    }
+
+    #[test]
+    fn unversioned_state_is_migrated() {
+        let legacy = bincode::serialize(&state()).unwrap();
+        assert_eq!(state_version(&legacy), 0);
+        assert_eq!(
+            state_version(&bincode::serialize(&State::default()).unwrap()),
+            0
+        );
+
+        let migrated = decode_state(&legacy).unwrap();
+        assert_eq!(encode_state(&migrated), encode_state(&state()));
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let mut encoded = encode_state(&state());
+        encoded[4..8].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            decode_state(&encoded),
+            Err(MigrationError::UnknownVersion(v)) if v == STATE_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn malformed_state_is_rejected() {
+        let encoded = encode_state(&state());
+        assert!(matches!(
+            decode_state(&encoded[..encoded.len() - 1]),
+            Err(MigrationError::Malformed(_))
+        ));
+    }
+}
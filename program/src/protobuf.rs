@@ -8,6 +8,19 @@ use hex_literal::hex;
 //
 // untron circuit only needs witness vote txs (to determine who's the next SR)
 // and TriggerSmartContract (EVM-ish) txs with USDT TRC20 transfer() calls.
+//
+// both are read by parse_tx, which walks the protobuf of every tx only once
+// and rejects all the other txs as early as it can.
+//
+// the tx parsers are fuzzed against prost in program/fuzz.
+// every bug found there gets a regression_* test at the bottom of this file.
+
+// Transaction.Contract.ContractType values we're interested in
+const VOTE_WITNESS_CONTRACT: u64 = 4;
+const TRIGGER_SMART_CONTRACT: u64 = 31;
+
+// USDT smart contract TR7NHqjeKQxGTCi8q8ZY4pL8otSzgjLj6t
+const USDT_CONTRACT: [u8; 21] = hex!("41a614f803b6fd780986a42c78ec9c7f77e6ded13c");
 
 pub struct BlockHeader {
     pub prev_block_id: [u8; 32],
@@ -33,6 +46,12 @@ pub struct VoteTx {
     pub votes: Vec<Vote>,
 }
 
+// RelevantTx is a transaction the program acts on
+pub enum RelevantTx {
+    UsdtTransfer(UsdtTransfer),
+    Vote(VoteTx),
+}
+
 // assert_eq but None instead of panic
 fn wagmi<T: core::cmp::PartialEq>(left: T, right: T) -> Option<()> {
     if left == right {
@@ -42,12 +61,18 @@ fn wagmi<T: core::cmp::PartialEq>(left: T, right: T) -> Option<()> {
     }
 }
 
-pub fn read_varint(arr: &[u8]) -> (usize, usize) {
-    let mut result = 0;
+// read_varint_u64 reads a protobuf varint as u64.
+// we can't read numbers into usize because the zkVM is 32-bit,
+// and timestamps or vote counts don't fit into 32 bits.
+// like in protobuf itself, bits that don't fit into 64 bits are discarded
+pub fn read_varint_u64(arr: &[u8]) -> (u64, usize) {
+    let mut result: u64 = 0;
     let mut offset = 0;
     loop {
         let i = arr[offset];
-        result |= ((i & 0x7f) as usize) << (offset * 7);
+        result |= ((i & 0x7f) as u64)
+            .checked_shl(offset as u32 * 7)
+            .unwrap_or(0);
         offset += 1;
         if i & 0x80 == 0 {
             break;
@@ -57,6 +82,129 @@ pub fn read_varint(arr: &[u8]) -> (usize, usize) {
     (result, offset)
 }
 
+// read_varint reads a protobuf varint as usize.
+// only use it for lengths and small enums, use read_varint_u64 for actual numbers
+pub fn read_varint(arr: &[u8]) -> (usize, usize) {
+    let (result, offset) = read_varint_u64(arr);
+    (result as usize, offset)
+}
+
+// read_uint256 reads a big endian ABI-encoded uint256 word as u64.
+// values that don't fit into u64 saturate to u64::MAX instead of being truncated,
+// so that a huge transfer can never be counted as a small one.
+// (USDT's total supply fits into u64, so this never happens with real transfers)
+pub fn read_uint256(word: &[u8]) -> u64 {
+    if word[..24].iter().any(|&b| b != 0) {
+        return u64::MAX;
+    }
+
+    let mut value_bytes = [0u8; 8];
+    value_bytes.copy_from_slice(&word[24..32]);
+    u64::from_be_bytes(value_bytes)
+}
+
+// Value is the value of a protobuf field
+enum Value<'a> {
+    // VARINT, I64 and I32
+    Number(u64),
+    // LEN
+    Bytes(&'a [u8]),
+}
+
+// try_read_varint is read_varint_u64 for untrusted data, None if the varint is cut off
+pub fn try_read_varint(arr: &[u8]) -> Option<(u64, usize)> {
+    let last = arr.iter().position(|&b| b & 0x80 == 0)?;
+    Some(read_varint_u64(&arr[..=last]))
+}
+
+// read_field reads the protobuf field at the start of arr
+// and returns its number, value and encoded length.
+// None if the field is cut off or is a (deprecated) group
+fn read_field(arr: &[u8]) -> Option<(u64, Value<'_>, usize)> {
+    let (key, mut offset) = try_read_varint(arr)?;
+    let value = match key & 7 {
+        0 => {
+            // VARINT
+            let (value, v) = try_read_varint(&arr[offset..])?;
+            offset += v;
+            Value::Number(value)
+        }
+        1 => {
+            // I64
+            let value = arr.get(offset..offset + 8)?;
+            offset += 8;
+            Value::Number(u64::from_le_bytes(value.try_into().ok()?))
+        }
+        2 => {
+            // LEN
+            let (length, v) = try_read_varint(&arr[offset..])?;
+            offset += v;
+            let length = usize::try_from(length).ok()?;
+            let value = arr.get(offset..offset.checked_add(length)?)?;
+            offset += length;
+            Value::Bytes(value)
+        }
+        5 => {
+            // I32
+            let value = arr.get(offset..offset + 4)?;
+            offset += 4;
+            Value::Number(u32::from_le_bytes(value.try_into().ok()?) as u64)
+        }
+        _ => return None,
+    };
+
+    Some((key >> 3, value, offset))
+}
+
+// find_field returns the value of a singular field in a protobuf message, if it's there.
+// None if it's there more than once, or if the message is malformed.
+// protobuf takes the last value of a singular field that's there more than once (and merges messages),
+// but java-tron always encodes each field once, so such a message can only be crafted
+// to make us read another value than java-tron does, and we don't read it at all
+fn find_field(mut message: &[u8], number: u64) -> Option<Option<Value<'_>>> {
+    let mut found = None;
+    while !message.is_empty() {
+        let (n, value, length) = read_field(message)?;
+        if n == number {
+            if found.is_some() {
+                return None;
+            }
+            found = Some(value);
+        }
+        message = &message[length..];
+    }
+    Some(found)
+}
+
+// find_first returns the value of the first element of a repeated field.
+// None if there's no such field or the message is malformed before it
+fn find_first(mut message: &[u8], number: u64) -> Option<Value<'_>> {
+    while !message.is_empty() {
+        let (n, value, length) = read_field(message)?;
+        if n == number {
+            return Some(value);
+        }
+        message = &message[length..];
+    }
+    None
+}
+
+// find_bytes returns the value of a singular LEN field (see find_field)
+fn find_bytes(message: &[u8], number: u64) -> Option<&[u8]> {
+    match find_field(message, number)?? {
+        Value::Bytes(value) => Some(value),
+        Value::Number(_) => None,
+    }
+}
+
+// find_number returns the value of a singular VARINT, I64 or I32 field (see find_field)
+fn find_number(message: &[u8], number: u64) -> Option<u64> {
+    match find_field(message, number)?? {
+        Value::Number(value) => Some(value),
+        Value::Bytes(_) => None,
+    }
+}
+
 pub fn parse_block_header(prev_block_id: [u8; 32], raw_data: &[u8], hash: [u8; 32]) -> BlockHeader {
     // protobuf fuckery. in tron, everything is in protobuf
     let mut offset = 0;
@@ -65,8 +213,7 @@ pub fn parse_block_header(prev_block_id: [u8; 32], raw_data: &[u8], hash: [u8; 3
     assert_eq!(raw_data[offset] >> 3, 1);
     offset += 1;
 
-    // we don't need timestamp so we skip it
-    let (timestamp, o) = read_varint(&raw_data[offset..]);
+    let (timestamp, o) = read_varint_u64(&raw_data[offset..]);
     offset += o;
 
     assert_eq!(raw_data[offset] & 7, 2); // LEN
@@ -87,212 +234,520 @@ pub fn parse_block_header(prev_block_id: [u8; 32], raw_data: &[u8], hash: [u8; 3
     assert_eq!(raw_data[offset] >> 3, 7);
     offset += 1;
 
-    let (block_number, _) = read_varint(&raw_data[offset..]);
+    let (block_number, _) = read_varint_u64(&raw_data[offset..]);
 
     let mut new_block_id = hash;
-    new_block_id[..8].copy_from_slice(&(block_number as u64).to_be_bytes());
+    new_block_id[..8].copy_from_slice(&block_number.to_be_bytes());
 
     BlockHeader {
         prev_block_id,
         new_block_id,
         tx_root,
-        timestamp: timestamp as u64,
+        timestamp,
     }
 }
 
-pub fn parse_usdt_transfer(tx: &[u8]) -> Option<UsdtTransfer> {
-    wagmi(tx[tx.len() - 1], 1)?; // ret.contractRet: SUCCESS (THIS THING IS CRITICAL!!!)
-
-    wagmi(tx[0] & 7, 2)?; // LEN
-    wagmi(tx[0] >> 3, 1)?; // 1:
-    let (_, mut offset) = read_varint(tx);
-    offset += 1;
+// read_address strips the 0x41 prefix from a Tron address
+fn read_address(tron_address: &[u8]) -> Option<[u8; 20]> {
+    wagmi(tron_address.len(), 21)?;
+    tron_address[1..].try_into().ok()
+}
 
-    // skipping unnecessary protobuf elements
-    loop {
-        if offset >= tx.len() {
-            return None;
-        }
-        let t = tx[offset];
-        if t == 0x5a {
-            // 11: LEN
-            break;
-        }
-        offset += 1;
-        if t & 7 == 5 {
-            offset += 4;
-        } else {
-            let (length, v) = read_varint(&tx[offset..]);
-            offset += v + (length * (t & 7 == 2) as usize);
-        }
+// parse_tx classifies a transaction in a single pass over its protobuf:
+// it reads the contract type once and only parses the contract if it can be a USDT transfer or a vote.
+// it returns None on anything it doesn't understand instead of panicking,
+// so that no transaction in a block can make its proof fail
+pub fn parse_tx(tx: &[u8]) -> Option<RelevantTx> {
+    let raw_data = find_bytes(tx, 1)?;
+    // contract is repeated, but java-tron rejects txs without exactly one
+    let contract = find_bytes(raw_data, 11)?;
+    let contract_type = find_number(contract, 1)?;
+    if contract_type != TRIGGER_SMART_CONTRACT && contract_type != VOTE_WITNESS_CONTRACT {
+        return None;
     }
 
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 11)?; // 11:
-    offset += 1;
-    let (_, v) = read_varint(&tx[offset..]);
-    offset += v;
+    let parameter = find_bytes(contract, 2)?;
+    let value = find_bytes(parameter, 2)?; // 1: type_url, we skip it; 2: value
 
-    wagmi(tx[offset] & 7, 0)?; // VARINT
-    wagmi(tx[offset] >> 3, 1)?; // 1: (we enter the contract protobuf)
-    offset += 1;
-    let (call_type, v) = read_varint(&tx[offset..]);
-    offset += v;
+    // most smart contract calls are not to USDT, so we reject them before looking any further
+    if contract_type == TRIGGER_SMART_CONTRACT {
+        wagmi(find_bytes(value, 2)?, &USDT_CONTRACT[..])?;
+    }
 
-    wagmi(call_type, 31); // TriggerSmartContract
+    // ret (we only look at the first one, like java-tron)
+    let Value::Bytes(ret) = find_first(tx, 5)? else {
+        return None;
+    };
+    wagmi(find_number(ret, 3)?, 1)?; // ret.contractRet: SUCCESS (THIS THING IS CRITICAL!!!)
 
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 2)?; // 2:
-    offset += 1;
-    let (_, v) = read_varint(&tx[offset..]);
-    offset += v;
+    if contract_type == TRIGGER_SMART_CONTRACT {
+        parse_transfer_call(value).map(RelevantTx::UsdtTransfer)
+    } else {
+        parse_vote_witness(value).map(RelevantTx::Vote)
+    }
+}
 
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 1)?; // 1:
-    offset += 1;
-    let (_, v) = read_varint(&tx[offset..]);
-    offset += v;
+// parse_transfer_call reads a transfer() call from a TriggerSmartContract to the USDT contract
+fn parse_transfer_call(trigger: &[u8]) -> Option<UsdtTransfer> {
+    // the EVM reads missing calldata as zeros, so a successful transfer() call
+    // with less than 68 bytes of data transfers to a zero-padded address/value
+    let data = find_bytes(trigger, 4)?;
+    let mut calldata = [0u8; 68];
+    let length = data.len().min(68);
+    calldata[..length].copy_from_slice(&data[..length]);
+    wagmi(&calldata[..4], &hex!("a9059cbb"))?;
 
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 2)?; // 2:
-    offset += 1;
-    let (_, v) = read_varint(&tx[offset..]);
-    offset += v;
+    let mut to = [0u8; 20];
+    to.copy_from_slice(&calldata[16..36]);
 
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 1)?; // 1:
-    offset += 1;
-    let (_, v) = read_varint(&tx[offset..]);
-    offset += v;
+    let value = read_uint256(&calldata[36..68]);
 
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 2)?; // 2:
-    offset += 1;
-    let (length, v) = read_varint(&tx[offset..]);
-    offset += v;
-
-    // USDT smart contract TR7NHqjeKQxGTCi8q8ZY4pL8otSzgjLj6t
-    wagmi(
-        &tx[offset..offset + length],
-        &hex!("41a614f803b6fd780986a42c78ec9c7f77e6ded13c"),
-    )?;
-    offset += length;
-
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 4)?; // 4:
-    offset += 1;
-    let (length, v) = read_varint(&tx[offset..]);
-    offset += v;
+    Some(UsdtTransfer { to, value })
+}
 
-    let data = &tx[offset..offset + length];
-    wagmi(&data[..4], &hex!("a9059cbb"))?;
+// parse_vote_witness reads the votes from a VoteWitnessContract
+fn parse_vote_witness(vote_witness: &[u8]) -> Option<VoteTx> {
+    let voter = read_address(find_bytes(vote_witness, 1)?)?;
 
-    let mut to = [0u8; 20];
-    to.copy_from_slice(&data[16..36]);
+    let mut votes = Vec::new();
+    let mut fields = vote_witness;
+    while !fields.is_empty() {
+        let (number, value, length) = read_field(fields)?;
+        fields = &fields[length..];
+
+        if let (2, Value::Bytes(vote)) = (number, value) {
+            let votes_count = match find_field(vote, 2)? {
+                Some(Value::Number(votes_count)) => votes_count,
+                Some(Value::Bytes(_)) => return None,
+                // proto3 omits zero values
+                None => 0,
+            };
+            votes.push(Vote {
+                witness_address: read_address(find_bytes(vote, 1)?)?,
+                votes_count,
+            });
+        }
+    }
 
-    let mut value_bytes = [0u8; 8];
-    value_bytes.copy_from_slice(&data[60..68]);
-    let value = u64::from_le_bytes(value_bytes);
+    Some(VoteTx { voter, votes })
+}
 
-    Some(UsdtTransfer { to, value })
+// parse_usdt_transfer is parse_tx for USDT transfers only
+pub fn parse_usdt_transfer(tx: &[u8]) -> Option<UsdtTransfer> {
+    match parse_tx(tx)? {
+        RelevantTx::UsdtTransfer(transfer) => Some(transfer),
+        RelevantTx::Vote(_) => None,
+    }
 }
 
+// parse_vote_tx is parse_tx for votes only
 pub fn parse_vote_tx(tx: &[u8]) -> Option<VoteTx> {
-    wagmi(tx[tx.len() - 1], 1)?; // ret.contractRet: SUCCESS (THIS THING IS CRITICAL!!!)
+    match parse_tx(tx)? {
+        RelevantTx::Vote(vote_tx) => Some(vote_tx),
+        RelevantTx::UsdtTransfer(_) => None,
+    }
+}
 
-    wagmi(tx[0] & 7, 2)?; // LEN
-    wagmi(tx[0] >> 3, 1)?; // 1:
-    let (_, mut offset) = read_varint(tx);
-    offset += 1;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
 
-    // skipping unnecessary protobuf elements
-    loop {
-        if offset >= tx.len() {
-            return None;
-        }
-        let t = tx[offset];
-        if t == 0x5a {
-            // 11: LEN
-            break;
-        }
-        offset += 1;
-        if t & 7 == 5 {
-            offset += 4;
-        } else {
-            let (length, v) = read_varint(&tx[offset..]);
-            offset += v + (length * (t & 7 == 2) as usize);
+    #[test]
+    fn parses_usdt_transfer() {
+        let transfer = parse_usdt_transfer(&usdt_transfer([1; 20], [2; 20], 1_000_000)).unwrap();
+        assert_eq!(transfer.to, [2; 20]);
+        assert_eq!(transfer.value, 1_000_000);
+    }
+
+    #[test]
+    fn ignores_non_transfers() {
+        assert!(parse_usdt_transfer(&failed(usdt_transfer([1; 20], [2; 20], 1))).is_none());
+        assert!(parse_usdt_transfer(&usdt_transfer_from([1; 20], [3; 20], [2; 20], 1)).is_none());
+        assert!(parse_usdt_transfer(&vote_tx([1; 20], &[([2; 20], 1)])).is_none());
+    }
+
+    #[test]
+    fn parses_vote_tx() {
+        let vote_tx =
+            parse_vote_tx(&vote_tx([1; 20], &[([2; 20], 5), ([3; 20], 1 << 40)])).unwrap();
+        assert_eq!(vote_tx.voter, [1; 20]);
+        assert_eq!(vote_tx.votes.len(), 2);
+        assert_eq!(vote_tx.votes[0].witness_address, [2; 20]);
+        assert_eq!(vote_tx.votes[0].votes_count, 5);
+        assert_eq!(vote_tx.votes[1].witness_address, [3; 20]);
+        assert_eq!(vote_tx.votes[1].votes_count, 1 << 40);
+    }
+
+    #[test]
+    fn ignores_non_votes() {
+        assert!(parse_vote_tx(&failed(vote_tx([1; 20], &[([2; 20], 5)]))).is_none());
+        assert!(parse_vote_tx(&usdt_transfer([1; 20], [2; 20], 1)).is_none());
+    }
+
+    #[test]
+    fn classifies_txs() {
+        assert!(matches!(
+            parse_tx(&usdt_transfer([1; 20], [2; 20], 1)),
+            Some(RelevantTx::UsdtTransfer(_))
+        ));
+        assert!(matches!(
+            parse_tx(&vote_tx([1; 20], &[([2; 20], 5)])),
+            Some(RelevantTx::Vote(_))
+        ));
+
+        // TransferContract (TRX transfer)
+        let mut transfer = len_field(1, &tron_address([1; 20]));
+        transfer.extend(len_field(2, &tron_address([2; 20])));
+        transfer.extend(varint_field(3, 1_000_000));
+        let trx_transfer = transaction(
+            1,
+            "type.googleapis.com/protocol.TransferContract",
+            &transfer,
+            CONTRACT_SUCCESS,
+        );
+        assert!(parse_tx(&trx_transfer).is_none());
+
+        // transfer() on another TRC20 token
+        let mut trigger = len_field(1, &tron_address([1; 20]));
+        trigger.extend(len_field(2, &tron_address([3; 20])));
+        trigger.extend(len_field(4, &hex!("a9059cbb")));
+        let other_token = transaction(
+            31,
+            "type.googleapis.com/protocol.TriggerSmartContract",
+            &trigger,
+            CONTRACT_SUCCESS,
+        );
+        assert!(parse_tx(&other_token).is_none());
+    }
+
+    #[test]
+    fn parses_block_header() {
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        let prev_block_id = chain.latest_block_id;
+        let block = chain.block(vec![usdt_transfer([1; 20], [2; 20], 1)]);
+        let hash = crate::crypto::hash(&block.raw_data);
+
+        let header = parse_block_header(prev_block_id, &block.raw_data, hash);
+        assert_eq!(header.new_block_id, chain.latest_block_id);
+        assert_eq!(header.timestamp, chain.timestamp);
+        assert_eq!(
+            header.tx_root,
+            crate::crypto::hash(&block.txs[0]),
+            "tx root of a single tx is its hash"
+        );
+    }
+
+    #[test]
+    fn varint_reads_numbers_wider_than_32_bits() {
+        // 1727600000000 (a Tron timestamp in ms), followed by garbage
+        assert_eq!(read_varint_u64(&hex!("80f8efe7a332ff")), (1727600000000, 6));
+        assert_eq!(read_varint(&hex!("ac02")), (300, 2));
+    }
+
+    #[test]
+    fn varint_discards_bits_past_64() {
+        let (value, offset) = read_varint_u64(&hex!("ffffffffffffffffffff7f"));
+        assert_eq!(value, u64::MAX);
+        assert_eq!(offset, 11);
+    }
+
+    #[test]
+    fn uint256_is_big_endian() {
+        let mut word = [0u8; 32];
+        word[29..].copy_from_slice(&hex!("0f4240")); // 1 USDT
+        assert_eq!(read_uint256(&word), 1_000_000);
+    }
+
+    #[test]
+    fn uint256_saturates_past_u64() {
+        let mut word = [0u8; 32];
+        word[23] = 1; // 2^64
+        assert_eq!(read_uint256(&word), u64::MAX);
+        assert_eq!(read_uint256(&[0xff; 32]), u64::MAX);
+    }
+
+    // replaces the ret of a transaction built with testing::transaction
+    fn with_ret(mut tx: Vec<u8>, ret: &[u8]) -> Vec<u8> {
+        tx.truncate(tx.len() - 4);
+        tx.extend(len_field(5, ret));
+        tx
+    }
+
+    #[test]
+    fn regression_empty_and_truncated_txs() {
+        assert!(parse_usdt_transfer(&[]).is_none());
+        assert!(parse_vote_tx(&[]).is_none());
+
+        let tx = usdt_transfer([1; 20], [2; 20], 1);
+        for length in 0..tx.len() {
+            assert!(parse_usdt_transfer(&tx[..length]).is_none());
+            assert!(parse_vote_tx(&tx[..length]).is_none());
         }
     }
 
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 11)?; // 11:
-    offset += 1;
-    let (_, v) = read_varint(&tx[offset..]);
-    offset += v;
+    #[test]
+    fn regression_ret_without_contract_ret() {
+        // ret: FAILED, contractRet: DEFAULT (omitted), so the tx still ends with 0x01
+        let tx = with_ret(usdt_transfer([1; 20], [2; 20], 1), &varint_field(2, 1));
+        assert_eq!(tx[tx.len() - 1], 1);
+        assert!(parse_usdt_transfer(&tx).is_none());
 
-    wagmi(tx[offset] & 7, 0)?; // VARINT
-    wagmi(tx[offset] >> 3, 1)?; // 1: (we enter the contract protobuf)
-    offset += 1;
-    let (call_type, v) = read_varint(&tx[offset..]);
-    offset += v;
+        let tx = with_ret(vote_tx([1; 20], &[([2; 20], 5)]), &varint_field(2, 1));
+        assert!(parse_vote_tx(&tx).is_none());
+    }
 
-    wagmi(call_type, 4)?; // VoteWitnessContract
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 2)?; // 2:
-    offset += 1;
-    let (_, v) = read_varint(&tx[offset..]);
-    offset += v;
+    #[test]
+    fn regression_short_transfer_calldata() {
+        // transfer(to) without the value, the EVM reads it as zero
+        let mut data = hex!("a9059cbb").to_vec();
+        data.extend([0; 12]);
+        data.extend([2; 20]);
+        let transfer = parse_usdt_transfer(&trigger_usdt([1; 20], &data, 1)).unwrap();
+        assert_eq!(transfer.to, [2; 20]);
+        assert_eq!(transfer.value, 0);
+
+        assert!(parse_usdt_transfer(&trigger_usdt([1; 20], &hex!("a905"), 1)).is_none());
+        assert!(parse_usdt_transfer(&trigger_usdt([1; 20], &[], 1)).is_none());
+    }
 
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 1)?; // 1:
-    offset += 1;
-    let (length, v) = read_varint(&tx[offset..]);
-    offset += v;
-    // wagmi(
-    //     &tx[offset..offset + length],
-    //     b"type.googleapis.com/protocol.VoteWitnessContract",
-    // )?;
-    offset += length;
-
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 2)?; // 2:
-    offset += 1;
-    let (_, v) = read_varint(&tx[offset..]);
-    offset += v;
+    #[test]
+    fn regression_transfer_with_call_value() {
+        let mut data = hex!("a9059cbb").to_vec();
+        data.extend([0; 12]);
+        data.extend([2; 20]);
+        data.extend([0; 31]);
+        data.push(7);
+
+        let mut trigger = len_field(1, &tron_address([1; 20]));
+        trigger.extend(len_field(2, &crate::testing::USDT_CONTRACT));
+        trigger.extend(varint_field(3, 1)); // call_value
+        trigger.extend(len_field(4, &data));
+        let tx = transaction(
+            31,
+            "type.googleapis.com/protocol.TriggerSmartContract",
+            &trigger,
+            1,
+        );
+
+        let transfer = parse_usdt_transfer(&tx).unwrap();
+        assert_eq!(transfer.to, [2; 20]);
+        assert_eq!(transfer.value, 7);
+    }
 
-    wagmi(tx[offset] & 7, 2)?; // LEN
-    wagmi(tx[offset] >> 3, 1)?; // 1:
-    offset += 1;
-    let (length, v) = read_varint(&tx[offset..]);
-    offset += v;
+    #[test]
+    fn regression_vote_tx_without_timestamp() {
+        // a vote tx whose raw_data ends with the contract,
+        // so the signature (field 2, like votes) comes right after the votes
+        let tx = vote_tx([1; 20], &[([2; 20], 5)]);
+        let contract = find_bytes(find_bytes(&tx, 1).unwrap(), 11).unwrap();
+
+        let mut tx = len_field(1, &len_field(11, contract));
+        tx.extend(len_field(2, &[0x5a; 65]));
+        tx.extend(len_field(5, &varint_field(3, 1)));
+
+        let vote_tx = parse_vote_tx(&tx).unwrap();
+        assert_eq!(vote_tx.votes.len(), 1);
+        assert_eq!(vote_tx.votes[0].witness_address, [2; 20]);
+        assert_eq!(vote_tx.votes[0].votes_count, 5);
+    }
 
-    let mut voter = [0u8; 20];
-    voter.copy_from_slice(&tx[offset + 1..offset + length]); // strip 0x41
-    offset += length;
+    #[test]
+    fn regression_zero_votes() {
+        let vote_tx = parse_vote_tx(&vote_tx([1; 20], &[([2; 20], 0), ([3; 20], 5)])).unwrap();
+        assert_eq!(vote_tx.votes.len(), 2);
+        assert_eq!(vote_tx.votes[0].votes_count, 0);
+        assert_eq!(vote_tx.votes[1].votes_count, 5);
+    }
 
-    let mut votes = Vec::new();
-    while offset < tx.len() && tx[offset] & 7 == 2 && tx[offset] >> 3 == 2 {
-        offset += 1;
-        let (_, v) = read_varint(&tx[offset..]);
-        offset += v;
-        wagmi(tx[offset] & 7, 2)?; // LEN
-        wagmi(tx[offset] >> 3, 1)?; // 1:
-        offset += 1;
-        let (length, v) = read_varint(&tx[offset..]);
-        offset += v;
-        let mut witness_address = [0u8; 20];
-        witness_address.copy_from_slice(&tx[offset + 1..offset + length]); // strip 0x41
-        offset += length;
-        wagmi(tx[offset] & 7, 0)?; // VARINT
-        wagmi(tx[offset] >> 3, 2)?; // 2:
-        offset += 1;
-        let (votes_count, v) = read_varint(&tx[offset..]);
-        offset += v;
-        votes.push(Vote {
-            witness_address,
-            votes_count: votes_count as u64,
-        });
+    // trigger builds a TriggerSmartContract from its fields, in order
+    fn trigger(fields: &[(u64, &[u8])]) -> Vec<u8> {
+        fields
+            .iter()
+            .flat_map(|(number, value)| len_field(*number, value))
+            .collect()
     }
 
-    Some(VoteTx { voter, votes })
+    // contract_tx builds a successful tx with the given Transaction.Contract
+    fn contract_tx(contract: &[u8]) -> Vec<u8> {
+        let mut tx = len_field(1, &len_field(11, contract));
+        tx.extend(len_field(5, &varint_field(3, 1)));
+        tx
+    }
+
+    fn any(type_url: &str, value: &[u8]) -> Vec<u8> {
+        let mut any = len_field(1, type_url.as_bytes());
+        any.extend(len_field(2, value));
+        any
+    }
+
+    fn transfer_data(to: [u8; 20], value: u8) -> Vec<u8> {
+        let mut data = hex!("a9059cbb").to_vec();
+        data.extend([0; 12]);
+        data.extend(to);
+        data.extend([0; 31]);
+        data.push(value);
+        data
+    }
+
+    #[test]
+    fn regression_repeated_contract_address() {
+        // protobuf takes the last contract_address, so this is a transfer of another token
+        let other = tron_address([3; 20]);
+        let data = transfer_data([2; 20], 7);
+        let usdt_first = trigger(&[
+            (1, &tron_address([1; 20])),
+            (2, &crate::testing::USDT_CONTRACT),
+            (2, &other),
+            (4, &data),
+        ]);
+        let tx = transaction(
+            31,
+            "type.googleapis.com/protocol.TriggerSmartContract",
+            &usdt_first,
+            1,
+        );
+        assert!(parse_tx(&tx).is_none());
+
+        let usdt_last = trigger(&[
+            (1, &tron_address([1; 20])),
+            (2, &other),
+            (2, &crate::testing::USDT_CONTRACT),
+            (4, &data),
+        ]);
+        let tx = transaction(
+            31,
+            "type.googleapis.com/protocol.TriggerSmartContract",
+            &usdt_last,
+            1,
+        );
+        assert!(parse_tx(&tx).is_none());
+    }
+
+    #[test]
+    fn regression_repeated_transfer_data() {
+        let mut trigger = trigger(&[
+            (1, &tron_address([1; 20])),
+            (2, &crate::testing::USDT_CONTRACT),
+            (4, &transfer_data([2; 20], 7)),
+        ]);
+        trigger.extend(len_field(4, &transfer_data([4; 20], 9)));
+        let tx = transaction(
+            31,
+            "type.googleapis.com/protocol.TriggerSmartContract",
+            &trigger,
+            1,
+        );
+        assert!(parse_usdt_transfer(&tx).is_none());
+    }
+
+    #[test]
+    fn regression_repeated_owner_address() {
+        let mut vote_witness = len_field(1, &tron_address([1; 20]));
+        vote_witness.extend(len_field(1, &tron_address([4; 20])));
+        let mut vote = len_field(1, &tron_address([2; 20]));
+        vote.extend(varint_field(2, 5));
+        vote_witness.extend(len_field(2, &vote));
+        let tx = transaction(
+            4,
+            "type.googleapis.com/protocol.VoteWitnessContract",
+            &vote_witness,
+            1,
+        );
+        assert!(parse_vote_tx(&tx).is_none());
+
+        // and inside a vote
+        let mut vote = len_field(1, &tron_address([2; 20]));
+        vote.extend(varint_field(2, 5));
+        vote.extend(varint_field(2, 6));
+        let mut vote_witness = len_field(1, &tron_address([1; 20]));
+        vote_witness.extend(len_field(2, &vote));
+        let tx = transaction(
+            4,
+            "type.googleapis.com/protocol.VoteWitnessContract",
+            &vote_witness,
+            1,
+        );
+        assert!(parse_vote_tx(&tx).is_none());
+    }
+
+    #[test]
+    fn regression_repeated_contract_type() {
+        let transfer = trigger(&[
+            (1, &tron_address([1; 20])),
+            (2, &crate::testing::USDT_CONTRACT),
+            (4, &transfer_data([2; 20], 7)),
+        ]);
+        let parameter = any("type.googleapis.com/protocol.TriggerSmartContract", &transfer);
+
+        // java-tron executes the last type, a TransferContract (1)
+        let mut contract = varint_field(1, 31);
+        contract.extend(varint_field(1, 1));
+        contract.extend(len_field(2, &parameter));
+        assert!(parse_tx(&contract_tx(&contract)).is_none());
+
+        let mut contract = varint_field(1, 31);
+        contract.extend(len_field(2, &parameter));
+        assert!(parse_tx(&contract_tx(&contract)).is_some());
+    }
+
+    #[test]
+    fn regression_repeated_parameter() {
+        let usdt = trigger(&[
+            (1, &tron_address([1; 20])),
+            (2, &crate::testing::USDT_CONTRACT),
+            (4, &transfer_data([2; 20], 7)),
+        ]);
+        let other = trigger(&[
+            (1, &tron_address([1; 20])),
+            (2, &tron_address([3; 20])),
+            (4, &transfer_data([2; 20], 7)),
+        ]);
+        let type_url = "type.googleapis.com/protocol.TriggerSmartContract";
+
+        // two parameters are merged, so the contract address is the other token's
+        let mut contract = varint_field(1, 31);
+        contract.extend(len_field(2, &any(type_url, &usdt)));
+        contract.extend(len_field(2, &any(type_url, &other)));
+        assert!(parse_tx(&contract_tx(&contract)).is_none());
+
+        // and so are two values in one parameter
+        let mut parameter = any(type_url, &usdt);
+        parameter.extend(len_field(2, &other));
+        let mut contract = varint_field(1, 31);
+        contract.extend(len_field(2, &parameter));
+        assert!(parse_tx(&contract_tx(&contract)).is_none());
+    }
+
+    #[test]
+    fn regression_repeated_raw_data_and_contract_ret() {
+        let tx = usdt_transfer([1; 20], [2; 20], 1);
+        let raw_data = find_bytes(&tx, 1).unwrap();
+
+        let mut twice = len_field(1, raw_data);
+        twice.extend(len_field(1, raw_data));
+        twice.extend(len_field(5, &varint_field(3, 1)));
+        assert!(parse_tx(&twice).is_none());
+
+        // SUCCESS, then REVERT: java-tron reads REVERT
+        let mut ret = varint_field(3, 1);
+        ret.extend(varint_field(3, 2));
+        assert!(parse_tx(&with_ret(tx, &ret)).is_none());
+    }
+
+    #[test]
+    fn regression_short_vote_address() {
+        let mut vote = len_field(1, &[0x41; 5]);
+        vote.extend(varint_field(2, 5));
+        let mut vote_witness = len_field(1, &tron_address([1; 20]));
+        vote_witness.extend(len_field(2, &vote));
+        let tx = transaction(
+            4,
+            "type.googleapis.com/protocol.VoteWitnessContract",
+            &vote_witness,
+            1,
+        );
+
+        assert!(parse_vote_tx(&tx).is_none());
+    }
 }
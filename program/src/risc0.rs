@@ -0,0 +1,18 @@
+#![no_main]
+risc0_zkvm::guest::entry!(main);
+
+use risc0_zkvm::guest::env;
+
+// RISC Zero zkVM entrypoint. same inputs and public values as the SP1 one (see main.rs).
+// the host must write each input as a Vec<u8> (ExecutorEnvBuilder::write).
+pub fn main() {
+    let serialized_state: Vec<u8> = env::read();
+    let serialized_actions: Vec<u8> = env::read();
+    let serialized_blocks: Vec<u8> = env::read();
+
+    let public_values =
+        untron_program::execute(&serialized_state, &serialized_actions, &serialized_blocks);
+
+    // commit the public values into the journal
+    env::commit_slice(&public_values);
+}
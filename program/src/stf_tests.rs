@@ -0,0 +1,306 @@
+use crate::testing::*;
+use crate::*;
+
+// an action far in the future. stf requires that there's always a pending action
+// that's newer than the processed blocks, so that the relayer can't skip actions
+const FUTURE: u64 = TEST_START_TIMESTAMP + 1_000_000_000;
+
+const RECEIVER: [u8; 20] = [0xaa; 20];
+const SENDER: [u8; 20] = [0xbb; 20];
+
+// timestamp of the n-th block of a chain started with TestChain::new
+fn block_timestamp(n: u64) -> u64 {
+    TEST_START_TIMESTAMP + n * BLOCK_TIME
+}
+
+// blocks builds a chain segment of `count` blocks with txs put into the blocks at given positions (1-based)
+fn blocks(chain: &mut TestChain, count: u64, txs: Vec<(u64, Vec<u8>)>) -> Vec<RawBlock> {
+    let first = chain.number + 1;
+    (first..first + count)
+        .map(|number| {
+            let block_txs = txs
+                .iter()
+                .filter(|(n, _)| *n == number - first + 1)
+                .map(|(_, tx)| tx.clone())
+                .collect();
+            chain.block(block_txs)
+        })
+        .collect()
+}
+
+fn run(state: &mut State, actions: Vec<Action>, blocks: Vec<RawBlock>) -> Vec<([u8; 32], u64)> {
+    stf(state, Execution { actions, blocks })
+        .into_iter()
+        .map(|(order_id, order)| (order_id, order.inflow))
+        .collect()
+}
+
+#[test]
+fn order_is_opened_and_closed_by_transfers() {
+    let mut chain = TestChain::new(TEST_START_BLOCK);
+    let mut state = chain.state();
+    let mut actions = ActionChain::new(state.action_chain);
+
+    let (open, order_id) = actions.action(block_timestamp(5), RECEIVER, 0, 100);
+    let (future, _) = actions.action(FUTURE, [0xff; 20], 0, 0);
+
+    let blocks = blocks(
+        &mut chain,
+        130,
+        vec![
+            (10, usdt_transfer(SENDER, RECEIVER, 60)),
+            (20, usdt_transfer(SENDER, RECEIVER, 40)),
+            // the order is already closed, so this one is ignored
+            (30, usdt_transfer(SENDER, RECEIVER, 1000)),
+        ],
+    );
+
+    let closed = run(&mut state, vec![open, future], blocks);
+
+    assert_eq!(closed, vec![(order_id, 100)]);
+    assert!(state.orders.is_empty());
+    assert_eq!(state.action_chain, actions.tip);
+    assert_eq!(state.pending_actions.len(), 1);
+}
+
+#[test]
+fn unfinalized_blocks_are_verified_but_not_scanned() {
+    let mut chain = TestChain::new(TEST_START_BLOCK);
+    let mut state = chain.state();
+    let mut actions = ActionChain::new(state.action_chain);
+
+    let (open, order_id) = actions.action(block_timestamp(1), RECEIVER, 0, 100);
+    let (future, _) = actions.action(FUTURE, [0xff; 20], 0, 0);
+
+    // the transfer is in the 19th block from the end
+    let blocks = blocks(
+        &mut chain,
+        130,
+        vec![(112, usdt_transfer(SENDER, RECEIVER, 100))],
+    );
+    let latest_finalized_block_id = crypto::hash(&blocks[110].raw_data);
+
+    let closed = run(&mut state, vec![open, future], blocks);
+
+    assert!(closed.is_empty());
+    assert_eq!(state.orders[&order_id].inflow, 0);
+    assert_eq!(state.latest_block_id[8..], latest_finalized_block_id[8..]);
+    assert_eq!(
+        block_id_to_number(state.latest_block_id) as u64,
+        TEST_START_BLOCK + 111
+    );
+    assert_eq!(state.latest_timestamp, block_timestamp(111));
+    assert_eq!(state.cycle.len(), 19);
+}
+
+#[test]
+fn failed_transfers_and_transfer_from_are_ignored() {
+    let mut chain = TestChain::new(TEST_START_BLOCK);
+    let mut state = chain.state();
+    let mut actions = ActionChain::new(state.action_chain);
+
+    let (open, order_id) = actions.action(block_timestamp(1), RECEIVER, 0, 100);
+    let (future, _) = actions.action(FUTURE, [0xff; 20], 0, 0);
+
+    let blocks = blocks(
+        &mut chain,
+        130,
+        vec![
+            (10, failed(usdt_transfer(SENDER, RECEIVER, 100))),
+            (11, usdt_transfer_from(SENDER, SENDER, RECEIVER, 100)),
+            (12, usdt_transfer(SENDER, [0xcc; 20], 100)),
+            (13, usdt_transfer(SENDER, RECEIVER, 7)),
+        ],
+    );
+
+    let closed = run(&mut state, vec![open, future], blocks);
+
+    assert!(closed.is_empty());
+    assert_eq!(state.orders[&order_id].inflow, 7);
+}
+
+#[test]
+fn orders_stay_active_across_executions() {
+    let mut chain = TestChain::new(TEST_START_BLOCK);
+    let mut state = chain.state();
+    let mut actions = ActionChain::new(state.action_chain);
+
+    let (open, order_id) = actions.action(block_timestamp(1), RECEIVER, 0, 100);
+    let (future, _) = actions.action(FUTURE, [0xff; 20], 0, 0);
+
+    let first = blocks(&mut chain, 130, vec![]);
+    assert!(run(&mut state, vec![open, future], first.clone()).is_empty());
+    assert!(state.orders.contains_key(&order_id));
+
+    // the 19 unfinalized blocks of the first execution are verified again in the second one
+    let mut second = first[111..].to_vec();
+    second.extend(blocks(
+        &mut chain,
+        120,
+        vec![(1, usdt_transfer(SENDER, RECEIVER, 100))],
+    ));
+    assert_eq!(run(&mut state, vec![], second), vec![(order_id, 100)]);
+    assert!(state.orders.is_empty());
+}
+
+#[test]
+fn stopped_order_is_closed_with_its_inflow() {
+    let mut chain = TestChain::new(TEST_START_BLOCK);
+    let mut state = chain.state();
+    let mut actions = ActionChain::new(state.action_chain);
+
+    let (open, order_id) = actions.action(block_timestamp(1), RECEIVER, 0, 100);
+    let (stop, _) = actions.action(block_timestamp(20), RECEIVER, 0, 0);
+    let (future, _) = actions.action(FUTURE, [0xff; 20], 0, 0);
+
+    let blocks = blocks(
+        &mut chain,
+        130,
+        vec![
+            (10, usdt_transfer(SENDER, RECEIVER, 30)),
+            (30, usdt_transfer(SENDER, RECEIVER, 30)),
+        ],
+    );
+
+    let closed = run(&mut state, vec![open, stop, future], blocks);
+
+    assert_eq!(closed, vec![(order_id, 30)]);
+    assert!(state.orders.is_empty());
+}
+
+#[test]
+fn maintenance_rotates_srs() {
+    let maintenance_block =
+        MAINTENANCE_PERIOD_BLOCK_OFFSET as u64 + 9031 * MAINTENANCE_PERIOD_INTERVAL as u64;
+    let mut chain = TestChain::new(maintenance_block - 40);
+    let mut state = chain.state();
+    let mut actions = ActionChain::new(state.action_chain);
+    let (future, _) = actions.action(FUTURE, [0xff; 20], 0, 0);
+
+    // 28 candidates, the one with the fewest votes doesn't make it
+    let candidates: Vec<_> = (100..128).map(sr_key).collect();
+    let votes: Vec<_> = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, key)| (key_address(key), 1000 + i as u64))
+        .collect();
+
+    let mut execution_blocks = blocks(
+        &mut chain,
+        40,
+        vec![
+            (5, vote_tx(SENDER, &votes[..14])),
+            (6, vote_tx(SENDER, &votes[14..])),
+            // failed votes don't count
+            (7, failed(vote_tx(SENDER, &[(votes[0].0, 1_000_000)]))),
+        ],
+    );
+    assert_eq!(chain.number, maintenance_block);
+
+    chain.set_srs(candidates[1..].to_vec());
+    execution_blocks.extend(blocks(&mut chain, 100, vec![]));
+
+    run(&mut state, vec![future], execution_blocks);
+
+    let expected: Vec<[u8; 20]> = votes[1..].iter().map(|(address, _)| *address).collect();
+    assert_eq!(state.srs.to_vec(), expected);
+    assert!(state.votes.is_empty());
+}
+
+#[test]
+#[should_panic(expected = "state.srs.contains(&sr)")]
+fn forged_signature_is_rejected() {
+    let mut chain = TestChain::new(TEST_START_BLOCK);
+    let mut state = chain.state();
+    let (future, _) = ActionChain::new(state.action_chain).action(FUTURE, [0xff; 20], 0, 0);
+
+    let mut execution_blocks = blocks(&mut chain, 60, vec![]);
+    execution_blocks.push(chain.block_signed_by(vec![], &sr_key(1000)));
+    execution_blocks.extend(blocks(&mut chain, 60, vec![]));
+
+    run(&mut state, vec![future], execution_blocks);
+}
+
+#[test]
+#[should_panic(expected = "!cycle.contains(&sr)")]
+fn repeated_proposer_is_rejected() {
+    let mut chain = TestChain::new(TEST_START_BLOCK);
+    let mut state = chain.state();
+    let (future, _) = ActionChain::new(state.action_chain).action(FUTURE, [0xff; 20], 0, 0);
+
+    let mut execution_blocks = blocks(&mut chain, 60, vec![]);
+    // the SR that produced the previous block
+    let key = chain.srs[26].clone();
+    execution_blocks.push(chain.block_signed_by(vec![], &key));
+    execution_blocks.extend(blocks(&mut chain, 60, vec![]));
+
+    run(&mut state, vec![future], execution_blocks);
+}
+
+#[test]
+#[should_panic]
+fn tampered_transactions_are_rejected() {
+    let mut chain = TestChain::new(TEST_START_BLOCK);
+    let mut state = chain.state();
+    let mut actions = ActionChain::new(state.action_chain);
+    let (open, _) = actions.action(block_timestamp(1), RECEIVER, 0, 100);
+    let (future, _) = actions.action(FUTURE, [0xff; 20], 0, 0);
+
+    let mut execution_blocks = blocks(
+        &mut chain,
+        130,
+        vec![(10, usdt_transfer(SENDER, [0xcc; 20], 100))],
+    );
+    execution_blocks[9].txs[0] = usdt_transfer(SENDER, RECEIVER, 100);
+
+    run(&mut state, vec![open, future], execution_blocks);
+}
+
+#[test]
+fn actions_over_the_pending_limit_are_not_taken() {
+    let mut chain = TestChain::new(TEST_START_BLOCK);
+    let mut state = chain.state();
+    let mut actions = ActionChain::new(state.action_chain);
+
+    let future: Vec<(Action, [u8; 32])> = (0..MAX_PENDING_ACTIONS as u64 + 5)
+        .map(|i| actions.action(FUTURE + i, [0xff; 20], 0, 0))
+        .collect();
+    let (_, last_taken) = future[MAX_PENDING_ACTIONS - 1];
+
+    let execution_blocks = blocks(&mut chain, 130, vec![]);
+    run(
+        &mut state,
+        future.into_iter().map(|(action, _)| action).collect(),
+        execution_blocks,
+    );
+
+    assert_eq!(state.pending_actions.len(), MAX_PENDING_ACTIONS);
+    // the action chain ends at the last action taken, so the rest can come with the next execution
+    assert_eq!(state.action_chain, last_taken);
+}
+
+#[test]
+fn actions_applied_by_the_blocks_dont_count_towards_the_pending_limit() {
+    let mut chain = TestChain::new(TEST_START_BLOCK);
+    let mut state = chain.state();
+    let mut actions = ActionChain::new(state.action_chain);
+
+    // more actions than can be pending, all of them applied by the first block
+    let mut execution_actions: Vec<Action> = (0..MAX_PENDING_ACTIONS + 5)
+        .map(|_| actions.action(block_timestamp(1), RECEIVER, 0, 0).0)
+        .collect();
+    let (open, order_id) = actions.action(block_timestamp(1), RECEIVER, 0, 100);
+    execution_actions.push(open);
+    execution_actions.push(actions.action(FUTURE, [0xff; 20], 0, 0).0);
+
+    let execution_blocks = blocks(
+        &mut chain,
+        130,
+        vec![(10, usdt_transfer(SENDER, RECEIVER, 100))],
+    );
+    let closed = run(&mut state, execution_actions, execution_blocks);
+
+    assert_eq!(closed, vec![(order_id, 100)]);
+    assert_eq!(state.pending_actions.len(), 1);
+    assert_eq!(state.action_chain, actions.tip);
+}
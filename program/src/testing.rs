@@ -0,0 +1,300 @@
+use hex_literal::hex;
+use k256::ecdsa::SigningKey;
+
+use crate::{crypto, Action, RawBlock, State};
+
+// RATIONALE:
+// this module builds synthetic Tron blocks and transactions for tests,
+// so that the state transition function can be tested without mainnet data.
+// everything here is encoded the same way java-tron encodes it (see Tron.proto),
+// but only with the fields that a typical mainnet block or transaction has.
+// blocks are signed by a deterministic set of test SR keys, so all outputs are reproducible.
+
+// USDT smart contract TR7NHqjeKQxGTCi8q8ZY4pL8otSzgjLj6t (with 0x41 prefix)
+pub const USDT_CONTRACT: [u8; 21] = hex!("41a614f803b6fd780986a42c78ec9c7f77e6ded13c");
+
+// a mainnet-like block number to start test chains from
+pub const TEST_START_BLOCK: u64 = 65_000_000;
+
+// a mainnet-like timestamp of TEST_START_BLOCK
+pub const TEST_START_TIMESTAMP: u64 = 1_727_600_000_000;
+
+// protobuf wire types
+const VARINT: u64 = 0;
+const LEN: u64 = 2;
+
+// Transaction.Result.contractResult values
+pub const CONTRACT_SUCCESS: u64 = 1;
+pub const CONTRACT_REVERT: u64 = 2;
+
+// Transaction.Contract.ContractType values
+const VOTE_WITNESS_CONTRACT: u64 = 4;
+const TRIGGER_SMART_CONTRACT: u64 = 31;
+
+pub fn varint(mut value: u64) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            encoded.push(byte);
+            return encoded;
+        }
+        encoded.push(byte | 0x80);
+    }
+}
+
+pub fn varint_field(field: u64, value: u64) -> Vec<u8> {
+    let mut encoded = varint(field << 3 | VARINT);
+    encoded.extend(varint(value));
+    encoded
+}
+
+pub fn len_field(field: u64, value: &[u8]) -> Vec<u8> {
+    let mut encoded = varint(field << 3 | LEN);
+    encoded.extend(varint(value.len() as u64));
+    encoded.extend_from_slice(value);
+    encoded
+}
+
+// tron_address adds the 0x41 prefix to an address
+pub fn tron_address(address: [u8; 20]) -> [u8; 21] {
+    let mut tron_address = [0x41; 21];
+    tron_address[1..].copy_from_slice(&address);
+    tron_address
+}
+
+// sr_key returns the i-th deterministic test key
+pub fn sr_key(i: u32) -> SigningKey {
+    let seed = crypto::hash(format!("untron test key {}", i).as_bytes());
+    SigningKey::from_bytes(&seed.into()).unwrap()
+}
+
+// key_address returns the Tron address of the key (without 0x41 prefix)
+pub fn key_address(key: &SigningKey) -> [u8; 20] {
+    let public_key = key.verifying_key().to_encoded_point(false);
+    crypto::public_key_to_address(&public_key.as_bytes()[1..])
+}
+
+// transaction builds a signed transaction with one contract and its execution result
+pub fn transaction(
+    contract_type: u64,
+    type_url: &str,
+    parameter: &[u8],
+    contract_ret: u64,
+) -> Vec<u8> {
+    let mut any = len_field(1, type_url.as_bytes());
+    any.extend(len_field(2, parameter));
+
+    let mut contract = varint_field(1, contract_type);
+    contract.extend(len_field(2, &any));
+
+    let mut raw = len_field(1, &hex!("3a6c")); // ref_block_bytes
+    raw.extend(len_field(4, &hex!("1f2c3d4e5f607182"))); // ref_block_hash
+    raw.extend(varint_field(8, TEST_START_TIMESTAMP + 60_000)); // expiration
+    raw.extend(len_field(11, &contract));
+    raw.extend(varint_field(14, TEST_START_TIMESTAMP)); // timestamp
+    if contract_type == TRIGGER_SMART_CONTRACT {
+        raw.extend(varint_field(18, 100_000_000)); // fee_limit
+    }
+
+    let mut tx = len_field(1, &raw);
+    // transaction signatures are not checked by the program, so any 65 bytes will do
+    tx.extend(len_field(2, &[0x5a; 65]));
+    tx.extend(len_field(5, &varint_field(3, contract_ret)));
+    tx
+}
+
+// trigger_usdt builds a TriggerSmartContract transaction calling the USDT contract
+pub fn trigger_usdt(owner: [u8; 20], data: &[u8], contract_ret: u64) -> Vec<u8> {
+    let mut trigger = len_field(1, &tron_address(owner));
+    trigger.extend(len_field(2, &USDT_CONTRACT));
+    trigger.extend(len_field(4, data));
+
+    transaction(
+        TRIGGER_SMART_CONTRACT,
+        "type.googleapis.com/protocol.TriggerSmartContract",
+        &trigger,
+        contract_ret,
+    )
+}
+
+fn abi_address(address: [u8; 20]) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(&address);
+    word
+}
+
+fn abi_uint(value: u64) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[24..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+// usdt_transfer builds a successful USDT transfer(to, value) transaction
+pub fn usdt_transfer(from: [u8; 20], to: [u8; 20], value: u64) -> Vec<u8> {
+    let mut data = hex!("a9059cbb").to_vec();
+    data.extend(abi_address(to));
+    data.extend(abi_uint(value));
+    trigger_usdt(from, &data, CONTRACT_SUCCESS)
+}
+
+// usdt_transfer_from builds a successful USDT transferFrom(from, to, value) transaction
+pub fn usdt_transfer_from(spender: [u8; 20], from: [u8; 20], to: [u8; 20], value: u64) -> Vec<u8> {
+    let mut data = hex!("23b872dd").to_vec();
+    data.extend(abi_address(from));
+    data.extend(abi_address(to));
+    data.extend(abi_uint(value));
+    trigger_usdt(spender, &data, CONTRACT_SUCCESS)
+}
+
+// vote_tx builds a successful VoteWitnessContract transaction
+pub fn vote_tx(voter: [u8; 20], votes: &[([u8; 20], u64)]) -> Vec<u8> {
+    let mut vote_witness = len_field(1, &tron_address(voter));
+    for (witness_address, votes_count) in votes {
+        let mut vote = len_field(1, &tron_address(*witness_address));
+        // proto3 omits zero values
+        if *votes_count != 0 {
+            vote.extend(varint_field(2, *votes_count));
+        }
+        vote_witness.extend(len_field(2, &vote));
+    }
+
+    transaction(
+        VOTE_WITNESS_CONTRACT,
+        "type.googleapis.com/protocol.VoteWitnessContract",
+        &vote_witness,
+        CONTRACT_SUCCESS,
+    )
+}
+
+// failed turns a successful transaction into a reverted one
+pub fn failed(mut tx: Vec<u8>) -> Vec<u8> {
+    let last = tx.len() - 1;
+    assert_eq!(tx[last], CONTRACT_SUCCESS as u8);
+    tx[last] = CONTRACT_REVERT as u8;
+    tx
+}
+
+// block_id builds the id of a block from its number and raw_data hash, like Tron does
+pub fn block_id(number: u64, raw_data_hash: [u8; 32]) -> [u8; 32] {
+    let mut id = raw_data_hash;
+    id[..8].copy_from_slice(&number.to_be_bytes());
+    id
+}
+
+// TestChain produces a valid chain of blocks on top of a genesis block,
+// signed by its SRs in round-robin order
+pub struct TestChain {
+    // keys of the current SRs
+    pub srs: Vec<SigningKey>,
+    // number, id and timestamp of the latest produced block
+    pub number: u64,
+    pub latest_block_id: [u8; 32],
+    pub timestamp: u64,
+    // index of the SR that produces the next block
+    next_sr: usize,
+}
+
+impl TestChain {
+    pub fn new(number: u64) -> Self {
+        Self {
+            srs: (0..27).map(sr_key).collect(),
+            number,
+            latest_block_id: block_id(number, [0; 32]),
+            timestamp: TEST_START_TIMESTAMP,
+            next_sr: 0,
+        }
+    }
+
+    // state returns the program state that trusts the current tip and SRs of the chain
+    pub fn state(&self) -> State {
+        let mut state = State {
+            latest_block_id: self.latest_block_id,
+            latest_timestamp: self.timestamp,
+            ..Default::default()
+        };
+        for (sr, key) in state.srs.iter_mut().zip(self.srs.iter()) {
+            *sr = key_address(key);
+        }
+        state
+    }
+
+    // set_srs replaces the SR set (e.g. after a maintenance period)
+    pub fn set_srs(&mut self, srs: Vec<SigningKey>) {
+        self.srs = srs;
+        self.next_sr = 0;
+    }
+
+    // block produces the next block, signed by the next SR
+    pub fn block(&mut self, txs: Vec<Vec<u8>>) -> RawBlock {
+        let key = self.srs[self.next_sr].clone();
+        self.next_sr = (self.next_sr + 1) % self.srs.len();
+        self.block_signed_by(txs, &key)
+    }
+
+    // block_signed_by produces the next block, signed by the given key
+    pub fn block_signed_by(&mut self, txs: Vec<Vec<u8>>, key: &SigningKey) -> RawBlock {
+        let number = self.number + 1;
+        let timestamp = self.timestamp + crate::BLOCK_TIME;
+
+        let tx_hashes: Vec<[u8; 32]> = txs.iter().map(|tx| crypto::hash(tx)).collect();
+
+        let mut raw_data = varint_field(1, timestamp);
+        raw_data.extend(len_field(2, &crypto::create_merkle_tree(&tx_hashes)));
+        raw_data.extend(len_field(3, &self.latest_block_id));
+        raw_data.extend(varint_field(7, number));
+        raw_data.extend(len_field(9, &tron_address(key_address(key))));
+        raw_data.extend(varint_field(10, 30)); // version
+
+        let raw_data_hash = crypto::hash(&raw_data);
+        let (signature, recid) = key.sign_prehash_recoverable(&raw_data_hash).unwrap();
+        let mut signature = signature.to_bytes().to_vec();
+        signature.push(recid.to_byte());
+
+        self.number = number;
+        self.timestamp = timestamp;
+        self.latest_block_id = block_id(number, raw_data_hash);
+
+        RawBlock {
+            raw_data,
+            signature,
+            txs,
+        }
+    }
+
+    // blocks produces n empty blocks
+    pub fn blocks(&mut self, n: usize) -> Vec<RawBlock> {
+        (0..n).map(|_| self.block(vec![])).collect()
+    }
+}
+
+// ActionChain builds chained actions the same way the Untron contract does
+pub struct ActionChain {
+    pub tip: [u8; 32],
+}
+
+impl ActionChain {
+    pub fn new(tip: [u8; 32]) -> Self {
+        Self { tip }
+    }
+
+    // action builds the next action and returns it with its id (the new tip of the chain)
+    pub fn action(
+        &mut self,
+        timestamp: u64,
+        address: [u8; 20],
+        min_deposit: u64,
+        size: u64,
+    ) -> (Action, [u8; 32]) {
+        let action = Action {
+            prev: self.tip,
+            timestamp,
+            address,
+            min_deposit,
+            size,
+        };
+        self.tip = crypto::hash(&action.abi_encode());
+        (action, self.tip)
+    }
+}
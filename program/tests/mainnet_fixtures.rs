@@ -0,0 +1,168 @@
+// replays the recorded mainnet blocks in program/fixtures (see src/fixtures.rs)
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use untron_program::fixtures::{Fixture, FixtureBlock};
+use untron_program::{crypto, protobuf, stf, Action, Execution, RawBlock, State};
+
+fn load_fixtures() -> Vec<(String, Fixture)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+
+    assert!(
+        !paths.is_empty(),
+        "no mainnet fixtures found, see program/fixtures/README.md to record them"
+    );
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let fixture = serde_json::from_slice(&fs::read(&path).unwrap())
+                .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            (path.display().to_string(), fixture)
+        })
+        .collect()
+}
+
+fn check_block(name: &str, block: &FixtureBlock) {
+    let raw_data_hash = crypto::hash(&block.raw_data);
+
+    let header = protobuf::parse_block_header(block.parent_id, &block.raw_data, raw_data_hash);
+    assert_eq!(
+        header.new_block_id, block.id,
+        "{} #{}: id",
+        name, block.number
+    );
+    assert_eq!(header.tx_root, block.tx_root, "{} #{}", name, block.number);
+    assert_eq!(
+        header.timestamp, block.timestamp,
+        "{} #{}",
+        name, block.number
+    );
+
+    let public_key = crypto::recover_public_key(&block.witness_signature, raw_data_hash);
+    assert_eq!(
+        crypto::public_key_to_address(&public_key),
+        block.witness_address,
+        "{} #{}: witness",
+        name,
+        block.number
+    );
+
+    let tx_hashes: Vec<[u8; 32]> = block.txs.iter().map(|tx| crypto::hash(&tx.raw)).collect();
+    assert_eq!(
+        crypto::create_merkle_tree(&tx_hashes),
+        block.tx_root,
+        "{} #{}: tx root",
+        name,
+        block.number
+    );
+
+    for (i, tx) in block.txs.iter().enumerate() {
+        let transfer = protobuf::parse_usdt_transfer(&tx.raw).map(|t| (t.to, t.value));
+        let expected = tx.usdt_transfer.as_ref().map(|t| (t.to, t.value));
+        assert_eq!(transfer, expected, "{} #{} tx {}", name, block.number, i);
+
+        let vote = protobuf::parse_vote_tx(&tx.raw).map(|vote_tx| {
+            let votes: Vec<_> = vote_tx
+                .votes
+                .iter()
+                .map(|vote| (vote.witness_address, vote.votes_count))
+                .collect();
+            (vote_tx.voter, votes)
+        });
+        let expected = tx.vote.as_ref().map(|vote| {
+            let votes: Vec<_> = vote
+                .votes
+                .iter()
+                .map(|vote| (vote.witness_address, vote.votes_count))
+                .collect();
+            (vote.voter, votes)
+        });
+        assert_eq!(vote, expected, "{} #{} tx {}", name, block.number, i);
+    }
+}
+
+#[test]
+fn blocks_parse_like_prost() {
+    for (name, fixture) in load_fixtures() {
+        for block in &fixture.blocks {
+            check_block(&name, block);
+        }
+    }
+}
+
+#[test]
+fn blocks_are_consecutive() {
+    for (name, fixture) in load_fixtures() {
+        for pair in fixture.blocks.windows(2) {
+            assert_eq!(pair[1].number, pair[0].number + 1, "{}", name);
+            assert_eq!(pair[1].parent_id, pair[0].id, "{}", name);
+        }
+    }
+}
+
+#[test]
+fn blocks_replay_through_stf() {
+    for (name, fixture) in load_fixtures() {
+        if !fixture.replay_stf {
+            continue;
+        }
+
+        let first = &fixture.blocks[0];
+        let producers: BTreeSet<[u8; 20]> = fixture
+            .blocks
+            .iter()
+            .map(|block| block.witness_address)
+            .collect();
+        assert_eq!(producers.len(), 27, "{}", name);
+
+        let mut state = State {
+            latest_block_id: first.parent_id,
+            latest_timestamp: first.timestamp - untron_program::BLOCK_TIME,
+            ..Default::default()
+        };
+        for (sr, producer) in state.srs.iter_mut().zip(producers) {
+            *sr = producer;
+        }
+
+        // stf needs an action newer than all blocks to prove that no actions were skipped
+        let future = Action {
+            prev: [0; 32],
+            timestamp: u64::MAX,
+            address: [0xff; 20],
+            min_deposit: 0,
+            size: 0,
+        };
+
+        let blocks = fixture
+            .blocks
+            .iter()
+            .map(|block| RawBlock {
+                raw_data: block.raw_data.clone(),
+                signature: block.witness_signature.clone(),
+                txs: block.txs.iter().map(|tx| tx.raw.clone()).collect(),
+            })
+            .collect();
+
+        let closed_orders = stf(
+            &mut state,
+            Execution {
+                actions: vec![future],
+                blocks,
+            },
+        );
+
+        let finalized = &fixture.blocks[fixture.blocks.len() - 20];
+        assert!(closed_orders.is_empty(), "{}", name);
+        assert_eq!(state.latest_block_id, finalized.id, "{}", name);
+        assert_eq!(state.latest_timestamp, finalized.timestamp, "{}", name);
+    }
+}
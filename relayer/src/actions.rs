@@ -0,0 +1,453 @@
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{info, warn};
+use untron_program::{crypto, Action};
+
+// RATIONALE:
+// actions come from two places that overlap: logs backfilled with eth_getLogs (the ones emitted
+// while the relayer was down) and the subscription to new events, which is opened before
+// the backfill so that nothing emitted during it is missed.
+// every action is chained to the previous one by its hash, so ActionFeed passes each action
+// exactly once, in chain order, drops the ones it has already passed, and fails on any gap.
+//
+// the subscription is a WebSocket that can drop at any time, so the listener runs in its own task:
+// whenever the subscription fails, it subscribes again (with backoff) and backfills what it missed,
+// and whenever an event doesn't follow the latest action, it refills the gap from the logs.
+// the relayer doesn't prove while the listener isn't live, since it may be missing actions.
+// errors here are Send + Sync so that they can be held in the listener's task.
+
+// delay before the first reconnection, doubled on every next one
+pub const LISTENER_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_LISTENER_BACKOFF: Duration = Duration::from_secs(60);
+
+pub type ActionStream<'a> = BoxStream<'a, Result<Action, Box<dyn Error + Send + Sync>>>;
+
+// ActionSource is where the actions of the Core come from (see zksync.rs)
+#[async_trait]
+pub trait ActionSource: Send + Sync {
+    // subscribe returns the actions emitted from now on
+    async fn subscribe(&self) -> Result<ActionStream<'_>, Box<dyn Error + Send + Sync>>;
+
+    // missed returns the actions after `tip` that were already emitted,
+    // checked against the latest action of the Core
+    async fn missed(&self, tip: [u8; 32]) -> Result<Vec<Action>, Box<dyn Error + Send + Sync>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerHealth {
+    // subscribing and backfilling the missed actions
+    Connecting,
+    // all actions up to now were sent, and new ones are followed
+    Live,
+    // the subscription failed this many times in a row, waiting to subscribe again
+    Reconnecting { failures: u32 },
+}
+
+// spawn_listener sends the actions after `tip` to `actions` until the receiver is dropped
+pub fn spawn_listener(
+    source: Arc<dyn ActionSource>,
+    tip: [u8; 32],
+    actions: mpsc::Sender<Action>,
+    backoff: Duration,
+) -> (JoinHandle<()>, watch::Receiver<ListenerHealth>) {
+    let (health_tx, health) = watch::channel(ListenerHealth::Connecting);
+    let task = tokio::spawn(listen(source, tip, actions, backoff, health_tx));
+    (task, health)
+}
+
+async fn listen(
+    source: Arc<dyn ActionSource>,
+    tip: [u8; 32],
+    actions: mpsc::Sender<Action>,
+    backoff: Duration,
+    health: watch::Sender<ListenerHealth>,
+) {
+    let mut feed = ActionFeed::new(tip);
+    let mut failures = 0;
+
+    loop {
+        health.send_replace(ListenerHealth::Connecting);
+        let result = follow(source.as_ref(), &mut feed, &actions, &health, &mut failures).await;
+        if actions.is_closed() {
+            return;
+        }
+        match result {
+            Ok(()) => warn!("Action subscription closed"),
+            Err(e) => warn!("Action listener failed: {}", e),
+        }
+
+        failures += 1;
+        health.send_replace(ListenerHealth::Reconnecting { failures });
+        let delay = backoff
+            .saturating_mul(2u32.saturating_pow(failures - 1))
+            .min(MAX_LISTENER_BACKOFF);
+        info!("Reconnecting the action listener in {:?}", delay);
+        sleep(delay).await;
+    }
+}
+
+// follow subscribes, backfills the missed actions, then sends the new ones until the subscription ends
+async fn follow(
+    source: &dyn ActionSource,
+    feed: &mut ActionFeed,
+    actions: &mpsc::Sender<Action>,
+    health: &watch::Sender<ListenerHealth>,
+    failures: &mut u32,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut subscription = source.subscribe().await?;
+
+    let missed = source.missed(feed.tip()).await?;
+    if !missed.is_empty() {
+        info!("Backfilled {} missed actions", missed.len());
+    }
+    send(feed, missed, actions).await?;
+
+    health.send_replace(ListenerHealth::Live);
+    *failures = 0;
+
+    while let Some(action) = subscription.next().await {
+        let action = action?;
+        info!("Received action: {:?}", action);
+
+        if !feed.follows(&action) {
+            warn!(
+                "Action {} doesn't follow the latest action {}, refilling the gap from the logs",
+                hex::encode(action.prev),
+                hex::encode(feed.tip())
+            );
+            let missed = source.missed(feed.tip()).await?;
+            send(feed, missed, actions).await?;
+        }
+        send(feed, vec![action], actions).await?;
+    }
+
+    Ok(())
+}
+
+// send passes the actions through the feed to the relayer
+async fn send(
+    feed: &mut ActionFeed,
+    new: Vec<Action>,
+    actions: &mpsc::Sender<Action>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for action in new {
+        if let Some(action) = feed.accept(action)? {
+            actions.send(action).await?;
+        }
+    }
+    Ok(())
+}
+
+// ActionFeed follows the action chain from a tip
+pub struct ActionFeed {
+    // hash of the latest passed action
+    tip: [u8; 32],
+    // hashes of all passed actions, to drop duplicates
+    seen: HashSet<[u8; 32]>,
+}
+
+impl ActionFeed {
+    pub fn new(tip: [u8; 32]) -> Self {
+        Self {
+            tip,
+            seen: HashSet::from([tip]),
+        }
+    }
+
+    pub fn tip(&self) -> [u8; 32] {
+        self.tip
+    }
+
+    // follows tells whether the action is the next one in the chain or was already passed
+    pub fn follows(&self, action: &Action) -> bool {
+        action.prev == self.tip || self.seen.contains(&crypto::hash(&action.abi_encode()))
+    }
+
+    // accept returns the action if it's the next one in the chain, or None if it was already passed
+    pub fn accept(
+        &mut self,
+        action: Action,
+    ) -> Result<Option<Action>, Box<dyn Error + Send + Sync>> {
+        let id = crypto::hash(&action.abi_encode());
+        if self.seen.contains(&id) {
+            return Ok(None);
+        }
+        if action.prev != self.tip {
+            return Err(format!(
+                "Action {} doesn't follow the latest action {}",
+                hex::encode(id),
+                hex::encode(self.tip)
+            )
+            .into());
+        }
+
+        self.tip = id;
+        self.seen.insert(id);
+        Ok(Some(action))
+    }
+}
+
+// ActionBuffer keeps the received actions until they're executed, in chain order.
+// the Core stamps every action with its block timestamp, so that's also timestamp order
+#[derive(Default)]
+pub struct ActionBuffer {
+    actions: VecDeque<Action>,
+}
+
+impl ActionBuffer {
+    pub fn new(actions: Vec<Action>) -> Self {
+        Self {
+            actions: actions.into(),
+        }
+    }
+
+    // drain moves the actions the listener has sent so far into the buffer and returns them.
+    // it never waits for more
+    pub fn drain(
+        &mut self,
+        rx: &mut mpsc::Receiver<Action>,
+    ) -> Result<Vec<Action>, Box<dyn Error>> {
+        let mut received = vec![];
+        loop {
+            match rx.try_recv() {
+                Ok(action) => received.push(action),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Err("Action listener stopped".into()),
+            }
+        }
+        self.actions.extend(received.iter().cloned());
+        Ok(received)
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    // latest returns the latest buffered action
+    pub fn latest(&self) -> Option<&Action> {
+        self.actions.back()
+    }
+
+    // take removes the actions up to `timestamp` and the first one after it, if there's any.
+    // that one tells the program that there are no more actions up to `timestamp`
+    pub fn take(&mut self, timestamp: u64) -> Vec<Action> {
+        let count = self
+            .actions
+            .iter()
+            .position(|action| action.timestamp > timestamp)
+            .map_or(self.actions.len(), |after| after + 1);
+        self.actions.drain(..count).collect()
+    }
+}
+
+// missed_actions returns the actions after `tip` from the actions of a range of blocks,
+// or None if `tip` isn't in it
+pub fn missed_actions(actions: &[Action], tip: [u8; 32]) -> Option<&[Action]> {
+    actions
+        .iter()
+        .position(|action| action.prev == tip)
+        .map(|first| &actions[first..])
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::sync::Mutex;
+
+    type Subscription = mpsc::UnboundedReceiver<Result<Action, Box<dyn Error + Send + Sync>>>;
+
+    // MockActions is the Core for tests: `chain` has all actions emitted so far,
+    // and every subscription takes the next receiver in `subscriptions` (or fails if there's none)
+    #[derive(Default)]
+    pub struct MockActions {
+        pub chain: Mutex<Vec<Action>>,
+        subscriptions: Mutex<Vec<Subscription>>,
+    }
+
+    impl MockActions {
+        // subscription adds a subscription and returns the sender of its events
+        pub fn subscription(
+            &self,
+        ) -> mpsc::UnboundedSender<Result<Action, Box<dyn Error + Send + Sync>>> {
+            let (tx, rx) = mpsc::unbounded_channel();
+            self.subscriptions.lock().unwrap().push(rx);
+            tx
+        }
+    }
+
+    #[async_trait]
+    impl ActionSource for MockActions {
+        async fn subscribe(&self) -> Result<ActionStream<'_>, Box<dyn Error + Send + Sync>> {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            if subscriptions.is_empty() {
+                return Err("connection refused".into());
+            }
+            let rx = subscriptions.remove(0);
+            Ok(futures::stream::unfold(rx, |mut rx| async move {
+                rx.recv().await.map(|event| (event, rx))
+            })
+            .boxed())
+        }
+
+        async fn missed(&self, tip: [u8; 32]) -> Result<Vec<Action>, Box<dyn Error + Send + Sync>> {
+            let chain = self.chain.lock().unwrap();
+            // with no actions yet, the tip is the genesis one
+            let latest = chain
+                .last()
+                .map(|action| crypto::hash(&action.abi_encode()));
+            if latest.is_none() || latest == Some(tip) {
+                return Ok(vec![]);
+            }
+            Ok(missed_actions(&chain, tip).ok_or("unknown tip")?.to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockActions;
+    use super::*;
+    use untron_program::testing::*;
+
+    fn actions(n: u64) -> (Vec<Action>, Vec<[u8; 32]>) {
+        let mut chain = ActionChain::new([0; 32]);
+        (0..n)
+            .map(|i| chain.action(TEST_START_TIMESTAMP + i, [0xaa; 20], 0, i))
+            .unzip()
+    }
+
+    fn sizes(actions: &[Action]) -> Vec<u64> {
+        actions.iter().map(|action| action.size).collect()
+    }
+
+    #[test]
+    fn feed_drops_duplicates_and_fails_on_gaps() {
+        let (actions, ids) = actions(4);
+        let mut feed = ActionFeed::new(ids[0]);
+
+        // the first one is the tip the feed starts from
+        assert!(feed.accept(actions[0].clone()).unwrap().is_none());
+        assert!(feed.accept(actions[1].clone()).unwrap().is_some());
+        assert!(feed.accept(actions[1].clone()).unwrap().is_none());
+        assert!(feed.accept(actions[3].clone()).is_err());
+        assert!(feed.accept(actions[2].clone()).unwrap().is_some());
+        assert!(feed.accept(actions[3].clone()).unwrap().is_some());
+        assert_eq!(feed.tip(), ids[3]);
+    }
+
+    #[tokio::test]
+    async fn buffer_drains_without_waiting_and_takes_by_timestamp() {
+        let (actions, _) = actions(4);
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut buffer = ActionBuffer::default();
+
+        assert!(buffer.drain(&mut rx).unwrap().is_empty());
+        for action in &actions {
+            tx.send(action.clone()).await.unwrap();
+        }
+        assert_eq!(sizes(&buffer.drain(&mut rx).unwrap()), [0, 1, 2, 3]);
+        assert_eq!(buffer.latest().unwrap().size, 3);
+
+        // the actions up to the timestamp of action 1, and action 2 that comes after it
+        assert_eq!(sizes(&buffer.take(TEST_START_TIMESTAMP + 1)), [0, 1, 2]);
+        assert_eq!(sizes(&buffer.take(TEST_START_TIMESTAMP + 10)), [3]);
+        assert!(buffer.is_empty());
+
+        drop(tx);
+        assert!(buffer.drain(&mut rx).is_err());
+    }
+
+    #[test]
+    fn finds_missed_actions() {
+        let (actions, ids) = actions(4);
+
+        assert_eq!(sizes(missed_actions(&actions, ids[1]).unwrap()), [2, 3]);
+        assert_eq!(
+            sizes(missed_actions(&actions, [0; 32]).unwrap()),
+            [0, 1, 2, 3]
+        );
+        assert!(missed_actions(&actions, ids[3]).is_none());
+        assert!(missed_actions(&actions[2..], ids[0]).is_none());
+    }
+
+    async fn received(rx: &mut mpsc::Receiver<Action>, n: usize) -> Vec<u64> {
+        let mut sizes = vec![];
+        for _ in 0..n {
+            sizes.push(rx.recv().await.unwrap().size);
+        }
+        sizes
+    }
+
+    async fn wait_for(health: &mut watch::Receiver<ListenerHealth>, expected: ListenerHealth) {
+        health.wait_for(|health| *health == expected).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn listener_reconnects_and_backfills() {
+        let (all, ids) = actions(6);
+        let source = Arc::new(MockActions::default());
+        *source.chain.lock().unwrap() = all[..2].to_vec();
+        let first = source.subscription();
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let (_task, mut health) =
+            spawn_listener(source.clone(), ids[0], tx, Duration::from_millis(10));
+
+        // action 1 is backfilled, then 2 comes from the subscription
+        assert_eq!(received(&mut rx, 1).await, [1]);
+        wait_for(&mut health, ListenerHealth::Live).await;
+        source.chain.lock().unwrap().push(all[2].clone());
+        first.send(Ok(all[2].clone())).unwrap();
+        assert_eq!(received(&mut rx, 1).await, [2]);
+
+        // the subscription drops, and 3 and 4 are emitted while the listener can't subscribe again
+        first.send(Err("connection reset".into())).unwrap();
+        let retried = |health: &ListenerHealth| {
+            matches!(health, ListenerHealth::Reconnecting { failures: 2.. })
+        };
+        health.wait_for(retried).await.unwrap();
+        source.chain.lock().unwrap().extend(all[3..5].to_vec());
+        let second = source.subscription();
+
+        // they're backfilled once it's back, and the duplicate from the subscription is dropped
+        assert_eq!(received(&mut rx, 2).await, [3, 4]);
+        wait_for(&mut health, ListenerHealth::Live).await;
+        source.chain.lock().unwrap().push(all[5].clone());
+        second.send(Ok(all[4].clone())).unwrap();
+        second.send(Ok(all[5].clone())).unwrap();
+        assert_eq!(received(&mut rx, 1).await, [5]);
+    }
+
+    #[tokio::test]
+    async fn listener_refills_gaps() {
+        let (all, ids) = actions(4);
+        let source = Arc::new(MockActions::default());
+        *source.chain.lock().unwrap() = all[..1].to_vec();
+        let subscription = source.subscription();
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let (_task, mut health) =
+            spawn_listener(source.clone(), ids[0], tx, Duration::from_millis(10));
+        wait_for(&mut health, ListenerHealth::Live).await;
+
+        // the event of action 1 is lost
+        source.chain.lock().unwrap().extend(all[1..].to_vec());
+        subscription.send(Ok(all[2].clone())).unwrap();
+        subscription.send(Ok(all[3].clone())).unwrap();
+        assert_eq!(received(&mut rx, 3).await, [1, 2, 3]);
+        assert_eq!(*health.borrow(), ListenerHealth::Live);
+    }
+}
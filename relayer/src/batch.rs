@@ -0,0 +1,251 @@
+use crate::executor::{MIN_BLOCKS, VERIFIED_BLOCKS};
+use crate::tron::block_timestamp;
+use std::error::Error;
+use std::future::Future;
+use tracing::{info, warn};
+use untron_program::migration::{decode_state, encode_state};
+use untron_program::{stf, Action, Execution, RawBlock};
+
+// RATIONALE:
+// a proof covers everything executed since the latest one, and its cost grows with the blocks in it.
+// a batch that's too big for the prover only fails after hours of proving, so before proving,
+// the relayer executes the batch in the zkVM to count its cycles (see max_cycles in [prover]),
+// and splits it into consecutive batches until each of them fits.
+// any block can end a batch, as long as the batch has all actions up to it and the first one after
+// (like an execution of executor.rs), and scans at least ORDER_TTL + 1 blocks. so splitting needs
+// no proof or execution from before: the state at the split is computed again natively.
+// a batch too small to split is proven as is, since there's no smaller proof of it.
+
+// the fewest blocks a batch scans
+pub const MIN_SCANNED: usize = MIN_BLOCKS - VERIFIED_BLOCKS;
+
+// Batch is the input of one proof: the proven state and the actions and blocks on top of it
+#[derive(Clone)]
+pub struct Batch {
+    // exact bytes of the state, as hashed in the Core contract
+    pub state: Vec<u8>,
+    // number of the latest block of the state
+    pub proven_block: u32,
+    pub actions: Vec<Action>,
+    pub blocks: Vec<RawBlock>,
+}
+
+impl Batch {
+    // scanned is the number of blocks whose contents the batch executes
+    pub fn scanned(&self) -> usize {
+        self.blocks.len() - VERIFIED_BLOCKS
+    }
+
+    // scanned_block is the number of the latest block the batch scans,
+    // which is the latest block of the state it proves
+    pub fn scanned_block(&self) -> u32 {
+        self.proven_block + self.scanned() as u32
+    }
+
+    // split splits the batch into two consecutive ones, the first of which scans `at` blocks
+    pub fn split(&self, at: usize) -> Result<(Batch, Batch), Box<dyn Error>> {
+        if at < MIN_SCANNED || self.scanned().saturating_sub(at) < MIN_SCANNED {
+            return Err(format!(
+                "Can't split a batch scanning {} blocks after {} blocks",
+                self.scanned(),
+                at
+            )
+            .into());
+        }
+
+        let timestamp = block_timestamp(&self.blocks[at - 1])?;
+        let count = self
+            .actions
+            .iter()
+            .position(|action| action.timestamp > timestamp)
+            .map_or(self.actions.len(), |after| after + 1);
+        let first = Batch {
+            state: self.state.clone(),
+            proven_block: self.proven_block,
+            actions: self.actions[..count].to_vec(),
+            blocks: self.blocks[..at + VERIFIED_BLOCKS].to_vec(),
+        };
+
+        let mut state = decode_state(&self.state)?;
+        stf(
+            &mut state,
+            Execution {
+                actions: first.actions.clone(),
+                blocks: first.blocks.clone(),
+            },
+        );
+        let second = Batch {
+            state: encode_state(&state),
+            proven_block: first.scanned_block(),
+            actions: self.actions[count..].to_vec(),
+            blocks: self.blocks[at..].to_vec(),
+        };
+        Ok((first, second))
+    }
+}
+
+// plan splits `batch` into consecutive batches of at most `max_cycles` cycles each,
+// where `cycles` executes a batch and returns its cycle count
+pub async fn plan<F, Fut>(
+    batch: Batch,
+    max_cycles: u64,
+    mut cycles: F,
+) -> Result<Vec<Batch>, Box<dyn Error>>
+where
+    F: FnMut(&Batch) -> Fut,
+    Fut: Future<Output = Result<u64, Box<dyn Error>>>,
+{
+    let mut planned = vec![];
+    // the next batch is on top
+    let mut remaining = vec![batch];
+    while let Some(batch) = remaining.pop() {
+        let count = cycles(&batch).await?;
+        if count <= max_cycles {
+            planned.push(batch);
+            continue;
+        }
+        if batch.scanned() < 2 * MIN_SCANNED {
+            warn!(
+                "Blocks {}..={} take {} cycles, over the budget of {}, but can't be split further",
+                batch.proven_block + 1,
+                batch.scanned_block(),
+                count,
+                max_cycles
+            );
+            planned.push(batch);
+            continue;
+        }
+
+        // cycles mostly grow with the blocks, so the first part gets as many as should fit
+        let fit = (batch.blocks.len() as u64 * max_cycles / count) as usize;
+        let at = fit
+            .saturating_sub(VERIFIED_BLOCKS)
+            .clamp(MIN_SCANNED, batch.scanned() - MIN_SCANNED);
+        info!(
+            "Blocks {}..={} take {} cycles, over the budget of {}; splitting after block {}",
+            batch.proven_block + 1,
+            batch.scanned_block(),
+            count,
+            max_cycles,
+            batch.proven_block + at as u32
+        );
+        let (first, second) = batch.split(at)?;
+        remaining.push(second);
+        remaining.push(first);
+    }
+    Ok(planned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use untron_program::testing::*;
+    use untron_program::{State, BLOCK_TIME};
+
+    // timestamp of the n-th block of a chain started with TestChain::new
+    fn block_timestamp(n: u64) -> u64 {
+        TEST_START_TIMESTAMP + n * BLOCK_TIME
+    }
+
+    // batch proves 500 blocks from the genesis state, filling an order at block 20 and another one at block 300
+    fn batch() -> (Batch, State) {
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        let genesis = chain.state();
+        let mut blocks = chain.blocks(19);
+        blocks.push(chain.block(vec![usdt_transfer([0x22; 20], [0x11; 20], 100)]));
+        blocks.extend(chain.blocks(279));
+        blocks.push(chain.block(vec![usdt_transfer([0x22; 20], [0x33; 20], 100)]));
+        blocks.extend(chain.blocks(200));
+
+        let mut actions = ActionChain::new([0; 32]);
+        let actions = vec![
+            actions.action(block_timestamp(10), [0x11; 20], 0, 100).0,
+            actions.action(block_timestamp(250), [0x33; 20], 0, 100).0,
+            actions.action(block_timestamp(1000), [0x44; 20], 0, 0).0,
+        ];
+
+        let batch = Batch {
+            state: encode_state(&genesis),
+            proven_block: TEST_START_BLOCK as u32,
+            actions,
+            blocks,
+        };
+        let mut state = genesis;
+        stf(
+            &mut state,
+            Execution {
+                actions: batch.actions.clone(),
+                blocks: batch.blocks.clone(),
+            },
+        );
+        (batch, state)
+    }
+
+    // prove executes the batches one after another and returns the final state
+    fn prove(batches: &[Batch]) -> State {
+        let mut state = decode_state(&batches[0].state).unwrap();
+        for (i, batch) in batches.iter().enumerate() {
+            assert_eq!(
+                encode_state(&state),
+                batch.state,
+                "batch {} doesn't chain",
+                i
+            );
+            assert!(batch.blocks.len() >= MIN_BLOCKS);
+            stf(
+                &mut state,
+                Execution {
+                    actions: batch.actions.clone(),
+                    blocks: batch.blocks.clone(),
+                },
+            );
+            assert_eq!(
+                untron_program::block_id_to_number(state.latest_block_id),
+                batch.scanned_block()
+            );
+        }
+        state
+    }
+
+    #[test]
+    fn split_batches_end_at_the_same_state() {
+        let (batch, state) = batch();
+
+        for at in [MIN_SCANNED, 250, 280, batch.scanned() - MIN_SCANNED] {
+            let (first, second) = batch.split(at).unwrap();
+            assert_eq!(first.scanned(), at);
+            assert_eq!(first.actions.len() + second.actions.len(), 3);
+            assert_eq!(encode_state(&prove(&[first, second])), encode_state(&state));
+        }
+        assert!(batch.split(MIN_SCANNED - 1).is_err());
+        assert!(batch.split(batch.scanned() - MIN_SCANNED + 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn plans_batches_within_the_cycle_budget() {
+        let (batch, state) = batch();
+        let cycles = |batch: &Batch| {
+            let count = batch.blocks.len() as u64 * 1000;
+            async move { Ok(count) }
+        };
+
+        // the batch fits
+        let planned = plan(batch.clone(), 500_000, cycles).await.unwrap();
+        assert_eq!(planned.len(), 1);
+
+        let planned = plan(batch.clone(), 150_000, cycles).await.unwrap();
+        assert!(planned.len() > 1);
+        for batch in &planned {
+            assert!(cycles(batch).await.unwrap() <= 150_000);
+        }
+        assert_eq!(planned[0].state, batch.state);
+        assert_eq!(encode_state(&prove(&planned)), encode_state(&state));
+
+        // parts that can't be split are over the budget
+        let planned = plan(batch.clone(), 1000, cycles).await.unwrap();
+        assert!(planned
+            .iter()
+            .all(|batch| batch.scanned() < 2 * MIN_SCANNED));
+        assert_eq!(encode_state(&prove(&planned)), encode_state(&state));
+    }
+}
@@ -1,4 +1,5 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
@@ -6,6 +7,10 @@ pub struct Config {
     pub tron: TronConfig,
     pub relay: RelayConfig,
     pub telegram: TelegramConfig,
+    #[serde(default)]
+    pub store: StoreConfig,
+    #[serde(default)]
+    pub prover: ProverConfig,
 }
 
 #[derive(Deserialize, Debug)]
@@ -15,17 +20,113 @@ pub struct ZkSyncConfig {
     pub core_address: String,
     pub usdt_address: String,
     pub fulfill: bool,
+    // most blocks per eth_getLogs request when backfilling actions and relays
+    #[serde(default = "default_log_range")]
+    pub log_range: u64,
+}
+
+fn default_log_range() -> u64 {
+    10_000
 }
 
 #[derive(Deserialize, Debug)]
 pub struct TronConfig {
+    // nodes to read Tron from, in order of preference (see tron/multi.rs)
+    #[serde(default)]
+    pub endpoints: Vec<TronEndpointConfig>,
+    // deprecated: a single gRPC endpoint, from before `endpoints`.
+    // still accepted (with a warning) so that old configs keep working
+    pub rpc: Option<String>,
+    // how many endpoints must return the same block before it's accepted
+    #[serde(default = "default_quorum")]
+    pub quorum: usize,
+    // how many more times to go through the endpoints when all of them failed
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    // delay before the first retry, doubled on every next one.
+    // endpoints that fail are also put aside for this long (doubled on every consecutive failure)
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    // blocks fetched per request when catching up (at most 100)
+    #[serde(default = "default_batch_size")]
+    pub batch_size: u32,
+    // how many batches can be fetched ahead of the processed blocks
+    #[serde(default = "default_prefetch")]
+    pub prefetch: usize,
+    // which blocks the relayer follows
+    #[serde(default)]
+    pub follow: Follow,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Follow {
+    // the latest block. the program doesn't check the contents of the last 19 blocks of an execution,
+    // but if a block the relayer has already processed gets reorged out, it has to rewind
+    #[default]
+    Head,
+    // the latest solidified block (WalletSolidity), which can't be reorged out
+    Solidified,
+}
+
+fn default_quorum() -> usize {
+    1
+}
+
+fn default_retries() -> u32 {
+    5
+}
+
+fn default_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_batch_size() -> u32 {
+    100
+}
+
+fn default_prefetch() -> usize {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TronEndpointConfig {
     pub rpc: String,
+    // the API served at `rpc`
+    #[serde(default)]
+    pub api: TronApi,
+    // for gRPC, the node's WalletSolidity endpoint (usually on port 50061), needed to follow solidified blocks.
+    // the HTTP API serves it at `rpc` (/walletsolidity/*)
+    pub solidity_rpc: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TronApi {
+    // a full node's gRPC API, e.g. http://127.0.0.1:50051
+    #[default]
+    Grpc,
+    // the HTTP API (/wallet/*) of a full node or TronGrid, e.g. https://api.trongrid.io
+    Http,
+}
+
+// when to prove (see policy.rs)
+#[derive(Deserialize, Debug, Clone)]
 pub struct RelayConfig {
+    // prove once this many seconds have passed since the latest proof...
     pub proof_interval: u64,
+    // ...and at least this many orders were closed since then
     pub min_orders_to_relay: usize,
+    // prove right away once this many orders were closed
+    pub max_closed_orders: Option<u64>,
+    // prove right away once the closed orders received this much USDT (with 6 decimals)
+    pub max_closed_value: Option<u64>,
+    // prove right away once the state is this big, in bytes
+    pub max_state_size: Option<u64>,
+    // prove right away once the proof would have this many blocks
+    pub max_pending_blocks: Option<u64>,
+    // don't prove while the zkSync gas price is over this, in wei
+    pub max_gas_price: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -35,3 +136,107 @@ pub struct TelegramConfig {
     pub topic_id: String,
     pub critical_prefix: String,
 }
+
+#[derive(Deserialize, Debug)]
+pub struct StoreConfig {
+    // SQLite database with everything the relayer processed since the last proof (see store.rs)
+    pub path: String,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            path: "relayer.db".to_string(),
+        }
+    }
+}
+
+// how proofs are generated (see prover.rs)
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProverConfig {
+    #[serde(default)]
+    pub backend: ProverBackendKind,
+    #[serde(default)]
+    pub system: ProofSystem,
+    // a directory of program ELFs to prove besides the bundled one,
+    // for when the Core's vkey is changed to one of them (see prover/program.rs)
+    pub programs_dir: Option<String>,
+    // where proofs and their metadata are exported
+    #[serde(default = "default_proofs_dir")]
+    pub proofs_dir: String,
+    // the most zkVM cycles a proof can take. bigger proofs are split into several (see batch.rs)
+    pub max_cycles: Option<u64>,
+    // how long a proof can take before it's cancelled, in seconds
+    #[serde(default = "default_proof_timeout")]
+    pub timeout: u64,
+    // for the network backend, the key of the account that pays for proofs.
+    // SP1_PRIVATE_KEY is used if it's not set
+    pub network_private_key: Option<String>,
+    // for the remote backend, the URL of the prover worker
+    pub remote_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProverBackendKind {
+    // only executes the program and returns its public values, with an empty proof.
+    // for contracts with a zero vkey, which don't verify proofs
+    Mock,
+    // proves on this machine's CPU
+    #[default]
+    Local,
+    // the SP1 prover network
+    Network,
+    // a self-hosted prover worker (see prover/remote.rs)
+    Remote,
+}
+
+// ProofSystem is the kind of proof sent to the Core, which must verify that kind.
+// core and compressed proofs aren't verifiable on-chain by the SP1 verifiers,
+// they're sent and exported as the bincode-encoded SP1ProofWithPublicValues
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProofSystem {
+    #[default]
+    Groth16,
+    Plonk,
+    // a STARK recursively compressed to a constant size, which can be aggregated later
+    Compressed,
+    // the STARK of every shard, the cheapest to generate
+    Core,
+}
+
+impl fmt::Display for ProofSystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ProofSystem::Groth16 => "groth16",
+            ProofSystem::Plonk => "plonk",
+            ProofSystem::Compressed => "compressed",
+            ProofSystem::Core => "core",
+        };
+        f.write_str(name)
+    }
+}
+
+fn default_proofs_dir() -> String {
+    "proofs".to_string()
+}
+
+fn default_proof_timeout() -> u64 {
+    4 * 3600
+}
+
+impl Default for ProverConfig {
+    fn default() -> Self {
+        Self {
+            backend: ProverBackendKind::default(),
+            system: ProofSystem::default(),
+            programs_dir: None,
+            proofs_dir: default_proofs_dir(),
+            max_cycles: None,
+            timeout: default_proof_timeout(),
+            network_private_key: None,
+            remote_url: None,
+        }
+    }
+}
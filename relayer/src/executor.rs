@@ -0,0 +1,442 @@
+use crate::actions::ActionBuffer;
+use crate::store::{Saved, Store};
+use crate::tron::block_timestamp;
+use crate::tron::stream::{BlockStream, Next};
+use std::error::Error;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use untron_program::{
+    block_id_to_number, stf, Action, Execution, OrderState, RawBlock, State, MAX_PENDING_ACTIONS,
+    ORDER_TTL,
+};
+
+// RATIONALE:
+// stf applies every action at the first scanned block at or after the action's timestamp,
+// and panics if there's no action after the latest scanned block, since then it can't know that
+// no more actions come before it. it also needs more than ORDER_TTL + 19 blocks, and only scans
+// the ones with 19 blocks on top of them. so it can't run block by block, and the relayer can't
+// wait for actions before every block either: they only come when users create orders.
+//
+// so the executor never waits for actions. on every new block, it drains whatever the listener
+// has sent into a buffer and executes the blocks it can: the ones before the latest known action,
+// with the actions up to them. actions are chained in timestamp order, so once an action is known,
+// all actions before its timestamp are known too.
+//
+// executing in parts gives the same state as executing everything at once, as long as every part
+// has all actions up to the blocks it scans (later ones just stay pending in the state).
+// so the next proof is all actions executed since the latest proof, with the blocks from the
+// latest proven one to the 19 verified on top of the latest scanned one, and it ends at the native state.
+// only blocks with 19 blocks on top are scanned, so Tron reorgs only replace blocks that aren't.
+// stf doesn't take actions over MAX_PENDING_ACTIONS, so new ones are held back while the actions
+// after the scanned blocks fill that up, and taken by a later execution.
+
+// the fewest blocks stf executes
+pub const MIN_BLOCKS: usize = ORDER_TTL as usize + 20;
+
+// how many blocks on top of the latest scanned one an execution verifies
+pub const VERIFIED_BLOCKS: usize = 19;
+
+pub type ClosedOrders = Vec<([u8; 32], OrderState)>;
+
+pub struct Executor {
+    blocks: BlockStream,
+    actions: mpsc::Receiver<Action>,
+    buffer: ActionBuffer,
+    // the native state after the latest execution
+    state: State,
+    // number of the latest block of the proven state
+    proven_block: u32,
+    // the blocks after `proven_block`, executed or not
+    pending_blocks: Vec<RawBlock>,
+    // the actions executed since the latest proof
+    pending_actions: Vec<Action>,
+}
+
+impl Executor {
+    // new continues from what was saved, with the blocks after the pending ones from `blocks`
+    // and the actions after the buffered ones from `actions`
+    pub fn new(
+        blocks: BlockStream,
+        actions: mpsc::Receiver<Action>,
+        proven_block: u32,
+        saved: Saved,
+    ) -> Self {
+        Self {
+            blocks,
+            actions,
+            buffer: ActionBuffer::new(saved.buffered_actions),
+            state: saved.state,
+            proven_block,
+            pending_blocks: saved.pending_blocks,
+            pending_actions: saved.pending_actions,
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    // proven_block is the number of the latest block of the proven state
+    pub fn proven_block(&self) -> u32 {
+        self.proven_block
+    }
+
+    // latest_block is the number of the latest received block
+    pub fn latest_block(&self) -> u32 {
+        self.proven_block + self.pending_blocks.len() as u32
+    }
+
+    // scanned_block is the number of the latest scanned block
+    fn scanned_block(&self) -> u32 {
+        block_id_to_number(self.state.latest_block_id)
+    }
+
+    // step waits for the next Tron block, then executes all blocks it can
+    // and returns the orders closed by them
+    pub async fn step(&mut self, store: &mut Store) -> Result<ClosedOrders, Box<dyn Error>> {
+        match self.blocks.next().await? {
+            Next::Block(block) => {
+                let number = self.latest_block() + 1;
+                store.add_block(number, &block)?;
+                self.pending_blocks.push(block);
+            }
+            Next::Reorg { fork } => {
+                if fork < self.scanned_block() {
+                    return Err(format!(
+                        "Tron reorg at block {} reverts blocks that are already executed",
+                        fork + 1
+                    )
+                    .into());
+                }
+                warn!(
+                    "Dropping Tron blocks {}..={}",
+                    fork + 1,
+                    self.latest_block()
+                );
+                self.pending_blocks
+                    .truncate((fork - self.proven_block) as usize);
+                store.rewind(fork)?;
+                return Ok(vec![]);
+            }
+        }
+
+        let received = self.buffer.drain(&mut self.actions)?;
+        store.add_actions(&received)?;
+
+        self.execute(store)
+    }
+
+    // execute runs stf over the blocks after the latest scanned one,
+    // scanning the ones before the latest known action
+    fn execute(&mut self, store: &mut Store) -> Result<ClosedOrders, Box<dyn Error>> {
+        let latest_action =
+            self.buffer
+                .latest()
+                .or(self.state.pending_actions.last().map(|(action, _)| action));
+        let Some(latest_timestamp) = latest_action.map(|action| action.timestamp) else {
+            return Ok(vec![]);
+        };
+
+        let first = (self.scanned_block() - self.proven_block) as usize;
+        let blocks = &self.pending_blocks[first..];
+        let mut count = blocks.len();
+        while count >= MIN_BLOCKS
+            && block_timestamp(&blocks[count - VERIFIED_BLOCKS - 1])? >= latest_timestamp
+        {
+            count -= 1;
+        }
+        if count < MIN_BLOCKS {
+            return Ok(vec![]);
+        }
+
+        let scanned_timestamp = block_timestamp(&blocks[count - VERIFIED_BLOCKS - 1])?;
+        // the pending actions up to the scanned blocks are applied, and take adds at most one after them
+        let waiting = self
+            .state
+            .pending_actions
+            .iter()
+            .filter(|(action, _)| action.timestamp > scanned_timestamp)
+            .count();
+        let actions = if waiting < MAX_PENDING_ACTIONS {
+            self.buffer.take(scanned_timestamp)
+        } else {
+            warn!(
+                "{} actions are already pending, holding back {} new ones",
+                waiting,
+                self.buffer.len()
+            );
+            vec![]
+        };
+        let execution = Execution {
+            actions: actions.clone(),
+            blocks: blocks[..count].to_vec(),
+        };
+        let first_block = self.scanned_block() + 1;
+        let closed_orders = stf(&mut self.state, execution);
+        store.executed(actions.len(), &self.state)?;
+
+        info!(
+            "Executed Tron blocks {}..={} with {} actions; {} closed orders found",
+            first_block,
+            self.scanned_block(),
+            actions.len(),
+            closed_orders.len()
+        );
+        // the whole state goes into every proof, so keep an eye on its size
+        let size = self.state.parts_size();
+        info!(
+            "State size: {} bytes ({} orders in {} bytes, {} votes in {} bytes, {}/{} pending actions in {} bytes)",
+            self.state.serialized_size(),
+            self.state.orders.len(),
+            size.orders,
+            self.state.votes.len(),
+            size.votes,
+            self.state.pending_actions.len(),
+            MAX_PENDING_ACTIONS,
+            size.pending_actions
+        );
+        self.pending_actions.extend(actions);
+
+        Ok(closed_orders)
+    }
+
+    // proof returns the actions and blocks that prove the native state on top of the proven one,
+    // or None if nothing was executed since the latest proof
+    pub fn proof(&self) -> Option<(&[Action], &[RawBlock])> {
+        let end = (self.scanned_block() - self.proven_block) as usize + VERIFIED_BLOCKS;
+        // the verified blocks can be missing after a reorg, until they're received again
+        if self.scanned_block() == self.proven_block || self.pending_blocks.len() < end {
+            return None;
+        }
+        Some((&self.pending_actions, &self.pending_blocks[..end]))
+    }
+
+    // proven drops the blocks and actions up to a proven state, whose latest block is `scanned`
+    // and which includes the first `actions` pending actions.
+    // that's the native state once the whole proof of `proof()` is accepted,
+    // or a state before it when the proof is split (see batch.rs)
+    pub fn proven(
+        &mut self,
+        store: &mut Store,
+        proven_state: &[u8],
+        scanned: u32,
+        actions: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        store.proven(proven_state, scanned, actions)?;
+        self.pending_blocks
+            .drain(..(scanned - self.proven_block) as usize);
+        self.pending_actions.drain(..actions);
+        self.proven_block = scanned;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::mock::MockActions;
+    use crate::actions::{spawn_listener, ListenerHealth};
+    use crate::config::Follow;
+    use crate::tron::mock::MockSource;
+    use crate::tron::stream::StreamConfig;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::timeout;
+    use untron_program::migration::encode_state;
+    use untron_program::testing::*;
+    use untron_program::{crypto, BLOCK_TIME};
+
+    const RECEIVER: [u8; 20] = [0x11; 20];
+
+    // timestamp of the n-th block of a chain started with TestChain::new
+    fn block_timestamp(n: u64) -> u64 {
+        TEST_START_TIMESTAMP + n * BLOCK_TIME
+    }
+
+    type Subscription = mpsc::UnboundedSender<Result<Action, Box<dyn Error + Send + Sync>>>;
+
+    struct Setup {
+        executor: Executor,
+        store: Store,
+        // the Core emits new actions here
+        subscription: Subscription,
+        genesis: State,
+        blocks: Vec<RawBlock>,
+    }
+
+    // setup runs an executor over `n` blocks from the genesis state with `pending` actions,
+    // and the Core's `actions` after them. block 20 fills an order of RECEIVER
+    async fn setup(n: usize, pending: Vec<(Action, [u8; 32])>, actions: Vec<Action>) -> Setup {
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        let mut genesis = chain.state();
+        if let Some((_, tip)) = pending.last() {
+            genesis.action_chain = *tip;
+        }
+        genesis.pending_actions = pending;
+        let mut blocks = chain.blocks(19);
+        blocks.push(chain.block(vec![usdt_transfer([0x22; 20], RECEIVER, 100)]));
+        blocks.extend(chain.blocks(n - 20));
+
+        let source = Arc::new(MockActions::default());
+        *source.chain.lock().unwrap() = actions;
+        let subscription = source.subscription();
+        let (tx, rx) = mpsc::channel(10);
+        let (_listener, mut health) =
+            spawn_listener(source, genesis.action_chain, tx, Duration::from_millis(10));
+        health
+            .wait_for(|health| *health == ListenerHealth::Live)
+            .await
+            .unwrap();
+
+        let stream = BlockStream::spawn(
+            Box::new(MockSource::new(blocks.clone()).unwrap()),
+            genesis.latest_block_id,
+            StreamConfig {
+                batch_size: 100,
+                prefetch: 2,
+                poll_interval: Duration::from_millis(10),
+                follow: Follow::Head,
+            },
+        );
+        let mut store = Store::open(":memory:").unwrap();
+        store.reset(&encode_state(&genesis)).unwrap();
+        let saved = store.load().unwrap().unwrap();
+
+        Setup {
+            executor: Executor::new(stream, rx, TEST_START_BLOCK as u32, saved),
+            store,
+            subscription,
+            genesis,
+            blocks,
+        }
+    }
+
+    // steps runs the executor over n blocks and returns the orders they closed
+    async fn steps(setup: &mut Setup, n: usize) -> ClosedOrders {
+        let mut closed_orders = vec![];
+        for _ in 0..n {
+            let step = timeout(
+                Duration::from_secs(5),
+                setup.executor.step(&mut setup.store),
+            );
+            closed_orders.extend(step.await.expect("the executor is stuck").unwrap());
+        }
+        closed_orders
+    }
+
+    // proven_state executes the proof of the executor in one go
+    fn proven_state(setup: &Setup) -> State {
+        let (actions, blocks) = setup.executor.proof().unwrap();
+        let mut state = setup.genesis.clone();
+        stf(
+            &mut state,
+            Execution {
+                actions: actions.to_vec(),
+                blocks: blocks.to_vec(),
+            },
+        );
+        state
+    }
+
+    #[tokio::test]
+    async fn doesnt_wait_for_actions() {
+        let mut setup = setup(150, vec![], vec![]).await;
+
+        assert!(steps(&mut setup, 150).await.is_empty());
+        assert_eq!(setup.executor.latest_block(), TEST_START_BLOCK as u32 + 150);
+        assert_eq!(
+            encode_state(setup.executor.state()),
+            encode_state(&setup.genesis)
+        );
+        assert!(setup.executor.proof().is_none());
+    }
+
+    #[tokio::test]
+    async fn executes_blocks_up_to_the_latest_action() {
+        let mut actions = ActionChain::new([0; 32]);
+        let (open, order_id) = actions.action(block_timestamp(10), RECEIVER, 0, 100);
+        let (later, _) = actions.action(block_timestamp(150), [0x33; 20], 0, 0);
+        let mut setup = setup(400, vec![], vec![open, later]).await;
+
+        // the first execution scans blocks 1..=101 as soon as there are enough of them
+        assert!(steps(&mut setup, MIN_BLOCKS - 1).await.is_empty());
+        let closed_orders = steps(&mut setup, 1).await;
+        assert_eq!(closed_orders.len(), 1);
+        assert_eq!(closed_orders[0].0, order_id);
+        let scanned = TEST_START_BLOCK as u32 + 101;
+        assert_eq!(
+            setup.executor.state().latest_block_id,
+            block_id(scanned as u64, crypto::hash(&setup.blocks[100].raw_data))
+        );
+
+        // blocks from 150 on wait for an action after them
+        steps(&mut setup, 150).await;
+        assert_eq!(setup.executor.scanned_block(), scanned);
+
+        // which is picked up by a later step
+        let (latest, _) = actions.action(block_timestamp(1000), [0x33; 20], 0, 0);
+        setup.subscription.send(Ok(latest)).unwrap();
+        let mut remaining = 130;
+        while setup.executor.scanned_block() == scanned {
+            assert!(remaining > 0, "the blocks weren't executed");
+            // the blocks are already fetched, so let the listener run between steps
+            tokio::task::yield_now().await;
+            steps(&mut setup, 1).await;
+            remaining -= 1;
+        }
+
+        // the executions together give the state of the proof in one go
+        assert_eq!(
+            encode_state(&proven_state(&setup)),
+            encode_state(setup.executor.state())
+        );
+
+        // and everything is saved
+        let saved = setup.store.load().unwrap().unwrap();
+        assert_eq!(
+            encode_state(&saved.state),
+            encode_state(setup.executor.state())
+        );
+        assert_eq!(saved.pending_blocks, setup.executor.pending_blocks);
+        assert_eq!(saved.pending_actions.len(), 3);
+        assert!(saved.buffered_actions.is_empty());
+
+        // after the proof, the next one starts from the native state
+        let proven = encode_state(setup.executor.state());
+        let scanned = setup.executor.scanned_block();
+        setup
+            .executor
+            .proven(&mut setup.store, &proven, scanned, 3)
+            .unwrap();
+        assert!(setup.executor.proof().is_none());
+        let saved = setup.store.load().unwrap().unwrap();
+        assert_eq!(saved.pending_blocks, setup.executor.pending_blocks);
+        assert!(saved.pending_actions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn holds_actions_back_while_the_pending_ones_are_full() {
+        let mut actions = ActionChain::new([0; 32]);
+        let pending: Vec<_> = (0..MAX_PENDING_ACTIONS)
+            .map(|_| actions.action(block_timestamp(1000), [0x33; 20], 0, 0))
+            .collect();
+        let (next, _) = actions.action(block_timestamp(1000), [0x33; 20], 0, 0);
+        let mut setup = setup(150, pending, vec![next]).await;
+
+        steps(&mut setup, 150).await;
+        let scanned = TEST_START_BLOCK as u32 + (MIN_BLOCKS - VERIFIED_BLOCKS) as u32;
+        assert_eq!(setup.executor.scanned_block(), scanned);
+        assert_eq!(
+            setup.executor.state().pending_actions.len(),
+            MAX_PENDING_ACTIONS
+        );
+        assert_eq!(setup.executor.buffer.len(), 1);
+
+        // and the proof of the blocks gives the same state
+        assert!(setup.executor.proof().unwrap().0.is_empty());
+        assert_eq!(
+            encode_state(&proven_state(&setup)),
+            encode_state(setup.executor.state())
+        );
+    }
+}
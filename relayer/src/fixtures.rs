@@ -0,0 +1,159 @@
+use crate::tron::proto::{
+    block_header, transaction::contract::ContractType, Transaction, TriggerSmartContract,
+    VoteWitnessContract,
+};
+use crate::tron::{self, TronSource};
+use prost::Message;
+use std::collections::BTreeSet;
+use std::error::Error;
+use tokio::fs;
+use tracing::info;
+use untron_program::fixtures::{
+    Fixture, FixtureBlock, FixtureTransfer, FixtureTx, FixtureVote, FixtureVoteCount,
+};
+use untron_program::{RawBlock, MAINTENANCE_PERIOD_BLOCK_OFFSET, MAINTENANCE_PERIOD_INTERVAL};
+
+// USDT smart contract TR7NHqjeKQxGTCi8q8ZY4pL8otSzgjLj6t
+const USDT_CONTRACT: [u8; 21] = [
+    0x41, 0xa6, 0x14, 0xf8, 0x03, 0xb6, 0xfd, 0x78, 0x09, 0x86, 0xa4, 0x2c, 0x78, 0xec, 0x9c, 0x7f,
+    0x77, 0xe6, 0xde, 0xd1, 0x3c,
+];
+
+// transfer(address,uint256)
+const TRANSFER_SELECTOR: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+
+// Transaction.Result.contractResult.SUCCESS
+const CONTRACT_SUCCESS: i32 = 1;
+
+// Records `count` mainnet blocks starting from `from` into a fixture file for program/tests/mainnet_fixtures.rs.
+// Blocks are recorded byte-exact, expected values are decoded with prost, independently from the program's protobuf parser.
+pub async fn record_fixtures(
+    tron: &mut dyn TronSource,
+    from: u32,
+    count: u32,
+    description: String,
+    path: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut blocks = Vec::with_capacity(count as usize);
+
+    for number in from..from + count {
+        let block = tron.block(number).await?;
+        blocks.push(fixture_block(block)?);
+        info!("Recorded Tron block {}", number);
+    }
+
+    let producers: BTreeSet<[u8; 20]> = blocks.iter().map(|block| block.witness_address).collect();
+    let replay_stf = blocks.len() as u64 > untron_program::ORDER_TTL + 19
+        && producers.len() == 27
+        && !blocks.iter().any(|block| {
+            (block.number as u32)
+                .wrapping_sub(MAINTENANCE_PERIOD_BLOCK_OFFSET)
+                .rem_euclid(MAINTENANCE_PERIOD_INTERVAL)
+                == 0
+        });
+
+    let fixture = Fixture {
+        description,
+        replay_stf,
+        blocks,
+    };
+
+    fs::write(path, serde_json::to_string_pretty(&fixture)?).await?;
+    info!(
+        "Wrote {} blocks to {} (replay_stf: {})",
+        fixture.blocks.len(),
+        path,
+        replay_stf
+    );
+
+    Ok(())
+}
+
+fn fixture_block(block: RawBlock) -> Result<FixtureBlock, Box<dyn Error>> {
+    // the block is byte-exact and its id and tx root are already checked (see tron::verify_block),
+    // prost is only used to decode the expected values
+    let raw = block_header::Raw::decode(block.raw_data.as_slice())?;
+
+    let id = tron::block_id(&block)?;
+
+    let txs = block
+        .txs
+        .into_iter()
+        .map(fixture_tx)
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+    Ok(FixtureBlock {
+        number: raw.number as u64,
+        id,
+        parent_id: raw.parent_hash.as_slice().try_into()?,
+        timestamp: raw.timestamp as u64,
+        tx_root: raw.tx_trie_root.as_slice().try_into()?,
+        witness_address: raw.witness_address[1..].try_into()?,
+        raw_data: block.raw_data,
+        witness_signature: block.signature,
+        txs,
+    })
+}
+
+fn fixture_tx(raw: Vec<u8>) -> Result<FixtureTx, Box<dyn Error>> {
+    let tx = Transaction::decode(raw.as_slice())?;
+
+    let success = tx
+        .ret
+        .first()
+        .is_some_and(|ret| ret.contract_ret == CONTRACT_SUCCESS);
+    let contract = tx
+        .raw_data
+        .and_then(|raw_data| raw_data.contract.into_iter().next());
+
+    let mut usdt_transfer = None;
+    let mut vote = None;
+
+    if let (true, Some(contract)) = (success, contract) {
+        let parameter = contract.parameter.unwrap_or_default();
+
+        if contract.r#type == ContractType::TriggerSmartContract as i32 {
+            let trigger = TriggerSmartContract::decode(parameter.value.as_slice())?;
+            // the EVM reads missing calldata as zeros
+            let mut calldata = [0u8; 68];
+            let length = trigger.data.len().min(68);
+            calldata[..length].copy_from_slice(&trigger.data[..length]);
+
+            if trigger.contract_address == USDT_CONTRACT && calldata[..4] == TRANSFER_SELECTOR {
+                // amounts that don't fit in u64 saturate, like in the program
+                let amount = &calldata[36..68];
+                let value = if amount[..24].iter().any(|byte| *byte != 0) {
+                    u64::MAX
+                } else {
+                    u64::from_be_bytes(amount[24..].try_into()?)
+                };
+
+                usdt_transfer = Some(FixtureTransfer {
+                    to: calldata[16..36].try_into()?,
+                    value,
+                });
+            }
+        } else if contract.r#type == ContractType::VoteWitnessContract as i32 {
+            let vote_witness = VoteWitnessContract::decode(parameter.value.as_slice())?;
+            vote = Some(FixtureVote {
+                voter: vote_witness.owner_address[1..].try_into()?,
+                votes: vote_witness
+                    .votes
+                    .into_iter()
+                    .map(|vote| {
+                        Ok(FixtureVoteCount {
+                            witness_address: vote.vote_address[1..].try_into()?,
+                            votes_count: vote.vote_count as u64,
+                        })
+                    })
+                    .collect::<Result<_, Box<dyn Error>>>()?,
+            });
+        }
+    }
+
+    Ok(FixtureTx {
+        raw,
+        usdt_transfer,
+        vote,
+    })
+}
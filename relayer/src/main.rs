@@ -1,12 +1,21 @@
 use crate::config::Config;
 use tokio::fs;
 
+mod actions;
+mod batch;
 mod config;
+mod executor;
+#[cfg(feature = "record-fixtures")]
+mod fixtures;
 mod fulfiller;
+mod policy;
 mod prover;
+mod reconstruct;
 mod relayer;
+mod store;
 mod telegram;
 mod tron;
+mod validator;
 mod zksync;
 use tokio::sync::mpsc;
 use tracing_subscriber::fmt::format::FmtSpan;
@@ -18,6 +27,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config_data = fs::read_to_string("config.toml").await?;
     let config: Config = toml::from_str(&config_data)?;
 
+    // untron-relayer record-fixtures <from> <count> <description> <path>
+    // records mainnet blocks for program/tests/mainnet_fixtures.rs (see program/fixtures/README.md).
+    // the fixture format is only for tests, so it's behind the record-fixtures feature
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("record-fixtures") {
+        #[cfg(feature = "record-fixtures")]
+        return record_fixtures(&config, &args).await;
+        #[cfg(not(feature = "record-fixtures"))]
+        return Err(
+            "record-fixtures needs the relayer built with --features record-fixtures".into(),
+        );
+    }
+
     let subscriber = tracing_subscriber::fmt()
         .with_span_events(FmtSpan::CLOSE)
         .with_writer(move || {
@@ -65,3 +87,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         tracing::info!("Relayer has crashed, restarting...");
     }
 }
+
+#[cfg(feature = "record-fixtures")]
+async fn record_fixtures(
+    config: &Config,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if args.len() != 6 {
+        return Err(
+            "usage: untron-relayer record-fixtures <from> <count> <description> <path>".into(),
+        );
+    }
+
+    tracing_subscriber::fmt().init();
+
+    let mut tron = tron::connect(&config.tron)?;
+    fixtures::record_fixtures(
+        tron.as_mut(),
+        args[2].parse()?,
+        args[3].parse()?,
+        args[4].clone(),
+        &args[5],
+    )
+    .await
+}
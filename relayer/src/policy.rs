@@ -0,0 +1,401 @@
+use crate::config::RelayConfig;
+use crate::executor::ClosedOrders;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+// RATIONALE:
+// every proof costs prover time and zkSync gas, and every hour without one delays the orders
+// that were closed in the meantime. which one matters more depends on the deployment, so when to
+// prove is configured in [relay] instead of being hardcoded:
+// - proof_interval and min_orders_to_relay: prove once the interval has passed since the latest
+//   proof and at least that many orders were closed;
+// - the optional max_* triggers prove right away, whatever the interval, once the closed orders,
+//   their USDT or the proof inputs (the state and the pending blocks) get that big;
+// - max_gas_price holds any proof back while zkSync is more expensive than that, or while the gas
+//   price can't be read.
+// the interval counts from the latest proof sent, which is kept in the store across restarts.
+// the policy only decides, so it can be tested with a fake clock and made-up numbers.
+
+// Clock tells the time in unix seconds
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+}
+
+// Metrics are the proof inputs the policy looks at
+#[derive(Debug, Clone, Copy)]
+pub struct Metrics {
+    // size of the serialized state in bytes
+    pub state_size: u64,
+    // blocks that would go into the proof
+    pub pending_blocks: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Interval { elapsed: u64, closed_orders: usize },
+    ClosedOrders(usize),
+    ClosedValue(u64),
+    StateSize(u64),
+    PendingBlocks(usize),
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trigger::Interval {
+                elapsed,
+                closed_orders,
+            } => write!(
+                f,
+                "{}s since the latest proof with {} closed orders",
+                elapsed, closed_orders
+            ),
+            Trigger::ClosedOrders(count) => write!(f, "{} closed orders", count),
+            Trigger::ClosedValue(value) => write!(f, "{} USDT in closed orders", value),
+            Trigger::StateSize(size) => write!(f, "state of {} bytes", size),
+            Trigger::PendingBlocks(count) => write!(f, "{} pending blocks", count),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    // no trigger fired
+    Wait,
+    Prove(Trigger),
+    // a trigger fired, but the gas price is over max_gas_price
+    Hold { trigger: Trigger, gas_price: u64 },
+}
+
+pub struct RelayPolicy {
+    config: RelayConfig,
+    clock: Box<dyn Clock>,
+    latest_proof: u64,
+    // orders closed since the latest proof, and the USDT they received
+    closed_orders: usize,
+    closed_value: u64,
+    latest_decision: Decision,
+}
+
+impl RelayPolicy {
+    // `latest_proof` is when the latest proof was sent, if there's any (see Store::latest_proof)
+    pub fn new(config: RelayConfig, clock: Box<dyn Clock>, latest_proof: Option<u64>) -> Self {
+        let latest_proof = latest_proof.unwrap_or_else(|| clock.now());
+        Self {
+            config,
+            clock,
+            latest_proof,
+            closed_orders: 0,
+            closed_value: 0,
+            latest_decision: Decision::Wait,
+        }
+    }
+
+    // closed counts orders closed since the latest proof
+    pub fn closed(&mut self, closed_orders: &ClosedOrders) {
+        self.closed_orders += closed_orders.len();
+        self.closed_value += closed_orders
+            .iter()
+            .map(|(_, order)| order.inflow)
+            .sum::<u64>();
+    }
+
+    // proven starts over after a proof
+    pub fn proven(&mut self) {
+        self.latest_proof = self.clock.now();
+        self.closed_orders = 0;
+        self.closed_value = 0;
+        self.latest_decision = Decision::Wait;
+    }
+
+    // trigger returns the first trigger that fires
+    pub fn trigger(&self, metrics: &Metrics) -> Option<Trigger> {
+        let config = &self.config;
+        let over = |limit: Option<u64>, value: u64| limit.is_some_and(|limit| value >= limit);
+
+        let elapsed = self.clock.now().saturating_sub(self.latest_proof);
+        if elapsed >= config.proof_interval && self.closed_orders >= config.min_orders_to_relay {
+            Some(Trigger::Interval {
+                elapsed,
+                closed_orders: self.closed_orders,
+            })
+        } else if over(config.max_closed_orders, self.closed_orders as u64) {
+            Some(Trigger::ClosedOrders(self.closed_orders))
+        } else if over(config.max_closed_value, self.closed_value) {
+            Some(Trigger::ClosedValue(self.closed_value))
+        } else if over(config.max_state_size, metrics.state_size) {
+            Some(Trigger::StateSize(metrics.state_size))
+        } else if over(config.max_pending_blocks, metrics.pending_blocks as u64) {
+            Some(Trigger::PendingBlocks(metrics.pending_blocks))
+        } else {
+            None
+        }
+    }
+
+    // decide tells whether to prove now. the gas price is only fetched when a trigger fires
+    // and there's a ceiling for it, and the proof waits while it can't be fetched
+    pub async fn decide<F, Fut>(&mut self, metrics: &Metrics, gas_price: F) -> Decision
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<u64, Box<dyn Error>>>,
+    {
+        let decision = match (self.trigger(metrics), self.config.max_gas_price) {
+            (None, _) => Decision::Wait,
+            (Some(trigger), None) => Decision::Prove(trigger),
+            (Some(trigger), Some(max_gas_price)) => match gas_price().await {
+                Ok(gas_price) if gas_price > max_gas_price => Decision::Hold { trigger, gas_price },
+                Ok(_) => Decision::Prove(trigger),
+                Err(e) => {
+                    warn!(
+                        "Failed to get the gas price, not proving what's triggered by {}: {}",
+                        trigger, e
+                    );
+                    Decision::Wait
+                }
+            },
+        };
+        self.log(decision, metrics);
+        decision
+    }
+
+    // log logs the decision, unless it's the same as the previous one (checked on every block)
+    fn log(&mut self, decision: Decision, metrics: &Metrics) {
+        let changed = match (self.latest_decision, decision) {
+            (Decision::Hold { trigger: a, .. }, Decision::Hold { trigger: b, .. }) => {
+                std::mem::discriminant(&a) != std::mem::discriminant(&b)
+            }
+            (a, b) => a != b || matches!(b, Decision::Prove(_)),
+        };
+        self.latest_decision = decision;
+        if !changed {
+            return;
+        }
+
+        match decision {
+            Decision::Wait => info!(
+                "Relay policy: waiting ({} closed orders with {} USDT, {} pending blocks, state of {} bytes)",
+                self.closed_orders, self.closed_value, metrics.pending_blocks, metrics.state_size
+            ),
+            Decision::Prove(trigger) => info!("Relay policy: proving, triggered by {}", trigger),
+            Decision::Hold { trigger, gas_price } => info!(
+                "Relay policy: holding the proof triggered by {}, gas price {} is over {}",
+                trigger,
+                gas_price,
+                self.config.max_gas_price.unwrap_or_default()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use untron_program::OrderState;
+
+    #[derive(Clone, Default)]
+    struct FakeClock(Arc<AtomicU64>);
+
+    impl FakeClock {
+        fn advance(&self, seconds: u64) {
+            self.0.fetch_add(seconds, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    fn config() -> RelayConfig {
+        RelayConfig {
+            proof_interval: 3600,
+            min_orders_to_relay: 2,
+            max_closed_orders: None,
+            max_closed_value: None,
+            max_state_size: None,
+            max_pending_blocks: None,
+            max_gas_price: None,
+        }
+    }
+
+    fn closed(inflows: &[u64]) -> ClosedOrders {
+        inflows
+            .iter()
+            .map(|&inflow| {
+                (
+                    [inflow as u8; 32],
+                    OrderState {
+                        address: [0; 20],
+                        timestamp: 0,
+                        inflow,
+                        min_deposit: 0,
+                        size: inflow,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    const SMALL: Metrics = Metrics {
+        state_size: 1000,
+        pending_blocks: 200,
+    };
+
+    async fn decide(policy: &mut RelayPolicy, metrics: &Metrics, gas_price: u64) -> Decision {
+        policy
+            .decide(metrics, || async move { Ok(gas_price) })
+            .await
+    }
+
+    #[tokio::test]
+    async fn proves_after_the_interval_with_enough_closed_orders() {
+        let clock = FakeClock::default();
+        let mut policy = RelayPolicy::new(config(), Box::new(clock.clone()), None);
+
+        policy.closed(&closed(&[10]));
+        assert_eq!(decide(&mut policy, &SMALL, 0).await, Decision::Wait);
+
+        // the interval has passed, but not enough orders were closed
+        clock.advance(3600);
+        assert_eq!(decide(&mut policy, &SMALL, 0).await, Decision::Wait);
+
+        policy.closed(&closed(&[20]));
+        assert_eq!(
+            decide(&mut policy, &SMALL, 0).await,
+            Decision::Prove(Trigger::Interval {
+                elapsed: 3600,
+                closed_orders: 2
+            })
+        );
+
+        // the interval starts over
+        policy.proven();
+        clock.advance(3599);
+        policy.closed(&closed(&[10, 20]));
+        assert_eq!(decide(&mut policy, &SMALL, 0).await, Decision::Wait);
+    }
+
+    #[tokio::test]
+    async fn proves_early_when_a_limit_is_reached() {
+        let clock = FakeClock::default();
+        let mut policy = RelayPolicy::new(
+            RelayConfig {
+                max_closed_orders: Some(3),
+                max_closed_value: Some(1000),
+                max_state_size: Some(10_000),
+                max_pending_blocks: Some(1000),
+                ..config()
+            },
+            Box::new(clock),
+            None,
+        );
+        assert_eq!(decide(&mut policy, &SMALL, 0).await, Decision::Wait);
+
+        let large_state = Metrics {
+            state_size: 10_000,
+            ..SMALL
+        };
+        assert_eq!(
+            decide(&mut policy, &large_state, 0).await,
+            Decision::Prove(Trigger::StateSize(10_000))
+        );
+        let many_blocks = Metrics {
+            pending_blocks: 1000,
+            ..SMALL
+        };
+        assert_eq!(
+            decide(&mut policy, &many_blocks, 0).await,
+            Decision::Prove(Trigger::PendingBlocks(1000))
+        );
+
+        policy.closed(&closed(&[600, 400]));
+        assert_eq!(
+            decide(&mut policy, &SMALL, 0).await,
+            Decision::Prove(Trigger::ClosedValue(1000))
+        );
+        policy.proven();
+        policy.closed(&closed(&[1, 2, 3]));
+        assert_eq!(
+            decide(&mut policy, &SMALL, 0).await,
+            Decision::Prove(Trigger::ClosedOrders(3))
+        );
+    }
+
+    #[tokio::test]
+    async fn holds_proofs_while_gas_is_expensive() {
+        let clock = FakeClock::default();
+        let mut policy = RelayPolicy::new(
+            RelayConfig {
+                min_orders_to_relay: 0,
+                max_gas_price: Some(100),
+                ..config()
+            },
+            Box::new(clock.clone()),
+            None,
+        );
+        let unavailable = || async { Err("no gas price".into()) };
+
+        // the gas price isn't needed while there's nothing to prove
+        assert_eq!(policy.decide(&SMALL, unavailable).await, Decision::Wait);
+
+        clock.advance(3600);
+        let trigger = Trigger::Interval {
+            elapsed: 3600,
+            closed_orders: 0,
+        };
+        assert_eq!(
+            decide(&mut policy, &SMALL, 101).await,
+            Decision::Hold {
+                trigger,
+                gas_price: 101
+            }
+        );
+        assert_eq!(
+            decide(&mut policy, &SMALL, 100).await,
+            Decision::Prove(trigger)
+        );
+        // nor while it can't be read
+        assert_eq!(policy.decide(&SMALL, unavailable).await, Decision::Wait);
+    }
+
+    #[tokio::test]
+    async fn counts_the_interval_from_the_latest_proof_sent() {
+        let clock = FakeClock::default();
+        clock.advance(10_000);
+        let config = RelayConfig {
+            min_orders_to_relay: 0,
+            ..config()
+        };
+
+        // the relayer restarted an hour after its latest proof
+        let mut policy = RelayPolicy::new(config.clone(), Box::new(clock.clone()), Some(6400));
+        assert_eq!(
+            decide(&mut policy, &SMALL, 0).await,
+            Decision::Prove(Trigger::Interval {
+                elapsed: 3600,
+                closed_orders: 0
+            })
+        );
+
+        // without a proof, the interval starts now
+        let mut policy = RelayPolicy::new(config, Box::new(clock), None);
+        assert_eq!(decide(&mut policy, &SMALL, 0).await, Decision::Wait);
+    }
+}
@@ -1,51 +1,516 @@
-use sp1_sdk::{HashableKey, ProverClient, SP1ProvingKey, SP1Stdin, SP1VerifyingKey};
+pub mod local;
+pub mod network;
+pub mod program;
+pub mod remote;
+
+use crate::config::{ProofSystem, ProverBackendKind, ProverConfig};
+use crate::store::{ProofJob, Store};
+use async_trait::async_trait;
+use program::Program;
+use serde_json::json;
+use sp1_sdk::{SP1ProofWithPublicValues, SP1Stdin};
 use std::error::Error;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::time::timeout;
+use tracing::{info, warn};
+use untron_program::crypto;
+
+// RATIONALE:
+// proving a few hours of Tron blocks takes a lot of compute, and where it runs depends on the operator:
+// this machine's CPU, the SP1 prover network, or a GPU machine of their own (see prover/remote.rs).
+// each of them is a ProverBackend selected in [prover], and the relayer only sees Prover.
+// a proof can take hours, so every job is saved in the store with the hash of its input.
+// if the relayer restarts while a job is running on a backend that outlives it (network, remote),
+// it waits for that job instead of paying for the same proof again.
+// a saved job is only dropped once it's over: proven, failed on the backend, or cancelled because it
+// didn't finish within the timeout. a saved job for another input is cancelled too.
+// the proof system is configured too (see ProofSystem), and every proof is exported to proofs_dir
+// with its metadata, so compressed proofs can be audited or aggregated later.
+// which program is proven depends on the vkey in the Core (see prover/program.rs).
+
+// Proof is the result of a job: the proof for the Core and the public values it proves
+pub struct Proof {
+    pub bytes: Vec<u8>,
+    pub public_values: Vec<u8>,
+}
+
+impl Proof {
+    // new encodes an SP1 proof for the Core: groth16 and plonk proofs as the SP1 verifiers take them,
+    // core and compressed ones as a whole
+    pub fn new(
+        proof: &SP1ProofWithPublicValues,
+        system: ProofSystem,
+    ) -> Result<Self, bincode::Error> {
+        let bytes = match system {
+            ProofSystem::Groth16 | ProofSystem::Plonk => proof.bytes(),
+            ProofSystem::Compressed | ProofSystem::Core => bincode::serialize(proof)?,
+        };
+        Ok(Self {
+            bytes,
+            public_values: proof.public_values.to_vec(),
+        })
+    }
+}
+
+#[async_trait]
+pub trait ProverBackend: Send + Sync {
+    // name identifies the backend in the store
+    fn name(&self) -> &'static str;
+
+    // resumable tells whether jobs keep running when the relayer stops
+    fn resumable(&self) -> bool {
+        false
+    }
+
+    // submit starts proving and returns the id of the job
+    async fn submit(&self, stdin: SP1Stdin, system: ProofSystem) -> Result<String, Box<dyn Error>>;
 
-use crate::zksync::ZkSyncClient;
+    // wait returns the proof once the job is done. it retries the errors that don't end the job
+    // (e.g. the backend is unreachable for a while), so an error means the job failed
+    async fn wait(&self, job: &str, system: ProofSystem) -> Result<Proof, Box<dyn Error>>;
+
+    // cancel stops the job, or drops it if it can't be stopped
+    async fn cancel(&self, job: &str) -> Result<(), Box<dyn Error>>;
+}
+
+// connect creates the backend configured in [prover] for a program
+pub fn connect(
+    config: &ProverConfig,
+    program: &Program,
+) -> Result<Box<dyn ProverBackend>, Box<dyn Error>> {
+    Ok(match config.backend {
+        ProverBackendKind::Mock => Box::new(local::LocalBackend::mock(program.elf)),
+        ProverBackendKind::Local => Box::new(local::LocalBackend::cpu(program.elf)),
+        ProverBackendKind::Network => Box::new(network::NetworkBackend::new(
+            program.elf,
+            config.network_private_key.as_deref(),
+        )?),
+        ProverBackendKind::Remote => Box::new(remote::RemoteBackend::new(
+            config
+                .remote_url
+                .as_deref()
+                .ok_or("remote_url is required for the remote prover backend")?,
+            program.vkey,
+        )),
+    })
+}
+
+// VkeySource reads the vkey the Core verifies proofs with (see zksync.rs)
+#[async_trait]
+pub trait VkeySource: Send + Sync {
+    async fn vkey(&self) -> Result<[u8; 32], Box<dyn Error>>;
+}
 
 pub struct Prover {
-    prover: ProverClient,
-    pk: SP1ProvingKey,
-    vk: SP1VerifyingKey,
-    elf: &'static [u8],
-    zksync_client: Arc<ZkSyncClient>,
+    // the bundled program first
+    programs: Vec<Program>,
+    config: ProverConfig,
+    // the vkey of the latest proof, to tell when the Core's changes
+    latest_vkey: Mutex<Option<[u8; 32]>>,
+    core: Arc<dyn VkeySource>,
 }
 
 impl Prover {
-    pub fn new(elf: &'static [u8], zksync_client: Arc<ZkSyncClient>) -> Self {
-        let prover = ProverClient::new();
-        let (pk, vk) = prover.setup(elf);
+    // new proves the `bundled` program and the ones in programs_dir
+    pub fn new(
+        bundled: &'static [u8],
+        config: &ProverConfig,
+        core: Arc<dyn VkeySource>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let programs = program::load(bundled, config.programs_dir.as_deref())?;
+        info!(
+            "Generating {} proofs on the {:?} backend",
+            config.system, config.backend
+        );
 
-        Self {
-            prover,
-            pk,
-            vk,
-            elf,
-            zksync_client,
+        Ok(Self {
+            programs,
+            config: config.clone(),
+            latest_vkey: Mutex::new(None),
+            core,
+        })
+    }
+
+    // program returns the program with the Core's vkey and that vkey.
+    // a Core with a zero vkey doesn't verify proofs, and gets the bundled program executed
+    async fn program(&self) -> Result<(&Program, [u8; 32]), Box<dyn Error>> {
+        let vkey = self.core.vkey().await?;
+        let program = if vkey == [0; 32] {
+            &self.programs[0]
+        } else {
+            match self.programs.iter().find(|program| program.vkey == vkey) {
+                Some(program) => program,
+                None => {
+                    tracing::error!(
+                        "No program has the Core's vkey 0x{}, add its ELF to programs_dir",
+                        hex::encode(vkey)
+                    );
+                    return Err("Vkey does not match".into());
+                }
+            }
+        };
+
+        let mut latest_vkey = self.latest_vkey.lock().unwrap();
+        if *latest_vkey != Some(vkey) {
+            info!(
+                "The Core's vkey is 0x{}, proving program {} (ELF sha256 0x{})",
+                hex::encode(vkey),
+                program.name,
+                hex::encode(program.checksum)
+            );
+            *latest_vkey = Some(vkey);
         }
+        Ok((program, vkey))
+    }
+
+    // cycles executes the program on `stdin` and returns how many cycles proving it takes
+    pub async fn cycles(&self, stdin: SP1Stdin) -> Result<u64, Box<dyn Error>> {
+        let (program, _) = self.program().await?;
+        program.mock.cycles(stdin).await
     }
 
     pub async fn generate_proof(
         &self,
         stdin: SP1Stdin,
+        store: &mut Store,
     ) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
-        let vkey = self.zksync_client.vkey().await;
-        if vkey == [0; 32] {
-            let (public_values, _) = self.prover.execute(self.elf, stdin.clone()).run()?;
-            return Ok((vec![], public_values.to_vec()));
+        let (program, vkey) = self.program().await?;
+        let backend: &dyn ProverBackend = if vkey == [0; 32] {
+            &program.mock
+        } else {
+            program.backend(|program| connect(&self.config, program))?
+        };
+        let system = self.config.system;
+
+        let input = input(system, vkey, &stdin)?;
+        let job = match self.saved_job(backend, input, store).await? {
+            Some(job) => {
+                info!(
+                    "Resuming proof job {} on the {} backend",
+                    job,
+                    backend.name()
+                );
+                job
+            }
+            None => {
+                let job = backend.submit(stdin, system).await?;
+                info!(
+                    "Started {} proof job {} on the {} backend",
+                    system,
+                    job,
+                    backend.name()
+                );
+                store.proof_job_started(&ProofJob {
+                    input,
+                    backend: backend.name().to_string(),
+                    job: job.clone(),
+                })?;
+                job
+            }
+        };
+
+        let limit = Duration::from_secs(self.config.timeout);
+        let proof = match timeout(limit, backend.wait(&job, system)).await {
+            Ok(result) => {
+                // wait only fails when the job did, so it's over either way
+                store.proof_job_finished(input)?;
+                result?
+            }
+            Err(_) => {
+                match backend.cancel(&job).await {
+                    Ok(()) => store.proof_job_finished(input)?,
+                    // kept, to be resumed or cancelled again by the next proof
+                    Err(e) => warn!("Failed to cancel proof job {}: {}", job, e),
+                }
+                return Err(format!("Proof job {} timed out after {:?}", job, limit).into());
+            }
+        };
+
+        if let Err(e) = self.export(program, backend, &job, input, &proof).await {
+            warn!("Failed to export proof job {}: {}", job, e);
         }
 
-        if vkey != self.vk.hash_bytes() {
-            tracing::error!(
-                "Contract's vkey does not match the one compiled into the program. Contact Untron team."
+        Ok((proof.bytes, proof.public_values))
+    }
+
+    // export writes the proof and its metadata to proofs_dir
+    async fn export(
+        &self,
+        program: &Program,
+        backend: &dyn ProverBackend,
+        job: &str,
+        input: [u8; 32],
+        proof: &Proof,
+    ) -> Result<(), Box<dyn Error>> {
+        let generated_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        let dir = &self.config.proofs_dir;
+        let name = format!("{}/proof-{}-{}", dir, generated_at, self.config.system);
+        let metadata = json!({
+            "system": self.config.system,
+            "backend": backend.name(),
+            "job": job,
+            "program": program.name,
+            "elf_sha256": format!("0x{}", hex::encode(program.checksum)),
+            "vkey": format!("0x{}", hex::encode(program.vkey)),
+            "input": format!("0x{}", hex::encode(input)),
+            "public_values": format!("0x{}", hex::encode(&proof.public_values)),
+            "proof_size": proof.bytes.len(),
+            "generated_at": generated_at,
+        });
+
+        fs::create_dir_all(dir).await?;
+        fs::write(format!("{}.bin", name), &proof.bytes).await?;
+        fs::write(
+            format!("{}.json", name),
+            serde_json::to_vec_pretty(&metadata)?,
+        )
+        .await?;
+        info!("Exported the proof to {}.bin", name);
+        Ok(())
+    }
+
+    // saved_job returns the saved job that proves `input` on `backend`, if it's still running,
+    // and cancels the saved jobs that don't
+    async fn saved_job(
+        &self,
+        backend: &dyn ProverBackend,
+        input: [u8; 32],
+        store: &mut Store,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        let mut resumed = None;
+        for saved in store.proof_jobs()? {
+            if saved.backend != backend.name() || !backend.resumable() {
+                store.proof_job_finished(saved.input)?;
+                continue;
+            }
+            if saved.input == input && resumed.is_none() {
+                resumed = Some(saved.job);
+                continue;
+            }
+
+            info!(
+                "Cancelling proof job {}, which proves another input",
+                saved.job
             );
-            return Err("Vkey does not match".into());
+            if let Err(e) = backend.cancel(&saved.job).await {
+                warn!("Failed to cancel proof job {}: {}", saved.job, e);
+            }
+            store.proof_job_finished(saved.input)?;
+        }
+        Ok(resumed)
+    }
+}
+
+// input is the hash of what a job proves.
+// a job for the same stdin in another system or program isn't the proof we need
+fn input(
+    system: ProofSystem,
+    vkey: [u8; 32],
+    stdin: &SP1Stdin,
+) -> Result<[u8; 32], bincode::Error> {
+    Ok(crypto::hash(&bincode::serialize(&(system, vkey, stdin))?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELF: &[u8] = include_bytes!("../../program/elf/riscv32im-succinct-zkvm-elf");
+    const VKEY: [u8; 32] = [7; 32];
+
+    // FakeBackend records what it's asked to do, and proves instantly unless it's stuck
+    #[derive(Clone, Default)]
+    struct FakeBackend {
+        resumable: bool,
+        stuck: bool,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FakeBackend {
+        fn called(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
         }
 
-        // Generates the ZK proof
-        let result = self.prover.prove(&self.pk, stdin).groth16().run()?;
-        self.prover.verify(&result, &self.vk)?;
-        Ok((result.bytes(), result.public_values.to_vec()))
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl ProverBackend for FakeBackend {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn resumable(&self) -> bool {
+            self.resumable
+        }
+
+        async fn submit(&self, _: SP1Stdin, _: ProofSystem) -> Result<String, Box<dyn Error>> {
+            self.called("submit".to_string());
+            Ok("new".to_string())
+        }
+
+        async fn wait(&self, job: &str, _: ProofSystem) -> Result<Proof, Box<dyn Error>> {
+            self.called(format!("wait {}", job));
+            if self.stuck {
+                std::future::pending::<()>().await;
+            }
+            Ok(Proof {
+                bytes: vec![1],
+                public_values: vec![2],
+            })
+        }
+
+        async fn cancel(&self, job: &str) -> Result<(), Box<dyn Error>> {
+            self.called(format!("cancel {}", job));
+            Ok(())
+        }
+    }
+
+    // FakeCore is a Core with a fixed vkey
+    struct FakeCore([u8; 32]);
+
+    #[async_trait]
+    impl VkeySource for FakeCore {
+        async fn vkey(&self) -> Result<[u8; 32], Box<dyn Error>> {
+            Ok(self.0)
+        }
+    }
+
+    // prover proves the program with VKEY on `backend`, for a Core with `vkey`
+    fn prover(backend: FakeBackend, vkey: [u8; 32], timeout: u64) -> Prover {
+        let mut program = Program::new("upgraded".to_string(), ELF);
+        program.vkey = VKEY;
+        Prover {
+            programs: vec![
+                Program::new("bundled".to_string(), ELF),
+                program.with_backend(Box::new(backend)),
+            ],
+            config: ProverConfig {
+                proofs_dir: std::env::temp_dir()
+                    .join(format!("untron-proofs-{}", std::process::id()))
+                    .display()
+                    .to_string(),
+                timeout,
+                ..Default::default()
+            },
+            latest_vkey: Mutex::new(None),
+            core: Arc::new(FakeCore(vkey)),
+        }
+    }
+
+    fn stdin() -> SP1Stdin {
+        let mut stdin = SP1Stdin::new();
+        stdin.write_vec(vec![1, 2, 3]);
+        stdin
+    }
+
+    fn saved(input: [u8; 32], job: &str) -> ProofJob {
+        ProofJob {
+            input,
+            backend: "fake".to_string(),
+            job: job.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn proves_the_program_with_the_core_vkey() {
+        let prover = prover(FakeBackend::default(), VKEY, 60);
+        let (program, vkey) = prover.program().await.unwrap();
+        assert_eq!(program.name, "upgraded");
+        assert_eq!(vkey, VKEY);
+    }
+
+    #[tokio::test]
+    async fn executes_the_bundled_program_for_a_zero_vkey() {
+        let prover = prover(FakeBackend::default(), [0; 32], 60);
+        let (program, vkey) = prover.program().await.unwrap();
+        assert_eq!(program.name, "bundled");
+        assert_eq!(vkey, [0; 32]);
+    }
+
+    #[tokio::test]
+    async fn fails_without_a_program_for_the_core_vkey() {
+        let prover = prover(FakeBackend::default(), [9; 32], 60);
+        assert!(prover.program().await.is_err());
+        assert_eq!(*prover.latest_vkey.lock().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn cancels_jobs_that_time_out() {
+        let backend = FakeBackend {
+            resumable: true,
+            stuck: true,
+            ..Default::default()
+        };
+        let prover = prover(backend.clone(), VKEY, 0);
+        let mut store = Store::open(":memory:").unwrap();
+
+        let e = prover
+            .generate_proof(stdin(), &mut store)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("timed out"));
+        assert_eq!(backend.calls(), ["submit", "wait new", "cancel new"]);
+        assert!(store.proof_jobs().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resumes_the_saved_job_for_the_same_input() {
+        let backend = FakeBackend {
+            resumable: true,
+            ..Default::default()
+        };
+        let prover = prover(backend.clone(), VKEY, 60);
+        let mut store = Store::open(":memory:").unwrap();
+        let input = input(ProofSystem::Groth16, VKEY, &stdin()).unwrap();
+        store.proof_job_started(&saved(input, "saved")).unwrap();
+
+        let proof = prover.generate_proof(stdin(), &mut store).await.unwrap();
+        assert_eq!(proof, (vec![1], vec![2]));
+        assert_eq!(backend.calls(), ["wait saved"]);
+        assert!(store.proof_jobs().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancels_saved_jobs_for_other_inputs() {
+        let backend = FakeBackend {
+            resumable: true,
+            ..Default::default()
+        };
+        let prover = prover(backend.clone(), VKEY, 60);
+        let mut store = Store::open(":memory:").unwrap();
+        // the same stdin proven by another program
+        let other = input(ProofSystem::Groth16, [8; 32], &stdin()).unwrap();
+        store.proof_job_started(&saved(other, "other")).unwrap();
+
+        prover.generate_proof(stdin(), &mut store).await.unwrap();
+        assert_eq!(backend.calls(), ["cancel other", "submit", "wait new"]);
+        assert!(store.proof_jobs().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_jobs_while_they_run() {
+        let backend = FakeBackend {
+            resumable: true,
+            stuck: true,
+            ..Default::default()
+        };
+        let prover = prover(backend.clone(), VKEY, 60);
+        let mut store = Store::open(":memory:").unwrap();
+
+        // the relayer stops while the job is running
+        let proof = prover.generate_proof(stdin(), &mut store);
+        assert!(tokio::time::timeout(Duration::from_millis(10), proof)
+            .await
+            .is_err());
+
+        let input = input(ProofSystem::Groth16, VKEY, &stdin()).unwrap();
+        assert_eq!(store.proof_jobs().unwrap(), [saved(input, "new")]);
     }
 }
@@ -0,0 +1,169 @@
+use super::{Proof, ProverBackend};
+use crate::config::ProofSystem;
+use async_trait::async_trait;
+use sp1_sdk::{ProverClient, SP1ProvingKey, SP1Stdin, SP1VerifyingKey};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::task::{self, JoinHandle};
+
+// RATIONALE:
+// local jobs run in this process, on a blocking thread so the relayer keeps following Tron meanwhile.
+// they die with the process, so they're never resumed after a restart.
+// a job that already started can't be interrupted: cancelling it only drops its result.
+// so that a cancelled job doesn't keep proving alongside the next ones, no local job starts
+// while another one of this process is still running (even if its relayer crashed and restarted).
+
+// the local job of this process that's still running, if any
+static RUNNING: Mutex<Option<String>> = Mutex::new(None);
+
+// Running marks a job as running until it's dropped at the end of the job
+struct Running;
+
+impl Running {
+    fn start(job: &str) -> Result<Self, String> {
+        let mut running = RUNNING.lock().unwrap();
+        if let Some(running) = running.as_ref() {
+            return Err(format!(
+                "Local proof job {} is still running, not starting another one",
+                running
+            ));
+        }
+        *running = Some(job.to_string());
+        Ok(Running)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        *RUNNING.lock().unwrap() = None;
+    }
+}
+
+// LocalBackend proves on this machine, or only executes the program when it's a mock
+pub struct LocalBackend {
+    name: &'static str,
+    client: Arc<ProverClient>,
+    pk: Arc<SP1ProvingKey>,
+    vk: Arc<SP1VerifyingKey>,
+    elf: &'static [u8],
+    next_job: AtomicU64,
+    jobs: Mutex<HashMap<String, JoinHandle<Result<Proof, String>>>>,
+}
+
+impl LocalBackend {
+    pub fn cpu(elf: &'static [u8]) -> Self {
+        Self::new("local", ProverClient::local(), elf)
+    }
+
+    // mock only executes the program and returns an empty proof
+    pub fn mock(elf: &'static [u8]) -> Self {
+        Self::new("mock", ProverClient::mock(), elf)
+    }
+
+    fn new(name: &'static str, client: ProverClient, elf: &'static [u8]) -> Self {
+        let (pk, vk) = client.setup(elf);
+        Self {
+            name,
+            client: Arc::new(client),
+            pk: Arc::new(pk),
+            vk: Arc::new(vk),
+            elf,
+            next_job: AtomicU64::new(0),
+            jobs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn vk(&self) -> &SP1VerifyingKey {
+        &self.vk
+    }
+
+    // cycles executes the program and returns how many cycles it took
+    pub async fn cycles(&self, stdin: SP1Stdin) -> Result<u64, Box<dyn Error>> {
+        let (client, elf) = (self.client.clone(), self.elf);
+        let cycles = task::spawn_blocking(move || {
+            client
+                .execute(elf, stdin)
+                .run()
+                .map(|(_, report)| report.total_instruction_count())
+                .map_err(|e| e.to_string())
+        })
+        .await??;
+        Ok(cycles)
+    }
+
+    fn is_mock(&self) -> bool {
+        self.name == "mock"
+    }
+}
+
+#[async_trait]
+impl ProverBackend for LocalBackend {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn submit(&self, stdin: SP1Stdin, system: ProofSystem) -> Result<String, Box<dyn Error>> {
+        let job = format!(
+            "{}-{}",
+            self.name,
+            self.next_job.fetch_add(1, Ordering::SeqCst)
+        );
+
+        let running = Running::start(&job)?;
+        let (client, pk, vk, elf) = (
+            self.client.clone(),
+            self.pk.clone(),
+            self.vk.clone(),
+            self.elf,
+        );
+        let handle = if self.is_mock() {
+            task::spawn_blocking(move || {
+                let _running = running;
+                let (public_values, _) = client
+                    .execute(elf, stdin)
+                    .run()
+                    .map_err(|e| e.to_string())?;
+                Ok(Proof {
+                    bytes: vec![],
+                    public_values: public_values.to_vec(),
+                })
+            })
+        } else {
+            task::spawn_blocking(move || {
+                let _running = running;
+                let prove = client.prove(&pk, stdin);
+                let prove = match system {
+                    ProofSystem::Groth16 => prove.groth16(),
+                    ProofSystem::Plonk => prove.plonk(),
+                    ProofSystem::Compressed => prove.compressed(),
+                    ProofSystem::Core => prove.core(),
+                };
+                let proof = prove.run().map_err(|e| e.to_string())?;
+                client.verify(&proof, &vk).map_err(|e| e.to_string())?;
+                Proof::new(&proof, system).map_err(|e| e.to_string())
+            })
+        };
+
+        self.jobs.lock().unwrap().insert(job.clone(), handle);
+        Ok(job)
+    }
+
+    async fn wait(&self, job: &str, _system: ProofSystem) -> Result<Proof, Box<dyn Error>> {
+        let handle = self
+            .jobs
+            .lock()
+            .unwrap()
+            .remove(job)
+            .ok_or_else(|| format!("Unknown proof job {}", job))?;
+        Ok(handle.await??)
+    }
+
+    async fn cancel(&self, job: &str) -> Result<(), Box<dyn Error>> {
+        if let Some(handle) = self.jobs.lock().unwrap().remove(job) {
+            handle.abort();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use super::{Proof, ProverBackend};
+use crate::config::ProofSystem;
+use async_trait::async_trait;
+use sp1_sdk::network::client::NetworkClient;
+use sp1_sdk::network::prover::NetworkProver;
+use sp1_sdk::proto::network::{ProofMode, ProofStatus};
+use sp1_sdk::{SP1ProofWithPublicValues, SP1Stdin};
+use std::error::Error;
+use std::time::Duration;
+use tracing::warn;
+
+// how often a request is checked, like in NetworkProver::wait_proof
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// how long to wait before checking again after failing to
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+// RATIONALE:
+// the SP1 prover network proves on someone else's GPUs and bills the account of the private key.
+// a request keeps running on the network when the relayer stops, and its id is all we need
+// to get the proof later, so network jobs are resumed after a restart.
+// the network has no way to cancel a request, so cancelling only stops waiting for it.
+// its status is polled like NetworkProver::wait_proof does, except that failing to get it
+// (the network is unreachable for a while) doesn't end the job: only the network unclaiming it does.
+
+pub struct NetworkBackend {
+    prover: NetworkProver,
+    client: NetworkClient,
+    elf: &'static [u8],
+}
+
+impl NetworkBackend {
+    // without a private key, SP1_PRIVATE_KEY is used
+    pub fn new(elf: &'static [u8], private_key: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let private_key = match private_key {
+            Some(private_key) => private_key.to_string(),
+            None => std::env::var("SP1_PRIVATE_KEY").map_err(|_| {
+                "network_private_key or SP1_PRIVATE_KEY is required for the network prover backend"
+            })?,
+        };
+        Ok(Self {
+            prover: NetworkProver::new_from_key(&private_key),
+            client: NetworkClient::new(&private_key),
+            elf,
+        })
+    }
+}
+
+#[async_trait]
+impl ProverBackend for NetworkBackend {
+    fn name(&self) -> &'static str {
+        "network"
+    }
+
+    fn resumable(&self) -> bool {
+        true
+    }
+
+    async fn submit(&self, stdin: SP1Stdin, system: ProofSystem) -> Result<String, Box<dyn Error>> {
+        let mode = match system {
+            ProofSystem::Groth16 => ProofMode::Groth16,
+            ProofSystem::Plonk => ProofMode::Plonk,
+            ProofSystem::Compressed => ProofMode::Compressed,
+            ProofSystem::Core => ProofMode::Core,
+        };
+        Ok(self.prover.request_proof(self.elf, stdin, mode).await?)
+    }
+
+    async fn wait(&self, job: &str, system: ProofSystem) -> Result<Proof, Box<dyn Error>> {
+        loop {
+            let (status, proof) = match self
+                .client
+                .get_proof_status::<SP1ProofWithPublicValues>(job)
+                .await
+            {
+                Ok(status) => status,
+                Err(e) => {
+                    warn!("Failed to check proof request {}, retrying: {}", job, e);
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+
+            match (status.status(), proof) {
+                (ProofStatus::ProofFulfilled, Some(proof)) => {
+                    return Ok(Proof::new(&proof, system)?)
+                }
+                (ProofStatus::ProofUnclaimed, _) => {
+                    return Err(format!(
+                        "Proof request {} failed: {}",
+                        job,
+                        status.unclaim_description()
+                    )
+                    .into())
+                }
+                _ => tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        }
+    }
+
+    async fn cancel(&self, _job: &str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
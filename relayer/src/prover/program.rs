@@ -0,0 +1,157 @@
+use super::local::LocalBackend;
+use super::ProverBackend;
+use sp1_sdk::HashableKey;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::info;
+use untron_program::crypto;
+
+// RATIONALE:
+// the Core verifies proofs of the program whose vkey it has, and its owner can change it with
+// setZKVariables at any time. so the relayer has the program it was built with, plus the ones in
+// programs_dir, and proves with the one whose vkey the Core has at the moment of the proof.
+// setting up a backend for a program can take minutes (the local one computes its proving key),
+// so it's only done for the programs that are actually used.
+
+// Program is an ELF of the Untron program the relayer can prove
+pub struct Program {
+    // file name of the ELF, or "bundled" for the one built into the relayer
+    pub name: String,
+    pub elf: &'static [u8],
+    // sha256 of the ELF
+    pub checksum: [u8; 32],
+    pub vkey: [u8; 32],
+    // executes the program without proving it
+    pub mock: LocalBackend,
+    backend: OnceLock<Box<dyn ProverBackend>>,
+}
+
+impl Program {
+    pub fn new(name: String, elf: &'static [u8]) -> Self {
+        let mock = LocalBackend::mock(elf);
+        Self {
+            name,
+            elf,
+            checksum: crypto::hash(elf),
+            vkey: mock.vk().hash_bytes(),
+            mock,
+            backend: OnceLock::new(),
+        }
+    }
+
+    // backend returns the backend that proves the program, set up with `connect` the first time
+    pub fn backend(
+        &self,
+        connect: impl FnOnce(&Program) -> Result<Box<dyn ProverBackend>, Box<dyn Error>>,
+    ) -> Result<&dyn ProverBackend, Box<dyn Error>> {
+        if let Some(backend) = self.backend.get() {
+            return Ok(backend.as_ref());
+        }
+        let backend = connect(self)?;
+        info!(
+            "Set up the {} backend for program {}",
+            backend.name(),
+            self.name
+        );
+        Ok(self.backend.get_or_init(|| backend).as_ref())
+    }
+
+    // with_backend sets the backend that proves the program, instead of connecting one
+    #[cfg(test)]
+    pub fn with_backend(self, backend: Box<dyn ProverBackend>) -> Self {
+        let _ = self.backend.set(backend);
+        self
+    }
+}
+
+// load returns the bundled program and the ones in `dir`, without duplicate vkeys
+pub fn load(bundled: &'static [u8], dir: Option<&str>) -> Result<Vec<Program>, Box<dyn Error>> {
+    let mut programs = vec![Program::new("bundled".to_string(), bundled)];
+
+    if let Some(dir) = dir {
+        let mut paths = fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+        paths.sort();
+        for path in paths.iter().filter(|path| path.is_file()) {
+            // the ELFs are loaded once and used until the relayer stops
+            let elf: &'static [u8] = Box::leak(fs::read(path)?.into_boxed_slice());
+            let program = Program::new(file_name(path), elf);
+            if let Some(known) = programs.iter().find(|known| known.vkey == program.vkey) {
+                info!(
+                    "Skipping {}, which has the vkey of {}",
+                    program.name, known.name
+                );
+                continue;
+            }
+            programs.push(program);
+        }
+    }
+
+    for program in &programs {
+        info!(
+            "Program {}: vkey 0x{}, ELF sha256 0x{}",
+            program.name,
+            hex::encode(program.vkey),
+            hex::encode(program.checksum)
+        );
+    }
+    Ok(programs)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELF: &[u8] = include_bytes!("../../../program/elf/riscv32im-succinct-zkvm-elf");
+
+    fn programs_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("untron-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn loads_only_the_bundled_program_without_programs_dir() {
+        let programs = load(ELF, None).unwrap();
+        assert_eq!(programs.len(), 1);
+        assert_eq!(programs[0].name, "bundled");
+        assert_eq!(programs[0].checksum, crypto::hash(ELF));
+    }
+
+    #[test]
+    fn skips_programs_with_a_known_vkey() {
+        let dir = programs_dir("dedup");
+        fs::write(dir.join("a.elf"), ELF).unwrap();
+        fs::write(dir.join("b.elf"), ELF).unwrap();
+
+        let programs = load(ELF, dir.to_str()).unwrap();
+        let names: Vec<_> = programs.iter().map(|program| &program.name).collect();
+        assert_eq!(names, ["bundled"]);
+    }
+
+    #[test]
+    fn skips_what_isnt_a_file() {
+        let dir = programs_dir("nested");
+        fs::create_dir(dir.join("nested")).unwrap();
+        fs::write(dir.join("nested").join("program.elf"), ELF).unwrap();
+
+        let programs = load(ELF, dir.to_str()).unwrap();
+        assert_eq!(programs.len(), 1);
+    }
+
+    #[test]
+    fn fails_without_the_programs_dir() {
+        let dir = programs_dir("missing").join("missing");
+        assert!(load(ELF, dir.to_str()).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use super::{Proof, ProverBackend};
+use crate::config::ProofSystem;
+use async_trait::async_trait;
+use serde::Deserialize;
+use sp1_sdk::SP1Stdin;
+use std::error::Error;
+use std::time::Duration;
+use tracing::warn;
+
+// RATIONALE:
+// operators with their own GPU machine run a prover worker there, and the relayer talks to it over HTTP:
+// - POST {url}/jobs?system=groth16&vkey=0x... with the bincode-encoded SP1Stdin starts a job
+//   and returns {"id": ...}. system is the ProofSystem in lowercase (core and compressed proofs come
+//   bincode-encoded), and vkey is the one of the program to prove;
+// - GET {url}/jobs/{id} returns {"status": "pending" | "done" | "failed"},
+//   with the hex-encoded "proof" and "public_values" when it's done, or "error" when it failed;
+// - DELETE {url}/jobs/{id} cancels the job.
+// the worker needs the ELFs of the programs it proves, like the relayer (see prover/program.rs).
+// jobs keep running on the worker when the relayer stops, so they're resumed after a restart.
+// while waiting, the worker being unreachable or failing with a 5xx doesn't end the job,
+// so the status is checked again later.
+
+// how often a pending job is checked
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct RemoteBackend {
+    client: reqwest::Client,
+    url: String,
+    vkey: [u8; 32],
+}
+
+impl RemoteBackend {
+    // `url` is the base URL of the worker, e.g. http://prover:8080
+    pub fn new(url: &str, vkey: [u8; 32]) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            vkey,
+        }
+    }
+}
+
+impl RemoteBackend {
+    async fn status(&self, job: &str) -> Result<Status, reqwest::Error> {
+        self.client
+            .get(format!("{}/jobs/{}", self.url, job))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+// transient tells whether a request can succeed if it's sent again
+fn transient(e: &reqwest::Error) -> bool {
+    e.is_connect()
+        || e.is_timeout()
+        || e.is_request()
+        || e.is_body()
+        || e.status().is_some_and(|status| status.is_server_error())
+}
+
+#[derive(Deserialize)]
+struct Submitted {
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum Status {
+    Pending,
+    Done {
+        proof: String,
+        public_values: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[async_trait]
+impl ProverBackend for RemoteBackend {
+    fn name(&self) -> &'static str {
+        "remote"
+    }
+
+    fn resumable(&self) -> bool {
+        true
+    }
+
+    async fn submit(&self, stdin: SP1Stdin, system: ProofSystem) -> Result<String, Box<dyn Error>> {
+        let submitted: Submitted = self
+            .client
+            .post(format!(
+                "{}/jobs?system={}&vkey=0x{}",
+                self.url,
+                system,
+                hex::encode(self.vkey)
+            ))
+            .body(bincode::serialize(&stdin)?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(submitted.id)
+    }
+
+    async fn wait(&self, job: &str, _system: ProofSystem) -> Result<Proof, Box<dyn Error>> {
+        loop {
+            let status = match self.status(job).await {
+                Ok(status) => status,
+                Err(e) if transient(&e) => {
+                    warn!("Failed to check proof job {}, retrying: {}", job, e);
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            match status {
+                Status::Pending => tokio::time::sleep(POLL_INTERVAL).await,
+                Status::Done {
+                    proof,
+                    public_values,
+                } => {
+                    return Ok(Proof {
+                        bytes: hex::decode(proof.trim_start_matches("0x"))?,
+                        public_values: hex::decode(public_values.trim_start_matches("0x"))?,
+                    })
+                }
+                Status::Failed { error } => {
+                    return Err(format!("Proof job {} failed: {}", job, error).into())
+                }
+            }
+        }
+    }
+
+    async fn cancel(&self, job: &str) -> Result<(), Box<dyn Error>> {
+        self.client
+            .delete(format!("{}/jobs/{}", self.url, job))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
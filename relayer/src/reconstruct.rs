@@ -0,0 +1,252 @@
+use crate::tron::TronSource;
+use alloy_sol_types::SolType;
+use std::error::Error;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use tracing::info;
+use untron_program::migration::{decode_state, encode_state};
+use untron_program::{block_id_to_number, crypto, stf, Action, Execution, UntronPublicValues};
+
+// RATIONALE:
+// the Core contract only stores the hash of the latest state, so a relayer that starts from scratch
+// has to rebuild the state itself. it doesn't need to trust anyone for that:
+// - the contract stores the genesis state, and every action it ever took is in its ActionChainUpdated events;
+// - every relay (closeOrders call) has the public values of its proof in the calldata,
+//   which tell up to which Tron block and action it went;
+// - so each relay can be executed again natively with the same blocks and actions,
+//   and its state hash compared with the one in its RelayUpdated event.
+// the public values only tell how far to go, the state comes from our own execution of stf,
+// so a wrong hint gives a wrong hash, never a wrong state.
+
+// Relay is what a closeOrders call proved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relay {
+    // the latest block that the proof scanned
+    pub latest_block_id: [u8; 32],
+    // the tip of the actions included in the proof
+    pub action_chain: [u8; 32],
+    // the state hash set by the relay (from its RelayUpdated event)
+    pub state_hash: [u8; 32],
+}
+
+impl Relay {
+    // from_public_values reads a relay from the public values of its proof
+    pub fn from_public_values(public_values: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let (_, latest_block_id, _, action_chain, _, state_hash, _) =
+            UntronPublicValues::abi_decode(public_values, true)?;
+        Ok(Self {
+            latest_block_id: latest_block_id.0,
+            action_chain: action_chain.0,
+            state_hash: state_hash.0,
+        })
+    }
+}
+
+// reconstruct executes all relays on top of the genesis state and returns the exact bytes of the
+// latest proven state. `actions` are all actions of the contract, in order, and `state_hash` is
+// the contract's current stateHash()
+pub async fn reconstruct(
+    genesis_state: Vec<u8>,
+    actions: &[Action],
+    relays: &[Relay],
+    state_hash: [u8; 32],
+    tron: &mut dyn TronSource,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut proven_state = genesis_state;
+    let mut state = decode_state(&proven_state)?;
+
+    // the actions must form a chain from the genesis state on, otherwise some of them are missing
+    let mut tip = state.action_chain;
+    for (i, action) in actions.iter().enumerate() {
+        if action.prev != tip {
+            return Err(format!("Action {} doesn't follow the previous one", i).into());
+        }
+        tip = crypto::hash(&action.abi_encode());
+    }
+
+    // index of the first action that's not in the state yet
+    let mut next_action = 0;
+
+    for (i, relay) in relays.iter().enumerate() {
+        // the included actions end right before the one that's chained to their tip
+        let included = if relay.action_chain == tip {
+            actions.len()
+        } else {
+            actions[next_action..]
+                .iter()
+                .position(|action| action.prev == relay.action_chain)
+                .map(|position| next_action + position)
+                .ok_or(format!("Relay {}: unknown action chain tip", i))?
+        };
+        let relay_actions = actions[next_action..included].to_vec();
+
+        let latest = block_id_to_number(state.latest_block_id);
+        let relay_latest = block_id_to_number(relay.latest_block_id);
+        if relay_latest < latest {
+            return Err(format!("Relay {} goes back to block {}", i, relay_latest).into());
+        }
+
+        // an execution without actions and blocks only upgrades the state version
+        if !relay_actions.is_empty() || relay_latest > latest {
+            // the proof also verified the 19 blocks after the latest scanned one
+            let blocks = tron.blocks(latest + 1..relay_latest + 20).await?;
+            info!(
+                "Replaying relay {}: Tron blocks {}..={}, {} actions",
+                i,
+                latest + 1,
+                relay_latest,
+                relay_actions.len()
+            );
+
+            let execution = Execution {
+                actions: relay_actions,
+                blocks,
+            };
+            catch_unwind(AssertUnwindSafe(|| stf(&mut state, execution)))
+                .map_err(|_| format!("Relay {}: the execution fails", i))?;
+        }
+        next_action = included;
+
+        proven_state = encode_state(&state);
+        if crypto::hash(&proven_state) != relay.state_hash {
+            return Err(format!(
+                "Relay {}: state hash {} doesn't match the relayed one {}",
+                i,
+                hex::encode(crypto::hash(&proven_state)),
+                hex::encode(relay.state_hash)
+            )
+            .into());
+        }
+    }
+
+    if crypto::hash(&proven_state) != state_hash {
+        return Err(format!(
+            "Reconstructed state hash {} doesn't match the Core's {}",
+            hex::encode(crypto::hash(&proven_state)),
+            hex::encode(state_hash)
+        )
+        .into());
+    }
+
+    Ok(proven_state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tron::mock::MockSource;
+    use untron_program::testing::*;
+    use untron_program::RawBlock;
+
+    const FUTURE: u64 = TEST_START_TIMESTAMP + 1_000_000_000;
+
+    // relay executes the relay like the relayer proves it and returns it with its inputs
+    fn relay(proven_state: &[u8], actions: &[Action], blocks: &[RawBlock]) -> (Relay, Vec<u8>) {
+        let public_values = untron_program::execute(
+            proven_state,
+            &bincode::serialize(actions).unwrap(),
+            &bincode::serialize(blocks).unwrap(),
+        );
+        let relay = Relay::from_public_values(&public_values).unwrap();
+        let mut state = decode_state(proven_state).unwrap();
+        if !actions.is_empty() || !blocks.is_empty() {
+            stf(
+                &mut state,
+                Execution {
+                    actions: actions.to_vec(),
+                    blocks: blocks.to_vec(),
+                },
+            );
+        }
+        (relay, encode_state(&state))
+    }
+
+    struct Setup {
+        genesis: Vec<u8>,
+        actions: Vec<Action>,
+        relays: Vec<Relay>,
+        state: Vec<u8>,
+        tron: MockSource,
+    }
+
+    // two relays of 130 and 150 blocks, with an action each, and an action after them
+    fn setup() -> Setup {
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        let genesis = encode_state(&chain.state());
+        let mut action_chain = ActionChain::new(chain.state().action_chain);
+        let actions: Vec<Action> = (0..3)
+            .map(|i| action_chain.action(FUTURE + i, [0xaa; 20], 0, 0).0)
+            .collect();
+        let blocks = chain.blocks(130 + 150 - 19);
+
+        let (first, state) = relay(&genesis, &actions[..1], &blocks[..130]);
+        let (second, state) = relay(&state, &actions[1..2], &blocks[130 - 19..]);
+
+        Setup {
+            genesis,
+            actions,
+            relays: vec![first, second],
+            state,
+            tron: MockSource::new(blocks).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_the_relays() {
+        let mut setup = setup();
+        let state_hash = crypto::hash(&setup.state);
+
+        let proven_state = reconstruct(
+            setup.genesis,
+            &setup.actions,
+            &setup.relays,
+            state_hash,
+            &mut setup.tron,
+        )
+        .await
+        .unwrap();
+        assert_eq!(proven_state, setup.state);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_history() {
+        let mut setup = setup();
+        let state_hash = crypto::hash(&setup.state);
+
+        // a missing action
+        let mut actions = setup.actions.clone();
+        actions.remove(1);
+        let result = reconstruct(
+            setup.genesis.clone(),
+            &actions,
+            &setup.relays,
+            state_hash,
+            &mut setup.tron,
+        )
+        .await;
+        assert!(result.is_err());
+
+        // a relay that doesn't match the Core
+        let result = reconstruct(
+            setup.genesis.clone(),
+            &setup.actions,
+            &setup.relays[..1],
+            state_hash,
+            &mut setup.tron,
+        )
+        .await;
+        assert!(result.is_err());
+
+        // a relay hint that gives another state
+        let mut relays = setup.relays.clone();
+        relays[1].latest_block_id = relays[0].latest_block_id;
+        let result = reconstruct(
+            setup.genesis,
+            &setup.actions,
+            &relays,
+            state_hash,
+            &mut setup.tron,
+        )
+        .await;
+        assert!(result.is_err());
+    }
+}
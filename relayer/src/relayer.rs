@@ -1,78 +1,140 @@
+use crate::actions::{spawn_listener, ListenerHealth, LISTENER_BACKOFF};
+use crate::batch::{plan, Batch};
 use crate::config::Config;
+use crate::executor::Executor;
+use crate::policy::{Decision, Metrics, RelayPolicy, SystemClock};
 use crate::prover::Prover;
-use crate::tron::TronClient;
+use crate::reconstruct::reconstruct;
+use crate::store::Store;
+use crate::tron::stream::{BlockStream, StreamConfig, POLL_INTERVAL};
+use crate::tron::{self, TronSource, MAX_RANGE};
 use crate::zksync::ZkSyncClient;
-use prost::Message;
 use sp1_sdk::SP1Stdin;
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::task;
-use tokio::{fs, sync::mpsc};
+use tokio::sync::{mpsc, watch};
+use tokio::{fs, task};
 use tracing::{info, warn};
-use untron_program::{block_id_to_number, Execution, RawBlock, State};
+use untron_program::migration::{decode_state, encode_state, state_version, STATE_VERSION};
+use untron_program::{block_id_to_number, crypto, Action, RawBlock};
+
+// proven states are backed up here after every proof
+const BACKUP_DIR: &str = "state";
 
 pub struct UntronRelayer {
     config: Config,
-    tron_client: TronClient,
     zksync_client: Arc<ZkSyncClient>,
     prover: Prover,
-    state: State,
+    // executes the Tron blocks and the actions as they come (see executor.rs)
+    executor: Executor,
+    // decides when to prove (see policy.rs)
+    policy: RelayPolicy,
+    listener_health: watch::Receiver<ListenerHealth>,
+    // exact bytes of the latest proven state, as hashed in the Core contract.
+    // they can be of an older state version, so we can't re-encode them from the state.
+    proven_state: Vec<u8>,
+    // everything since the latest proof is saved in the store as it's received (see store.rs)
+    store: Store,
 }
 
 impl UntronRelayer {
     pub async fn new(config: Config) -> Result<Self, Box<dyn std::error::Error>> {
-        let tron_client = TronClient::new(&config.tron.rpc).await?;
-
         let zksync_client = Arc::new(ZkSyncClient::new(&config.zksync).await?);
         let prover = Prover::new(
             include_bytes!("../../program/elf/riscv32im-succinct-zkvm-elf"),
+            &config.prover,
             zksync_client.clone(),
-        );
+        )?;
 
-        // Read the latest state from the latest backup file
-        // TODO: Replace this with a proper state reconstruction logic.
-
-        let state = match fs::read_dir("/state").await {
-            Ok(mut entries) => {
-                let mut latest_file = None;
-                let mut latest_modified = None;
-
-                while let Some(entry) = entries.next_entry().await? {
-                    if let Ok(metadata) = entry.metadata().await {
-                        if let Ok(modified) = metadata.modified() {
-                            if latest_modified.map_or(true, |t| modified > t) {
-                                latest_file = Some(entry);
-                                latest_modified = Some(modified);
-                            }
-                        }
-                    }
-                }
-
-                let latest_file = latest_file;
+        let mut tron = tron::connect(&config.tron)?;
 
-                if let Some(file) = latest_file {
-                    let contents = fs::read(file.path()).await?;
-                    info!("Loading state from backup: {:?}", file.path());
-                    bincode::deserialize(&contents).unwrap_or_else(|_| State::default())
-                } else {
-                    warn!("No state backups found. Using default state.");
-                    State::default()
-                }
+        // Resume from the store if it's on top of the state in the Core,
+        // otherwise start from the state in the Core
+        let state_hash = zksync_client.state_hash().await?;
+        let mut store = Store::open(&config.store.path)?;
+        let saved = match store.load()? {
+            Some(saved) if crypto::hash(&saved.proven_state) == state_hash => {
+                info!(
+                    "Resuming from {}: {} pending blocks, {} pending actions",
+                    config.store.path,
+                    saved.pending_blocks.len(),
+                    saved.pending_actions.len()
+                );
+                saved
             }
-            Err(_) => {
-                warn!("Failed to read state backups directory. Using default state.");
-                State::default()
+            saved => {
+                if saved.is_some() {
+                    warn!("The state in the Core has changed since the relayer stopped");
+                }
+                let proven_state =
+                    load_proven_state(&zksync_client, state_hash, tron.as_mut()).await?;
+                store.reset(&proven_state)?;
+                store.load()?.ok_or("Failed to save the proven state")?
             }
         };
 
-        info!("State loaded: {:?}", state);
+        let interrupted = store.interrupted_proofs()?;
+        if interrupted > 0 {
+            warn!(
+                "{} proof attempts were interrupted by a restart, resuming their jobs where the prover backend allows it",
+                interrupted
+            );
+            store.close_interrupted_proofs()?;
+        }
+
+        // The state is decoded by the store, migrating it to the current version if it's older
+        let version = state_version(&saved.proven_state);
+        if version != STATE_VERSION {
+            info!(
+                "Migrated state from version {} to version {}",
+                version, STATE_VERSION
+            );
+        }
+
+        info!("State loaded: {:?}", saved.state);
+
+        // Fetch the blocks after the pending ones, and the actions after the buffered ones
+        let latest_block_id = match saved.pending_blocks.last() {
+            Some(block) => tron::block_id(block)?,
+            None => saved.state.latest_block_id,
+        };
+        let blocks = BlockStream::spawn(
+            tron,
+            latest_block_id,
+            StreamConfig {
+                batch_size: config.tron.batch_size.min(MAX_RANGE),
+                prefetch: config.tron.prefetch,
+                poll_interval: POLL_INTERVAL,
+                follow: config.tron.follow,
+            },
+        );
+
+        let tip = saved
+            .buffered_actions
+            .last()
+            .map(|action| crypto::hash(&action.abi_encode()))
+            .unwrap_or(saved.state.action_chain);
+        let (actions_tx, actions_rx) = mpsc::channel(1000);
+        let (_listener, listener_health) =
+            spawn_listener(zksync_client.clone(), tip, actions_tx, LISTENER_BACKOFF);
+
+        let proven_block = block_id_to_number(decode_state(&saved.proven_state)?.latest_block_id);
+        let proven_state = saved.proven_state.clone();
+        let executor = Executor::new(blocks, actions_rx, proven_block, saved);
+        let policy = RelayPolicy::new(
+            config.relay.clone(),
+            Box::new(SystemClock),
+            store.latest_proof()?,
+        );
 
         Ok(Self {
             config,
-            tron_client,
             zksync_client,
             prover,
-            state,
+            executor,
+            policy,
+            listener_health,
+            proven_state,
+            store,
         })
     }
 
@@ -91,136 +153,239 @@ impl UntronRelayer {
             });
         }
 
-        // Spawn action listener
-        let (pending_actions_tx, mut pending_actions_rx) = mpsc::channel(1000);
-        let zksync_client_clone = self.zksync_client.clone();
-        task::spawn(async move {
-            if let Err(e) = zksync_client_clone.start_listener(pending_actions_tx).await {
-                tracing::error!("Action listener error: {}", e);
-            }
-        });
-
-        let mut latest_known_block_number = block_id_to_number(self.state.latest_block_id);
-        let mut latest_proof_timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        let mut total_closed_orders = 0;
-        let mut proven_state = self.state.clone();
-        let mut pending_actions = vec![];
-        let mut pending_blocks = vec![];
+        // The proven state is in an older format, so upgrade it on-chain before proving anything on top of it
+        if state_version(&self.proven_state) != STATE_VERSION {
+            self.upgrade_state().await?;
+        }
 
         // Main relayer loop
         loop {
-            // Fetch new actions and blocks
-            loop {
-                let block = self.tron_client.get_now_block2().await?;
-                let block_number = block.block_header.unwrap().raw_data.unwrap().number as u32;
-                info!("Got Tron block: {}", block_number);
-
-                if block_number != latest_known_block_number {
-                    break;
-                }
+            // Wait for the next Tron block and execute what we can.
+            // Never waits for actions: the ones the listener has sent are taken as they are
+            let closed_orders = self.executor.step(&mut self.store).await?;
+
+            self.policy.closed(&closed_orders);
 
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            // Send closed orders to fulfiller via channel
+
+            if !closed_orders.is_empty() {
+                tx.send(closed_orders).await?;
             }
 
-            let block = self
-                .tron_client
-                .get_block_by_number(latest_known_block_number + 1)
-                .await?;
-            let block_header = block.block_header.unwrap();
-            let txs = block
-                .transactions
-                .into_iter()
-                .map(|tx| tx.transaction.unwrap().encode_to_vec())
-                .collect();
-
-            latest_known_block_number += 1;
-
-            // Update state
-
-            let mut actions = vec![];
-            while let Some(action) = pending_actions_rx.recv().await {
-                actions.push(action.clone());
-                pending_actions.push(action);
+            // Check if it's time to generate a proof (see policy.rs)
+            let Some((pending_actions, pending_blocks)) = self.executor.proof() else {
+                continue;
+            };
+            let metrics = Metrics {
+                state_size: self.executor.state().serialized_size(),
+                pending_blocks: pending_blocks.len(),
+            };
+            let zksync_client = &self.zksync_client;
+            let decision = self
+                .policy
+                .decide(&metrics, || zksync_client.gas_price())
+                .await;
+            if !matches!(decision, Decision::Prove(_)) {
+                continue;
+            }
+
+            // while the listener is down, the Core may have actions we don't have,
+            // and a proof without them would be rejected
+            let health = *self.listener_health.borrow();
+            if health != ListenerHealth::Live {
+                warn!(
+                    "Action listener is {:?}, not proving until it's live",
+                    health
+                );
+                continue;
             }
 
-            let block = RawBlock {
-                raw_data: block_header.raw_data.unwrap().encode_to_vec(),
-                signature: block_header.witness_signature,
-                txs,
+            let batch = Batch {
+                state: self.proven_state.clone(),
+                proven_block: self.executor.proven_block(),
+                actions: pending_actions.to_vec(),
+                blocks: pending_blocks.to_vec(),
             };
-            pending_blocks.push(block.clone());
 
-            let execution = Execution {
-                actions,
-                blocks: vec![block],
+            // Execute the proof first, and split it if it takes more cycles than the prover can (see batch.rs)
+            let batches = match self.config.prover.max_cycles {
+                Some(max_cycles) => {
+                    let prover = &self.prover;
+                    plan(batch, max_cycles, |batch| prover.cycles(stdin(batch))).await?
+                }
+                None => vec![batch],
             };
+            if batches.len() > 1 {
+                info!("Splitting the proof into {} proofs", batches.len());
+            }
+
+            // every batch proves the state the next one starts from, and the last one the native state
+            for (i, batch) in batches.iter().enumerate() {
+                let proven_state = match batches.get(i + 1) {
+                    Some(next) => next.state.clone(),
+                    None => encode_state(self.executor.state()),
+                };
+                self.prove(batch, proven_state).await?;
+            }
+            self.policy.proven();
 
-            let closed_orders = untron_program::stf(&mut self.state, execution);
-            total_closed_orders += closed_orders.len();
+            // Sleep
 
-            info!(
-                "State transition executed; {} closed orders found",
-                closed_orders.len()
-            );
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+    }
 
-            // Send closed orders to fulfiller via channel
+    // Proves a batch and sends the proof to the Core, whose state is then `proven_state`
+    async fn prove(
+        &mut self,
+        batch: &Batch,
+        proven_state: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        info!(
+            "Generating a ZK proof for {} Tron blocks and {} new actions",
+            batch.blocks.len(),
+            batch.actions.len()
+        );
 
-            if !closed_orders.is_empty() {
-                tx.send(closed_orders).await?;
+        let attempt = self.store.proof_started(
+            batch.blocks.len(),
+            batch.actions.len(),
+            batch.scanned_block(),
+        )?;
+        // Send proof to the Core contract
+        let sent = match self
+            .prover
+            .generate_proof(stdin(batch), &mut self.store)
+            .await
+        {
+            Ok((proof, public_inputs)) => {
+                self.zksync_client.close_orders(proof, public_inputs).await
             }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &sent {
+            self.store.proof_finished(attempt, Some(&e.to_string()))?;
+        }
+        sent?;
 
-            // Check if it's time to generate a proof
-            let now = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            if now > latest_proof_timestamp + self.config.relay.proof_interval
-                && self.config.relay.min_orders_to_relay >= total_closed_orders
-            {
-                info!(
-                    "Requirements passed; generating a ZK proof for {} Tron blocks, {} new actions, and {} closed orders",
-                    pending_blocks.len(),
-                    pending_actions.len(),
-                    total_closed_orders
-                );
+        self.executor.proven(
+            &mut self.store,
+            &proven_state,
+            batch.scanned_block(),
+            batch.actions.len(),
+        )?;
+        self.proven_state = proven_state;
+        self.store.proof_finished(attempt, None)?;
 
-                latest_proof_timestamp = now;
-                total_closed_orders = 0;
-            } else {
-                continue;
-            }
+        info!("Successfully sent proof to the Core; state updated");
 
-            let mut stdin = SP1Stdin::new();
-            stdin.write_vec(bincode::serialize(&proven_state).unwrap());
-            stdin.write_vec(bincode::serialize(&pending_actions).unwrap());
-            stdin.write_vec(bincode::serialize(&pending_blocks).unwrap());
-            let (proof, public_inputs) = self.prover.generate_proof(stdin).await?;
+        // Backup state in "state" directory
+        let backup_name = format!("{}/state-{}.bin", BACKUP_DIR, batch.scanned_block());
+        fs::create_dir_all(BACKUP_DIR).await?;
+        fs::write(backup_name, &self.proven_state).await?;
 
-            // Send proof to the Core contract
+        Ok(())
+    }
 
-            self.zksync_client
-                .close_orders(proof, public_inputs)
-                .await?;
+    // Proves an execution without actions and blocks, which makes the program
+    // re-encode the proven state in the current version (see program/src/migration.rs)
+    async fn upgrade_state(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        info!(
+            "Proven state is version {}, upgrading it to version {}",
+            state_version(&self.proven_state),
+            STATE_VERSION
+        );
 
-            proven_state = self.state.clone();
+        let mut stdin = SP1Stdin::new();
+        stdin.write_vec(self.proven_state.clone());
+        stdin.write_vec(bincode::serialize(&Vec::<Action>::new()).unwrap());
+        stdin.write_vec(bincode::serialize(&Vec::<RawBlock>::new()).unwrap());
+        let (proof, public_inputs) = self.prover.generate_proof(stdin, &mut self.store).await?;
 
-            info!("Successfully sent proof to the Core; state updated");
+        self.zksync_client
+            .close_orders(proof, public_inputs)
+            .await?;
 
-            // Backup state in "state" directory
-            let state_backup = bincode::serialize(&proven_state).unwrap();
-            let backup_name = format!("state/state-{}.bin", latest_known_block_number);
-            fs::create_dir_all("state").await?;
-            fs::write(backup_name, state_backup).await?;
+        // the native state can be ahead of the proven one if the relayer resumed from the store
+        self.proven_state = encode_state(&decode_state(&self.proven_state)?);
+        self.store.upgraded(&self.proven_state)?;
 
-            // Sleep
+        info!("State upgrade sent to the Core");
 
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-        }
+        Ok(())
     }
 
     // Additional methods for state reconstruction and STF execution
 }
+
+// stdin is the input of the program for a batch (see program/src/main.rs)
+fn stdin(batch: &Batch) -> SP1Stdin {
+    let mut stdin = SP1Stdin::new();
+    stdin.write_vec(batch.state.clone());
+    stdin.write_vec(bincode::serialize(&batch.actions).unwrap());
+    stdin.write_vec(bincode::serialize(&batch.blocks).unwrap());
+    stdin
+}
+
+// load_proven_state returns the state in the Core: the latest backup if it's that one,
+// otherwise the state rebuilt from the Core's history (see reconstruct.rs)
+async fn load_proven_state(
+    zksync_client: &ZkSyncClient,
+    state_hash: [u8; 32],
+    tron: &mut dyn TronSource,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if let Some(backup) = read_backup().await? {
+        if crypto::hash(&backup) == state_hash {
+            return Ok(backup);
+        }
+        warn!("The latest state backup isn't the state in the Core");
+    }
+
+    info!("Reconstructing the state from the Core's history");
+    let proven_state = reconstruct(
+        zksync_client.genesis_state().await?,
+        &zksync_client.actions().await?,
+        &zksync_client.relays().await?,
+        state_hash,
+        tron,
+    )
+    .await?;
+    info!("State reconstructed");
+    Ok(proven_state)
+}
+
+// read_backup reads the latest backup of the proven state, if there's any
+async fn read_backup() -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let mut entries = match fs::read_dir(BACKUP_DIR).await {
+        Ok(entries) => entries,
+        Err(_) => {
+            warn!("Failed to read state backups directory");
+            return Ok(None);
+        }
+    };
+
+    let mut latest_file = None;
+    let mut latest_modified = None;
+
+    while let Some(entry) = entries.next_entry().await? {
+        if let Ok(metadata) = entry.metadata().await {
+            if let Ok(modified) = metadata.modified() {
+                if latest_modified < Some(modified) {
+                    latest_file = Some(entry);
+                    latest_modified = Some(modified);
+                }
+            }
+        }
+    }
+
+    match latest_file {
+        Some(file) => {
+            info!("Loading state from backup: {:?}", file.path());
+            Ok(Some(fs::read(file.path()).await?))
+        }
+        None => {
+            warn!("No state backups found");
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,449 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use std::error::Error;
+use std::path::Path;
+use std::time::SystemTime;
+use untron_program::migration::{decode_state, encode_state};
+use untron_program::{block_id_to_number, Action, RawBlock, State};
+
+// RATIONALE:
+// proving is expensive, so the relayer only proves every few hours, and everything it has
+// processed since the last proof (the blocks, the actions and the state after them) used to live
+// only in memory, so a restart had to scan hours of blocks again.
+// the store keeps all of it in SQLite, written as it's received and executed,
+// so after a crash the relayer continues from the last block it received, with the same state.
+//
+// actions are stored as soon as they're received, with a NULL block until they're executed
+// (see executor.rs), then with the latest block scanned by their execution.
+// the blocks are stored from the one after the latest proven block, including the ones
+// that aren't executed yet, so that a restart doesn't fetch them again.
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS proven_state (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    state BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS native_state (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    state BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS pending_blocks (
+    number INTEGER PRIMARY KEY,
+    block BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS pending_actions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    block INTEGER,
+    action BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS proof_attempts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    started_at INTEGER NOT NULL,
+    finished_at INTEGER,
+    blocks INTEGER NOT NULL,
+    actions INTEGER NOT NULL,
+    latest_block INTEGER NOT NULL,
+    -- NULL while running, then 'sent' or 'failed'
+    status TEXT,
+    error TEXT
+);
+CREATE TABLE IF NOT EXISTS proof_jobs (
+    -- hash of the prover's input, so that a job is only resumed for the same proof
+    input BLOB PRIMARY KEY,
+    backend TEXT NOT NULL,
+    job TEXT NOT NULL,
+    started_at INTEGER NOT NULL
+);
+";
+
+// Saved is what the relayer had when it stopped
+pub struct Saved {
+    // exact bytes of the latest proven state, as hashed in the Core contract
+    pub proven_state: Vec<u8>,
+    // the native state after the latest execution
+    pub state: State,
+    // the blocks after the latest proven block
+    pub pending_blocks: Vec<RawBlock>,
+    // the actions executed since the latest proof
+    pub pending_actions: Vec<Action>,
+    // the actions received but not executed yet
+    pub buffered_actions: Vec<Action>,
+}
+
+// ProofJob is a proof started by a prover backend (see prover.rs)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProofJob {
+    pub input: [u8; 32],
+    pub backend: String,
+    pub job: String,
+}
+
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    // load returns what was saved, or None if nothing was proven yet
+    pub fn load(&self) -> Result<Option<Saved>, Box<dyn Error>> {
+        let proven_state: Option<Vec<u8>> = self
+            .conn
+            .query_row("SELECT state FROM proven_state", [], |row| row.get(0))
+            .optional()?;
+        let Some(proven_state) = proven_state else {
+            return Ok(None);
+        };
+
+        let state: Option<Vec<u8>> = self
+            .conn
+            .query_row("SELECT state FROM native_state", [], |row| row.get(0))
+            .optional()?;
+        let state = decode_state(state.as_deref().unwrap_or(&proven_state))?;
+
+        let pending_blocks = self
+            .conn
+            .prepare("SELECT block FROM pending_blocks ORDER BY number")?
+            .query_map([], |row| row.get::<_, Vec<u8>>(0))?
+            .map(|block| Ok(bincode::deserialize(&block?)?))
+            .collect::<Result<Vec<RawBlock>, Box<dyn Error>>>()?;
+
+        let mut pending_actions = vec![];
+        let mut buffered_actions = vec![];
+        let mut statement = self
+            .conn
+            .prepare("SELECT block, action FROM pending_actions ORDER BY id")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let block: Option<u32> = row.get(0)?;
+            let action: Action = bincode::deserialize(&row.get::<_, Vec<u8>>(1)?)?;
+            match block {
+                Some(_) => pending_actions.push(action),
+                None => buffered_actions.push(action),
+            }
+        }
+
+        Ok(Some(Saved {
+            proven_state,
+            state,
+            pending_blocks,
+            pending_actions,
+            buffered_actions,
+        }))
+    }
+
+    // reset starts over from a proven state, dropping everything that was on top of the previous one
+    pub fn reset(&mut self, proven_state: &[u8]) -> Result<(), Box<dyn Error>> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO proven_state (id, state) VALUES (0, ?1)",
+            params![proven_state],
+        )?;
+        tx.execute("DELETE FROM native_state", [])?;
+        tx.execute("DELETE FROM pending_blocks", [])?;
+        tx.execute("DELETE FROM pending_actions", [])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // proven replaces the proven state with one whose latest block is `latest_block`,
+    // and drops the blocks and the first `actions` executed actions, which are now proven.
+    // the native state stays, since it can be ahead of the proven one when a proof is split
+    pub fn proven(
+        &mut self,
+        proven_state: &[u8],
+        latest_block: u32,
+        actions: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO proven_state (id, state) VALUES (0, ?1)",
+            params![proven_state],
+        )?;
+        tx.execute(
+            "DELETE FROM pending_blocks WHERE number <= ?1",
+            params![latest_block],
+        )?;
+        tx.execute(
+            "DELETE FROM pending_actions WHERE id IN
+                (SELECT id FROM pending_actions WHERE block IS NOT NULL ORDER BY id LIMIT ?1)",
+            params![actions as i64],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // upgraded replaces the proven state with the same state in a newer version,
+    // which keeps the pending blocks and actions on top of it
+    pub fn upgraded(&mut self, proven_state: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "UPDATE proven_state SET state = ?1 WHERE id = 0",
+            params![proven_state],
+        )?;
+        Ok(())
+    }
+
+    // add_block saves a block received after the pending ones
+    pub fn add_block(&mut self, number: u32, block: &RawBlock) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO pending_blocks (number, block) VALUES (?1, ?2)",
+            params![number, bincode::serialize(block)?],
+        )?;
+        Ok(())
+    }
+
+    // add_actions saves received actions, not executed yet
+    pub fn add_actions(&mut self, actions: &[Action]) -> Result<(), Box<dyn Error>> {
+        let tx = self.conn.transaction()?;
+        for action in actions {
+            tx.execute(
+                "INSERT INTO pending_actions (block, action) VALUES (NULL, ?1)",
+                params![bincode::serialize(action)?],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // executed saves the native state after an execution of the first `actions` buffered actions
+    pub fn executed(&mut self, actions: usize, state: &State) -> Result<(), Box<dyn Error>> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "UPDATE pending_actions SET block = ?1 WHERE id IN
+                (SELECT id FROM pending_actions WHERE block IS NULL ORDER BY id LIMIT ?2)",
+            params![block_id_to_number(state.latest_block_id), actions as i64],
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO native_state (id, state) VALUES (0, ?1)",
+            params![encode_state(state)],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // rewind drops the blocks after `fork`
+    pub fn rewind(&mut self, fork: u32) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "DELETE FROM pending_blocks WHERE number > ?1",
+            params![fork],
+        )?;
+        Ok(())
+    }
+
+    // proof_started records a proof attempt and returns its id
+    pub fn proof_started(
+        &mut self,
+        blocks: usize,
+        actions: usize,
+        latest_block: u32,
+    ) -> Result<i64, Box<dyn Error>> {
+        self.conn.execute(
+            "INSERT INTO proof_attempts (started_at, blocks, actions, latest_block)
+                VALUES (?1, ?2, ?3, ?4)",
+            params![now(), blocks as i64, actions as i64, latest_block],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    // proof_finished records how a proof attempt ended
+    pub fn proof_finished(&mut self, id: i64, error: Option<&str>) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "UPDATE proof_attempts SET finished_at = ?1, status = ?2, error = ?3 WHERE id = ?4",
+            params![
+                now(),
+                if error.is_some() { "failed" } else { "sent" },
+                error,
+                id
+            ],
+        )?;
+        Ok(())
+    }
+
+    // latest_proof returns when the latest proof was sent, in unix seconds
+    pub fn latest_proof(&self) -> Result<Option<u64>, Box<dyn Error>> {
+        Ok(self.conn.query_row(
+            "SELECT MAX(finished_at) FROM proof_attempts WHERE status = 'sent'",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    // interrupted_proofs counts the proof attempts that never finished (the relayer stopped during them)
+    pub fn interrupted_proofs(&self) -> Result<u32, Box<dyn Error>> {
+        Ok(self.conn.query_row(
+            "SELECT COUNT(*) FROM proof_attempts WHERE status IS NULL",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    // close_interrupted_proofs marks the proof attempts that never finished as failed
+    pub fn close_interrupted_proofs(&mut self) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "UPDATE proof_attempts SET status = 'failed', error = 'interrupted' WHERE status IS NULL",
+            [],
+        )?;
+        Ok(())
+    }
+
+    // proof_job_started records a job that's proving `input`
+    pub fn proof_job_started(&mut self, job: &ProofJob) -> Result<(), Box<dyn Error>> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO proof_jobs (input, backend, job, started_at) VALUES (?1, ?2, ?3, ?4)",
+            params![job.input, job.backend, job.job, now()],
+        )?;
+        Ok(())
+    }
+
+    // proof_job_finished drops the job that was proving `input`
+    pub fn proof_job_finished(&mut self, input: [u8; 32]) -> Result<(), Box<dyn Error>> {
+        self.conn
+            .execute("DELETE FROM proof_jobs WHERE input = ?1", params![input])?;
+        Ok(())
+    }
+
+    // proof_jobs returns the jobs that didn't finish, oldest first
+    pub fn proof_jobs(&self) -> Result<Vec<ProofJob>, Box<dyn Error>> {
+        let jobs = self
+            .conn
+            .prepare("SELECT input, backend, job FROM proof_jobs ORDER BY started_at")?
+            .query_map([], |row| {
+                Ok(ProofJob {
+                    input: row.get(0)?,
+                    backend: row.get(1)?,
+                    job: row.get(2)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(jobs)
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use untron_program::crypto;
+    use untron_program::testing::*;
+
+    fn action(chain: &mut ActionChain, size: u64) -> Action {
+        chain.action(1, [0x11; 20], 1, size).0
+    }
+
+    fn encoded(actions: &[Action]) -> Vec<u8> {
+        bincode::serialize(actions).unwrap()
+    }
+
+    #[test]
+    fn resumes_where_it_stopped() {
+        let path = std::env::temp_dir().join(format!("untron-store-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        let proven = encode_state(&chain.state());
+        let mut actions = ActionChain::new([0; 32]);
+        let (a, b, c) = (
+            action(&mut actions, 1),
+            action(&mut actions, 2),
+            action(&mut actions, 3),
+        );
+        let blocks = chain.blocks(3);
+        let state = chain.state();
+        let first = TEST_START_BLOCK as u32 + 1;
+
+        {
+            let mut store = Store::open(&path).unwrap();
+            assert!(store.load().unwrap().is_none());
+
+            store.reset(&proven).unwrap();
+            store.add_actions(&[a.clone(), b.clone()]).unwrap();
+            for (i, block) in blocks.iter().enumerate() {
+                store.add_block(first + i as u32, block).unwrap();
+            }
+            store.executed(1, &state).unwrap();
+            store.add_actions(std::slice::from_ref(&c)).unwrap();
+
+            // the last block is reorged out
+            store.rewind(first + 1).unwrap();
+            store.proof_started(2, 1, first).unwrap();
+        }
+
+        let store = Store::open(&path).unwrap();
+        let saved = store.load().unwrap().unwrap();
+        assert_eq!(saved.proven_state, proven);
+        assert_eq!(encode_state(&saved.state), encode_state(&state));
+        assert_eq!(saved.pending_blocks, blocks[..2]);
+        assert_eq!(encoded(&saved.pending_actions), encoded(&[a]));
+        assert_eq!(encoded(&saved.buffered_actions), encoded(&[b, c]));
+        assert_eq!(store.interrupted_proofs().unwrap(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn proof_drops_proven_blocks_and_actions() {
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        let mut actions = ActionChain::new([0; 32]);
+        let mut store = Store::open(":memory:").unwrap();
+        let first = TEST_START_BLOCK as u32 + 1;
+
+        store.reset(&encode_state(&chain.state())).unwrap();
+        let (a, b) = (action(&mut actions, 1), action(&mut actions, 2));
+        store.add_actions(&[a, b.clone()]).unwrap();
+        let blocks = chain.blocks(2);
+        store.add_block(first, &blocks[0]).unwrap();
+        store.add_block(first + 1, &blocks[1]).unwrap();
+
+        // the execution scanned the first block
+        let mut state = chain.state();
+        state.latest_block_id = block_id(first as u64, crypto::hash(&blocks[0].raw_data));
+        store.executed(1, &state).unwrap();
+
+        // a failed attempt doesn't count as a proof
+        let failed = store.proof_started(2, 1, first).unwrap();
+        store.proof_finished(failed, Some("timed out")).unwrap();
+        assert_eq!(store.latest_proof().unwrap(), None);
+
+        let id = store.proof_started(2, 1, first).unwrap();
+        store.proven(&encode_state(&state), first, 1).unwrap();
+        store.proof_finished(id, None).unwrap();
+        assert!(store.latest_proof().unwrap().is_some());
+
+        let saved = store.load().unwrap().unwrap();
+        assert_eq!(saved.proven_state, encode_state(&state));
+        assert_eq!(saved.pending_blocks, blocks[1..]);
+        assert!(saved.pending_actions.is_empty());
+        assert_eq!(encoded(&saved.buffered_actions), encoded(&[b]));
+        assert_eq!(store.interrupted_proofs().unwrap(), 0);
+    }
+
+    #[test]
+    fn keeps_proof_jobs_until_they_finish() {
+        let mut store = Store::open(":memory:").unwrap();
+        let job = |input: u8, job: &str| ProofJob {
+            input: [input; 32],
+            backend: "network".to_string(),
+            job: job.to_string(),
+        };
+
+        store.proof_job_started(&job(1, "a")).unwrap();
+        store.proof_job_started(&job(2, "b")).unwrap();
+        // the same input proven again replaces the job
+        store.proof_job_started(&job(2, "c")).unwrap();
+        let mut jobs = store.proof_jobs().unwrap();
+        jobs.sort_by_key(|job| job.input);
+        assert_eq!(jobs, [job(1, "a"), job(2, "c")]);
+
+        store.proof_job_finished([1; 32]).unwrap();
+        assert_eq!(store.proof_jobs().unwrap(), [job(2, "c")]);
+    }
+}
@@ -1,38 +1,331 @@
-mod proto;
+pub mod grpc;
+pub mod http;
+#[cfg(test)]
+pub mod mock;
+pub mod multi;
+pub mod proto;
+mod raw;
+pub mod stream;
 
-use proto::{wallet_client::WalletClient, BlockExtention, EmptyMessage, NumberMessage};
+use crate::config::{TronApi, TronConfig, TronEndpointConfig};
+use async_trait::async_trait;
+use prost::Message;
+use proto::block_header;
 use std::error::Error;
-use tonic::transport::Channel;
-use tonic::Request;
+use std::ops::Range;
+use std::time::Duration;
+use tracing::warn;
+use untron_program::{crypto, RawBlock};
 
-pub struct TronClient {
-    client: WalletClient<Channel>,
+// RATIONALE:
+// the relayer only needs a few things from Tron: the latest block number and blocks by number,
+// byte-exact so that the program hashes the same bytes the SRs signed.
+// these are behind TronSource, so that the relayer can work with whatever endpoint the operator has
+// (a full node's gRPC or the HTTP API, e.g. TronGrid) and tests can run with no network (see tron/mock.rs).
+// every implementation checks the blocks it returns with verify_block, so a block that
+// doesn't hash to its id or tx root never gets to the program.
+
+// most blocks a node returns for a range request (BLOCK_LIMIT_NUM in java-tron)
+pub const MAX_RANGE: u32 = 100;
+
+#[async_trait]
+pub trait TronSource: Send {
+    // the latest block the node has
+    async fn now_block(&mut self) -> Result<RawBlock, Box<dyn Error>>;
+
+    // the latest solidified block: confirmed by enough SRs that it can't be reorged out
+    async fn solid_block(&mut self) -> Result<RawBlock, Box<dyn Error>>;
+
+    async fn block(&mut self, number: u32) -> Result<RawBlock, Box<dyn Error>>;
+
+    // consecutive blocks, in order
+    async fn blocks(&mut self, numbers: Range<u32>) -> Result<Vec<RawBlock>, Box<dyn Error>> {
+        let mut blocks = Vec::with_capacity(numbers.len());
+        for number in numbers {
+            blocks.push(self.block(number).await?);
+        }
+        Ok(blocks)
+    }
+}
+
+// connect creates the TronSource for the endpoints configured in [tron]
+pub fn connect(config: &TronConfig) -> Result<Box<dyn TronSource>, Box<dyn Error>> {
+    let endpoints = endpoints(config)?
+        .iter()
+        .map(|endpoint| Ok((endpoint.rpc.clone(), connect_endpoint(endpoint)?)))
+        .collect::<Result<_, Box<dyn Error>>>()?;
+
+    Ok(Box::new(multi::MultiSource::new(
+        endpoints,
+        config.quorum,
+        config.retries,
+        Duration::from_millis(config.backoff_ms),
+    )?))
+}
+
+// endpoints returns the configured endpoints, or the deprecated `rpc` as the only (gRPC) endpoint
+fn endpoints(config: &TronConfig) -> Result<Vec<TronEndpointConfig>, Box<dyn Error>> {
+    match (&config.rpc, config.endpoints.is_empty()) {
+        (Some(_), false) => Err("set either [tron] rpc or [[tron.endpoints]], not both".into()),
+        (Some(rpc), true) => {
+            warn!(
+                "[tron] rpc is deprecated and will be removed, configure it as a [[tron.endpoints]] entry with api = \"grpc\" instead"
+            );
+            Ok(vec![TronEndpointConfig {
+                rpc: rpc.clone(),
+                api: TronApi::Grpc,
+                solidity_rpc: None,
+            }])
+        }
+        (None, true) => Err("no Tron endpoints configured, add a [[tron.endpoints]] entry".into()),
+        (None, false) => Ok(config.endpoints.clone()),
+    }
+}
+
+fn connect_endpoint(endpoint: &TronEndpointConfig) -> Result<Box<dyn TronSource>, Box<dyn Error>> {
+    Ok(match endpoint.api {
+        TronApi::Grpc => Box::new(grpc::GrpcSource::new(
+            &endpoint.rpc,
+            endpoint.solidity_rpc.as_deref(),
+        )?),
+        TronApi::Http => Box::new(http::HttpSource::new(&endpoint.rpc)),
+    })
+}
+
+// block_number returns the number of the block from its header
+pub fn block_number(block: &RawBlock) -> Result<u32, Box<dyn Error>> {
+    Ok(block_header::Raw::decode(block.raw_data.as_slice())?.number as u32)
+}
+
+// block_timestamp returns the timestamp of the block from its header, in Tron format (like actions')
+pub fn block_timestamp(block: &RawBlock) -> Result<u64, Box<dyn Error>> {
+    Ok(block_header::Raw::decode(block.raw_data.as_slice())?.timestamp as u64)
+}
+
+// parent_id returns the id of the block's parent from its header
+pub fn parent_id(block: &RawBlock) -> Result<[u8; 32], Box<dyn Error>> {
+    let raw_data = block_header::Raw::decode(block.raw_data.as_slice())?;
+    Ok(raw_data.parent_hash.as_slice().try_into()?)
+}
+
+// block_id computes the id of the block the same way the program does
+pub fn block_id(block: &RawBlock) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut id = crypto::hash(&block.raw_data);
+    id[..8].copy_from_slice(&(block_number(block)? as u64).to_be_bytes());
+    Ok(id)
+}
+
+// verify_block checks that the block id the node gave us is the hash of the block's raw_data
+// and that the tx root in it is the merkle root of the txs, the same way the program will.
+// otherwise the proof would fail on this block.
+pub fn verify_block(expected_id: &[u8], block: &RawBlock) -> Result<(), Box<dyn Error>> {
+    let raw_data = block_header::Raw::decode(block.raw_data.as_slice())?;
+
+    let id = block_id(block)?;
+    if id.as_slice() != expected_id {
+        return Err(format!(
+            "Block {}: id {} doesn't match its raw_data (computed {})",
+            raw_data.number,
+            hex::encode(expected_id),
+            hex::encode(id)
+        )
+        .into());
+    }
+
+    let tx_hashes: Vec<[u8; 32]> = block.txs.iter().map(|tx| crypto::hash(tx)).collect();
+    let tx_root = crypto::merkle_root(tx_hashes);
+    if tx_root.as_slice() != raw_data.tx_trie_root.as_slice() {
+        return Err(format!(
+            "Block {}: tx root {} doesn't match its {} transactions (computed {})",
+            raw_data.number,
+            hex::encode(&raw_data.tx_trie_root),
+            block.txs.len(),
+            hex::encode(tx_root)
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// check_range makes sure the node returned all the blocks we asked for, in order
+fn check_range(numbers: &Range<u32>, blocks: &[RawBlock]) -> Result<(), Box<dyn Error>> {
+    if blocks.len() != numbers.len() {
+        return Err(format!(
+            "Requested blocks {}..{}, got {} blocks",
+            numbers.start,
+            numbers.end,
+            blocks.len()
+        )
+        .into());
+    }
+    for (number, block) in numbers.clone().zip(blocks) {
+        check_number(number, block)?;
+    }
+    Ok(())
+}
+
+// check_number makes sure the node returned the block we asked for
+fn check_number(requested: u32, block: &RawBlock) -> Result<(), Box<dyn Error>> {
+    let number = block_number(block)?;
+    if number != requested {
+        return Err(format!("Requested block {}, got block {}", requested, number).into());
+    }
+    Ok(())
 }
 
-impl TronClient {
-    pub async fn new(rpc_url: &str) -> Result<Self, Box<dyn Error>> {
-        let client = WalletClient::connect(rpc_url.to_string()).await?;
-        Ok(Self { client })
+#[cfg(test)]
+mod tests {
+    use super::http::{HttpBlock, HttpBlockList};
+    use super::mock::MockSource;
+    use super::proto::transaction;
+    use super::raw::RawTransaction;
+    use super::{block_id, *};
+    use serde_json::json;
+    use untron_program::testing::*;
+
+    fn test_blocks() -> Vec<RawBlock> {
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        vec![
+            chain.block(vec![]),
+            chain.block(vec![usdt_transfer([0x11; 20], [0x22; 20], 1_000_000)]),
+            chain.block(vec![
+                vote_tx([0x11; 20], &[([0x33; 20], 10)]),
+                failed(usdt_transfer([0x11; 20], [0x22; 20], 1_000_000)),
+                usdt_transfer_from([0x11; 20], [0x22; 20], [0x33; 20], 5),
+            ]),
+        ]
     }
 
-    pub async fn get_block_by_number(
-        &mut self,
-        block_number: u32,
-    ) -> Result<BlockExtention, Box<dyn Error>> {
-        let request = Request::new(NumberMessage {
-            num: block_number as i64,
-        });
-        let response = self.client.get_block_by_num2(request).await?;
-        let block = response.into_inner();
-        Ok(block)
+    // http_json returns the block as the HTTP API would
+    fn http_json(block: &RawBlock) -> serde_json::Value {
+        let raw = block_header::Raw::decode(block.raw_data.as_slice()).unwrap();
+        let transactions: Vec<_> = block
+            .txs
+            .iter()
+            .map(|tx| {
+                let tx = RawTransaction::decode(tx.as_slice()).unwrap();
+                json!({
+                    "ret": tx.ret.iter().map(|ret| json!({
+                        "contractRet": transaction::result::ContractResult::try_from(ret.contract_ret)
+                            .unwrap()
+                            .as_str_name(),
+                    })).collect::<Vec<_>>(),
+                    "signature": tx.signature.iter().map(hex::encode).collect::<Vec<_>>(),
+                    "raw_data_hex": hex::encode(&tx.raw_data),
+                })
+            })
+            .collect();
+
+        json!({
+            "blockID": hex::encode(block_id(block).unwrap()),
+            "block_header": {
+                "raw_data": {
+                    "number": raw.number,
+                    "txTrieRoot": hex::encode(&raw.tx_trie_root),
+                    "witness_address": hex::encode(&raw.witness_address),
+                    "parentHash": hex::encode(&raw.parent_hash),
+                    "version": raw.version,
+                    "timestamp": raw.timestamp,
+                },
+                "witness_signature": hex::encode(&block.signature),
+            },
+            "transactions": transactions,
+        })
     }
 
-    pub async fn get_now_block2(&mut self) -> Result<BlockExtention, Box<dyn Error>> {
-        let request = Request::new(EmptyMessage {});
-        let response = self.client.get_now_block2(request).await?;
-        let block = response.into_inner();
-        Ok(block)
+    #[tokio::test]
+    async fn mock_serves_blocks_up_to_head() {
+        let blocks = test_blocks();
+        let first = TEST_START_BLOCK as u32 + 1;
+        let mut source = MockSource::new(blocks.clone()).unwrap();
+
+        assert_eq!(source.now_block().await.unwrap(), blocks[2]);
+        assert_eq!(source.block(first + 1).await.unwrap(), blocks[1]);
+        assert_eq!(source.blocks(first..first + 3).await.unwrap(), blocks);
+
+        source.head = first;
+        assert_eq!(source.now_block().await.unwrap(), blocks[0]);
+        assert!(source.block(first + 1).await.is_err());
+        assert!(source.blocks(first..first + 2).await.is_err());
     }
 
-    // Additional methods for processing blocks and transactions
+    #[tokio::test]
+    async fn mock_rejects_tampered_blocks() {
+        let mut blocks = test_blocks();
+        blocks[1].txs[0].push(0);
+        let mut source = MockSource::new(blocks).unwrap();
+
+        let error = source.block(TEST_START_BLOCK as u32 + 2).await.unwrap_err();
+        assert!(error.to_string().contains("tx root"), "{}", error);
+    }
+
+    #[test]
+    fn verify_block_checks_id() {
+        let block = &test_blocks()[2];
+        let mut id = block_id(block).unwrap();
+        verify_block(&id, block).unwrap();
+
+        id[31] ^= 1;
+        assert!(verify_block(&id, block).is_err());
+    }
+
+    #[test]
+    fn http_blocks_are_byte_exact() {
+        for block in test_blocks() {
+            let http_block: HttpBlock = serde_json::from_value(http_json(&block)).unwrap();
+            assert_eq!(http_block.into_raw_block().unwrap(), block);
+        }
+
+        let missing: HttpBlock = serde_json::from_value(json!({})).unwrap();
+        assert!(missing.into_raw_block().is_err());
+
+        let blocks = test_blocks();
+        let list: HttpBlockList = serde_json::from_value(json!({
+            "block": blocks.iter().map(http_json).collect::<Vec<_>>(),
+        }))
+        .unwrap();
+        let list: Vec<_> = list
+            .block
+            .into_iter()
+            .map(|block| block.into_raw_block().unwrap())
+            .collect();
+        assert_eq!(list, blocks);
+    }
+
+    #[test]
+    fn legacy_rpc_is_a_grpc_endpoint() {
+        let config: TronConfig = toml::from_str(r#"rpc = "http://127.0.0.1:50051""#).unwrap();
+        assert_eq!(
+            endpoints(&config).unwrap(),
+            vec![TronEndpointConfig {
+                rpc: "http://127.0.0.1:50051".to_string(),
+                api: TronApi::Grpc,
+                solidity_rpc: None,
+            }]
+        );
+
+        let config: TronConfig = toml::from_str(
+            r#"
+            [[endpoints]]
+            rpc = "https://api.trongrid.io"
+            api = "http"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(endpoints(&config).unwrap(), config.endpoints);
+
+        let config: TronConfig = toml::from_str(
+            r#"
+            rpc = "http://127.0.0.1:50051"
+            [[endpoints]]
+            rpc = "https://api.trongrid.io"
+            "#,
+        )
+        .unwrap();
+        assert!(endpoints(&config).is_err());
+
+        let config: TronConfig = toml::from_str("").unwrap();
+        assert!(endpoints(&config).is_err());
+    }
 }
@@ -0,0 +1,133 @@
+use super::proto::{BlockLimit, EmptyMessage, NumberMessage};
+use super::raw::{RawBlockExtention, RawBlockListExtention};
+use super::{check_number, check_range, verify_block, TronSource, MAX_RANGE};
+use async_trait::async_trait;
+use prost::Message;
+use std::error::Error;
+use std::ops::Range;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::Channel;
+use tonic::Request;
+use untron_program::RawBlock;
+
+// GrpcSource reads blocks from a java-tron full node's gRPC API (the Wallet service),
+// and solidified blocks from its WalletSolidity service, which is served on another port
+pub struct GrpcSource {
+    channel: Channel,
+    solidity_channel: Option<Channel>,
+}
+
+impl GrpcSource {
+    // the connection is made on the first request, so that a node that's down
+    // doesn't stop the relayer from starting with the other endpoints
+    pub fn new(rpc_url: &str, solidity_rpc_url: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let channel = Channel::from_shared(rpc_url.to_string())?.connect_lazy();
+        let solidity_channel = match solidity_rpc_url {
+            Some(url) => Some(Channel::from_shared(url.to_string())?.connect_lazy()),
+            None => None,
+        };
+        Ok(Self {
+            channel,
+            solidity_channel,
+        })
+    }
+}
+
+// call calls a gRPC method, decoding the response into the raw views (see tron/raw.rs)
+// instead of the generated messages, so that we get the blocks exactly as the node sent them
+async fn call<M, R>(channel: Channel, path: &'static str, request: M) -> Result<R, Box<dyn Error>>
+where
+    M: Message + Send + Sync + 'static,
+    R: Message + Default + Send + 'static,
+{
+    let mut grpc = tonic::client::Grpc::new(channel);
+    grpc.ready().await?;
+    let response = grpc
+        .unary(
+            Request::new(request),
+            PathAndQuery::from_static(path),
+            ProstCodec::<M, R>::default(),
+        )
+        .await?;
+    Ok(response.into_inner())
+}
+
+// into_raw_block builds a block from the raw views and checks it against its block id
+fn into_raw_block(block: RawBlockExtention) -> Result<RawBlock, Box<dyn Error>> {
+    let header = block.block_header.ok_or("block without header")?;
+    let raw_block = RawBlock {
+        raw_data: header.raw_data,
+        signature: header.witness_signature,
+        txs: block
+            .transactions
+            .into_iter()
+            .map(|tx| tx.transaction)
+            .collect(),
+    };
+
+    verify_block(&block.blockid, &raw_block)?;
+
+    Ok(raw_block)
+}
+
+#[async_trait]
+impl TronSource for GrpcSource {
+    async fn now_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+        let block: RawBlockExtention = call(
+            self.channel.clone(),
+            "/protocol.Wallet/GetNowBlock2",
+            EmptyMessage {},
+        )
+        .await?;
+        into_raw_block(block)
+    }
+
+    async fn solid_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+        let channel = self
+            .solidity_channel
+            .clone()
+            .ok_or("solidity_rpc isn't set for the gRPC endpoint")?;
+        let block: RawBlockExtention = call(
+            channel,
+            "/protocol.WalletSolidity/GetNowBlock2",
+            EmptyMessage {},
+        )
+        .await?;
+        into_raw_block(block)
+    }
+
+    async fn block(&mut self, number: u32) -> Result<RawBlock, Box<dyn Error>> {
+        let block: RawBlockExtention = call(
+            self.channel.clone(),
+            "/protocol.Wallet/GetBlockByNum2",
+            NumberMessage { num: number as i64 },
+        )
+        .await?;
+        let block = into_raw_block(block)?;
+        check_number(number, &block)?;
+        Ok(block)
+    }
+
+    // GetBlockByLimitNext2 returns up to MAX_RANGE blocks per call
+    async fn blocks(&mut self, numbers: Range<u32>) -> Result<Vec<RawBlock>, Box<dyn Error>> {
+        let mut blocks = Vec::with_capacity(numbers.len());
+        for start in numbers.clone().step_by(MAX_RANGE as usize) {
+            let end = numbers.end.min(start + MAX_RANGE);
+            let list: RawBlockListExtention = call(
+                self.channel.clone(),
+                "/protocol.Wallet/GetBlockByLimitNext2",
+                BlockLimit {
+                    start_num: start as i64,
+                    end_num: end as i64,
+                },
+            )
+            .await?;
+            for block in list.block {
+                blocks.push(into_raw_block(block)?);
+            }
+        }
+        check_range(&numbers, &blocks)?;
+        Ok(blocks)
+    }
+}
@@ -0,0 +1,231 @@
+use super::proto::{block_header, transaction};
+use super::raw::RawTransaction;
+use super::{check_number, check_range, verify_block, TronSource, MAX_RANGE};
+use async_trait::async_trait;
+use prost::Message;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::error::Error;
+use std::ops::Range;
+use untron_program::RawBlock;
+
+// RATIONALE:
+// the HTTP API (/wallet/*) returns blocks as JSON, so unlike gRPC we can't get their bytes as is
+// and have to encode them back into protobuf:
+// - transactions come with raw_data_hex, so only the signatures and ret are encoded around it;
+// - the header has no hex form, so its raw_data is encoded from the JSON fields.
+// java-tron and prost both write the fields in order and skip the default values,
+// so this gives the same bytes for everything blocks contain in practice,
+// and verify_block catches any block where it doesn't.
+
+// HttpSource reads blocks from the HTTP API of a java-tron full node or TronGrid
+pub struct HttpSource {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpSource {
+    // `url` is the base URL of the API, e.g. https://api.trongrid.io
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    async fn call<R: DeserializeOwned>(
+        &mut self,
+        path: &str,
+        body: serde_json::Value,
+    ) -> Result<R, Box<dyn Error>> {
+        let response = self
+            .client
+            .post(format!("{}/{}", self.url, path))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
+    }
+}
+
+#[async_trait]
+impl TronSource for HttpSource {
+    async fn now_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+        self.call::<HttpBlock>("wallet/getnowblock", json!({}))
+            .await?
+            .into_raw_block()
+    }
+
+    async fn solid_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+        self.call::<HttpBlock>("walletsolidity/getnowblock", json!({}))
+            .await?
+            .into_raw_block()
+    }
+
+    async fn block(&mut self, number: u32) -> Result<RawBlock, Box<dyn Error>> {
+        let block = self
+            .call::<HttpBlock>("wallet/getblockbynum", json!({ "num": number }))
+            .await?
+            .into_raw_block()?;
+        check_number(number, &block)?;
+        Ok(block)
+    }
+
+    // getblockbylimitnext returns up to MAX_RANGE blocks per call
+    async fn blocks(&mut self, numbers: Range<u32>) -> Result<Vec<RawBlock>, Box<dyn Error>> {
+        let mut blocks = Vec::with_capacity(numbers.len());
+        for start in numbers.clone().step_by(MAX_RANGE as usize) {
+            let end = numbers.end.min(start + MAX_RANGE);
+            let list: HttpBlockList = self
+                .call(
+                    "wallet/getblockbylimitnext",
+                    json!({ "startNum": start, "endNum": end }),
+                )
+                .await?;
+            for block in list.block {
+                blocks.push(block.into_raw_block()?);
+            }
+        }
+        check_range(&numbers, &blocks)?;
+        Ok(blocks)
+    }
+}
+
+// the JSON the HTTP API returns for a range of blocks. the node returns {} if it has none of them
+#[derive(Deserialize, Debug)]
+pub struct HttpBlockList {
+    #[serde(default)]
+    pub block: Vec<HttpBlock>,
+}
+
+// the JSON the HTTP API returns for a block. the node returns {} for blocks it doesn't have
+#[derive(Deserialize, Debug)]
+pub struct HttpBlock {
+    #[serde(rename = "blockID")]
+    block_id: Option<String>,
+    block_header: Option<HttpBlockHeader>,
+    // missing in empty blocks
+    #[serde(default)]
+    transactions: Vec<HttpTransaction>,
+}
+
+#[derive(Deserialize, Debug)]
+struct HttpBlockHeader {
+    raw_data: HttpBlockHeaderRaw,
+    witness_signature: String,
+}
+
+// BlockHeader.raw, with the JSON field names of java-tron's JsonFormat
+#[derive(Deserialize, Debug)]
+struct HttpBlockHeaderRaw {
+    #[serde(default)]
+    timestamp: i64,
+    #[serde(rename = "txTrieRoot", default)]
+    tx_trie_root: String,
+    #[serde(rename = "parentHash", default)]
+    parent_hash: String,
+    #[serde(default)]
+    number: i64,
+    #[serde(default)]
+    witness_id: i64,
+    #[serde(default)]
+    witness_address: String,
+    #[serde(default)]
+    version: i32,
+    #[serde(rename = "accountStateRoot", default)]
+    account_state_root: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct HttpTransaction {
+    raw_data_hex: String,
+    #[serde(default)]
+    signature: Vec<String>,
+    #[serde(default)]
+    ret: Vec<HttpTransactionResult>,
+}
+
+// Transaction.Result. blocks only have contractRet set (and fee, in some very old ones)
+#[derive(Deserialize, Debug)]
+struct HttpTransactionResult {
+    #[serde(default)]
+    fee: i64,
+    // the code is named SUCESS in Tron.proto
+    ret: Option<String>,
+    #[serde(rename = "contractRet")]
+    contract_ret: Option<String>,
+}
+
+impl HttpBlock {
+    pub fn into_raw_block(self) -> Result<RawBlock, Box<dyn Error>> {
+        let block_id = hex::decode(self.block_id.ok_or("Block not found")?)?;
+        let header = self.block_header.ok_or("Block without header")?;
+
+        let raw_data = block_header::Raw {
+            timestamp: header.raw_data.timestamp,
+            tx_trie_root: hex::decode(header.raw_data.tx_trie_root)?,
+            parent_hash: hex::decode(header.raw_data.parent_hash)?,
+            number: header.raw_data.number,
+            witness_id: header.raw_data.witness_id,
+            witness_address: hex::decode(header.raw_data.witness_address)?,
+            version: header.raw_data.version,
+            account_state_root: hex::decode(header.raw_data.account_state_root)?,
+        };
+
+        let block = RawBlock {
+            raw_data: raw_data.encode_to_vec(),
+            signature: hex::decode(header.witness_signature)?,
+            txs: self
+                .transactions
+                .into_iter()
+                .map(HttpTransaction::encode)
+                .collect::<Result<_, _>>()?,
+        };
+
+        verify_block(&block_id, &block)?;
+
+        Ok(block)
+    }
+}
+
+impl HttpTransaction {
+    // encode returns the Transaction message as it's hashed into the block's tx root
+    fn encode(self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let ret = self
+            .ret
+            .into_iter()
+            .map(|ret| -> Result<_, Box<dyn Error>> {
+                Ok(transaction::Result {
+                    fee: ret.fee,
+                    ret: match ret.ret {
+                        Some(code) => transaction::result::Code::from_str_name(&code)
+                            .ok_or(format!("Unknown transaction result code {}", code))?
+                            as i32,
+                        None => 0,
+                    },
+                    contract_ret: match ret.contract_ret {
+                        Some(result) => transaction::result::ContractResult::from_str_name(&result)
+                            .ok_or(format!("Unknown contract result {}", result))?
+                            as i32,
+                        None => 0,
+                    },
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        let tx = RawTransaction {
+            raw_data: hex::decode(self.raw_data_hex)?,
+            signature: self
+                .signature
+                .into_iter()
+                .map(hex::decode)
+                .collect::<Result<_, _>>()?,
+            ret,
+        };
+
+        Ok(tx.encode_to_vec())
+    }
+}
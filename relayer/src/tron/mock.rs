@@ -0,0 +1,77 @@
+use super::{block_id, block_number, verify_block, TronSource};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::error::Error;
+use untron_program::fixtures::Fixture;
+use untron_program::RawBlock;
+
+// MockSource serves blocks from memory, for tests that run the relayer with no network.
+// it can be filled with synthetic blocks (untron_program::testing) or recorded mainnet fixtures,
+// and only has blocks up to `head`, so that tests can make new blocks appear
+pub struct MockSource {
+    blocks: BTreeMap<u32, RawBlock>,
+    ids: BTreeMap<u32, [u8; 32]>,
+    pub head: u32,
+    // the latest solidified block, the head unless set
+    pub solid: Option<u32>,
+}
+
+impl MockSource {
+    // new serves the blocks with their head at the last one
+    pub fn new(blocks: Vec<RawBlock>) -> Result<Self, Box<dyn Error>> {
+        let mut source = Self {
+            blocks: BTreeMap::new(),
+            ids: BTreeMap::new(),
+            head: 0,
+            solid: None,
+        };
+        for block in blocks {
+            let id = block_id(&block)?;
+            source.push(block, id)?;
+        }
+        Ok(source)
+    }
+
+    // from_fixture serves the blocks of a mainnet fixture (see program/fixtures)
+    pub fn from_fixture(fixture: &Fixture) -> Result<Self, Box<dyn Error>> {
+        let mut source = Self::new(vec![])?;
+        for block in &fixture.blocks {
+            let raw_block = RawBlock {
+                raw_data: block.raw_data.clone(),
+                signature: block.witness_signature.clone(),
+                txs: block.txs.iter().map(|tx| tx.raw.clone()).collect(),
+            };
+            source.push(raw_block, block.id)?;
+        }
+        Ok(source)
+    }
+
+    // push adds a block on top of the others and moves the head to it
+    pub fn push(&mut self, block: RawBlock, id: [u8; 32]) -> Result<(), Box<dyn Error>> {
+        let number = block_number(&block)?;
+        self.blocks.insert(number, block);
+        self.ids.insert(number, id);
+        self.head = number;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TronSource for MockSource {
+    async fn now_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+        self.block(self.head).await
+    }
+
+    async fn solid_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+        self.block(self.solid.unwrap_or(self.head)).await
+    }
+
+    async fn block(&mut self, number: u32) -> Result<RawBlock, Box<dyn Error>> {
+        let block = match self.blocks.get(&number) {
+            Some(block) if number <= self.head => block.clone(),
+            _ => return Err(format!("Block {} not found", number).into()),
+        };
+        verify_block(&self.ids[&number], &block)?;
+        Ok(block)
+    }
+}
@@ -0,0 +1,480 @@
+use super::{block_id, block_number, TronSource};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ops::Range;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+use tracing::{info, warn};
+use untron_program::RawBlock;
+
+// RATIONALE:
+// a single Tron node going down (or returning garbage) shouldn't crash the relayer,
+// and a single lying node shouldn't make us spend hours proving blocks that aren't on the chain.
+// MultiSource reads from several endpoints:
+// - every endpoint has a health score, and they're tried from the healthiest one;
+// - an endpoint that fails is put aside for a backoff that doubles on every consecutive failure;
+// - if all endpoints fail, they're all tried again after a backoff, up to `retries` times;
+// - in quorum mode, a block is only accepted once `quorum` endpoints returned the same block id.
+//   endpoints that returned a different one are penalized like failed ones.
+
+// how much of the health score is the result of the latest request
+const SCORE_WEIGHT: f64 = 0.2;
+
+// longest time an endpoint is put aside for
+const MAX_ENDPOINT_BACKOFF: Duration = Duration::from_secs(300);
+
+struct Endpoint {
+    name: String,
+    source: Box<dyn TronSource>,
+    // moving average of successes (1) and failures (0), starts at 1
+    score: f64,
+    consecutive_failures: u32,
+    // the endpoint isn't tried before this unless all others are put aside too
+    available_at: Instant,
+}
+
+impl Endpoint {
+    fn succeeded(&mut self) {
+        if self.consecutive_failures > 0 {
+            info!("Tron endpoint {} is back", self.name);
+        }
+        self.score = self.score * (1.0 - SCORE_WEIGHT) + SCORE_WEIGHT;
+        self.consecutive_failures = 0;
+        self.available_at = Instant::now();
+    }
+
+    fn failed(&mut self, backoff: Duration, error: &str) {
+        self.score *= 1.0 - SCORE_WEIGHT;
+        self.consecutive_failures += 1;
+        let backoff = backoff
+            .saturating_mul(1 << (self.consecutive_failures - 1).min(16))
+            .min(MAX_ENDPOINT_BACKOFF);
+        self.available_at = Instant::now() + backoff;
+        warn!(
+            "Tron endpoint {} failed ({} in a row, score {:.2}, put aside for {:?}): {}",
+            self.name, self.consecutive_failures, self.score, backoff, error
+        );
+    }
+}
+
+// the endpoints that returned the same blocks
+struct Answer {
+    endpoints: Vec<usize>,
+    blocks: Vec<RawBlock>,
+}
+
+pub struct MultiSource {
+    endpoints: Vec<Endpoint>,
+    quorum: usize,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl MultiSource {
+    // `endpoints` are named sources, in order of preference
+    pub fn new(
+        endpoints: Vec<(String, Box<dyn TronSource>)>,
+        quorum: usize,
+        retries: u32,
+        backoff: Duration,
+    ) -> Result<Self, Box<dyn Error>> {
+        if quorum == 0 || quorum > endpoints.len() {
+            return Err(format!(
+                "Tron quorum must be between 1 and the number of endpoints ({}), got {}",
+                endpoints.len(),
+                quorum
+            )
+            .into());
+        }
+
+        let now = Instant::now();
+        Ok(Self {
+            endpoints: endpoints
+                .into_iter()
+                .map(|(name, source)| Endpoint {
+                    name,
+                    source,
+                    score: 1.0,
+                    consecutive_failures: 0,
+                    available_at: now,
+                })
+                .collect(),
+            quorum,
+            retries,
+            backoff,
+        })
+    }
+
+    // order returns the indices of the endpoints in the order they should be tried:
+    // available ones from the healthiest, then the put aside ones from the soonest available
+    fn order(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let mut order: Vec<usize> = (0..self.endpoints.len()).collect();
+        order.sort_by(|&a, &b| {
+            let (a, b) = (&self.endpoints[a], &self.endpoints[b]);
+            (a.available_at > now)
+                .cmp(&(b.available_at > now))
+                .then(if a.available_at > now {
+                    a.available_at.cmp(&b.available_at)
+                } else {
+                    b.score.total_cmp(&a.score)
+                })
+        });
+        order
+    }
+
+    // backoff is called before every attempt of a request: it waits before retries,
+    // with a doubling backoff, and gives up after `retries` of them
+    async fn backoff(&mut self, attempt: u32, what: &str) -> Result<(), Box<dyn Error>> {
+        if attempt > self.retries {
+            return Err(format!(
+                "Couldn't get {} from any Tron endpoint after {} attempts",
+                what, attempt
+            )
+            .into());
+        }
+        if attempt > 0 {
+            let backoff = self.backoff.saturating_mul(1 << (attempt - 1).min(16));
+            warn!(
+                "Couldn't get {} from any Tron endpoint, retrying in {:?}",
+                what, backoff
+            );
+            sleep(backoff).await;
+        }
+        Ok(())
+    }
+
+    // heads returns the latest (or latest solidified) blocks of the first `quorum` endpoints
+    // that answered, with their numbers
+    async fn heads(&mut self, solid: bool) -> Option<Vec<(u32, RawBlock)>> {
+        let mut heads = vec![];
+        for i in self.order() {
+            let endpoint = &mut self.endpoints[i];
+            let head = if solid {
+                endpoint.source.solid_block().await
+            } else {
+                endpoint.source.now_block().await
+            };
+            let head = match head {
+                Ok(block) => block_number(&block).map(|number| (number, block)),
+                Err(e) => Err(e),
+            };
+            match head {
+                Ok(head) => {
+                    endpoint.succeeded();
+                    heads.push(head);
+                    if heads.len() == self.quorum {
+                        return Some(heads);
+                    }
+                }
+                Err(e) => endpoint.failed(self.backoff, &e.to_string()),
+            }
+        }
+        None
+    }
+
+    // latest returns the latest (or latest solidified) block, see now_block
+    async fn latest(&mut self, solid: bool) -> Result<RawBlock, Box<dyn Error>> {
+        let what = if solid {
+            "the latest solidified block"
+        } else {
+            "the latest block"
+        };
+
+        for attempt in 0.. {
+            self.backoff(attempt, what).await?;
+
+            if let Some(heads) = self.heads(solid).await {
+                let (number, block) = heads.into_iter().min_by_key(|(number, _)| *number).unwrap();
+                if self.quorum == 1 {
+                    return Ok(block);
+                }
+                if let Some(mut blocks) = self.agreed_blocks(number..number + 1).await {
+                    return Ok(blocks.remove(0));
+                }
+            }
+        }
+        unreachable!()
+    }
+
+    // agreed_blocks asks the endpoints for the blocks until `quorum` of them returned the same ones
+    async fn agreed_blocks(&mut self, numbers: Range<u32>) -> Option<Vec<RawBlock>> {
+        // answers by the ids of the returned blocks
+        let mut answers: BTreeMap<Vec<[u8; 32]>, Answer> = BTreeMap::new();
+
+        for i in self.order() {
+            let endpoint = &mut self.endpoints[i];
+            let blocks = if numbers.len() == 1 {
+                endpoint
+                    .source
+                    .block(numbers.start)
+                    .await
+                    .map(|block| vec![block])
+            } else {
+                endpoint.source.blocks(numbers.clone()).await
+            };
+            let ids = blocks.and_then(|blocks| {
+                let ids = blocks.iter().map(block_id).collect::<Result<Vec<_>, _>>()?;
+                Ok((ids, blocks))
+            });
+            let (ids, blocks) = match ids {
+                Ok(ids) => ids,
+                Err(e) => {
+                    endpoint.failed(self.backoff, &e.to_string());
+                    continue;
+                }
+            };
+
+            let answer = answers.entry(ids.clone()).or_insert_with(|| Answer {
+                endpoints: vec![],
+                blocks,
+            });
+            answer.endpoints.push(i);
+            if answer.endpoints.len() < self.quorum {
+                continue;
+            }
+
+            let answer = answers.remove(&ids).unwrap();
+            for i in answer.endpoints {
+                self.endpoints[i].succeeded();
+            }
+            for (other_ids, other) in answers {
+                for i in other.endpoints {
+                    self.endpoints[i].failed(
+                        self.backoff,
+                        &format!(
+                            "returned blocks {}..{} ending with {}, but {} endpoints agree on {}",
+                            numbers.start,
+                            numbers.end,
+                            hex::encode(other_ids.last().unwrap_or(&[0; 32])),
+                            self.quorum,
+                            hex::encode(ids.last().unwrap_or(&[0; 32]))
+                        ),
+                    );
+                }
+            }
+            return Some(answer.blocks);
+        }
+
+        if answers.len() > 1 {
+            warn!(
+                "Tron endpoints returned {} different blocks {}..{}, none of them by {} endpoints",
+                answers.len(),
+                numbers.start,
+                numbers.end,
+                self.quorum
+            );
+        }
+        // the endpoints that answered can't be blamed for the others failing
+        for answer in answers.values() {
+            for &i in &answer.endpoints {
+                self.endpoints[i].succeeded();
+            }
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl TronSource for MultiSource {
+    // in quorum mode, this is the latest block that `quorum` endpoints agree on,
+    // which is the lowest of their latest blocks (they're rarely at the same height)
+    async fn now_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+        self.latest(false).await
+    }
+
+    // same as now_block, for solidified blocks
+    async fn solid_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+        self.latest(true).await
+    }
+
+    async fn block(&mut self, number: u32) -> Result<RawBlock, Box<dyn Error>> {
+        Ok(self.blocks(number..number + 1).await?.remove(0))
+    }
+
+    async fn blocks(&mut self, numbers: Range<u32>) -> Result<Vec<RawBlock>, Box<dyn Error>> {
+        if numbers.is_empty() {
+            return Ok(vec![]);
+        }
+
+        for attempt in 0.. {
+            let what = format!("blocks {}..{}", numbers.start, numbers.end);
+            self.backoff(attempt, &what).await?;
+
+            if let Some(blocks) = self.agreed_blocks(numbers.clone()).await {
+                return Ok(blocks);
+            }
+        }
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tron::mock::MockSource;
+    use untron_program::testing::*;
+
+    // Flaky fails its next `failures` requests
+    struct Flaky {
+        source: MockSource,
+        failures: u32,
+    }
+
+    #[async_trait]
+    impl TronSource for Flaky {
+        async fn now_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err("connection refused".into());
+            }
+            self.source.now_block().await
+        }
+
+        async fn solid_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err("connection refused".into());
+            }
+            self.source.solid_block().await
+        }
+
+        async fn block(&mut self, number: u32) -> Result<RawBlock, Box<dyn Error>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err("connection refused".into());
+            }
+            self.source.block(number).await
+        }
+    }
+
+    // chain returns 3 blocks on top of TEST_START_BLOCK with a transaction from `sender` in each,
+    // so that chains with different senders are different forks
+    fn chain(sender: u8) -> Vec<RawBlock> {
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        (0..3)
+            .map(|_| chain.block(vec![usdt_transfer([sender; 20], [0x22; 20], 1)]))
+            .collect()
+    }
+
+    fn source(blocks: Vec<RawBlock>, failures: u32) -> Box<dyn TronSource> {
+        Box::new(Flaky {
+            source: MockSource::new(blocks).unwrap(),
+            failures,
+        })
+    }
+
+    fn multi_source(sources: Vec<Box<dyn TronSource>>, quorum: usize, retries: u32) -> MultiSource {
+        let endpoints = sources
+            .into_iter()
+            .enumerate()
+            .map(|(i, source)| (format!("endpoint {}", i), source))
+            .collect();
+        MultiSource::new(endpoints, quorum, retries, Duration::ZERO).unwrap()
+    }
+
+    const FIRST: u32 = TEST_START_BLOCK as u32 + 1;
+
+    #[tokio::test]
+    async fn fails_over_to_healthy_endpoints() {
+        let honest = chain(0x11);
+        let mut multi = multi_source(
+            vec![source(honest.clone(), u32::MAX), source(honest.clone(), 0)],
+            1,
+            0,
+        );
+
+        assert_eq!(multi.block(FIRST).await.unwrap(), honest[0]);
+        assert_eq!(multi.now_block().await.unwrap(), honest[2]);
+
+        // after failing once, the endpoint is tried after the healthy one
+        assert_eq!(multi.endpoints[0].consecutive_failures, 1);
+        assert!(multi.endpoints[0].score < multi.endpoints[1].score);
+        assert_eq!(multi.order(), vec![1, 0]);
+    }
+
+    #[tokio::test]
+    async fn retries_when_all_endpoints_fail() {
+        let honest = chain(0x11);
+
+        let mut multi = multi_source(
+            vec![source(honest.clone(), 2), source(honest.clone(), 2)],
+            1,
+            2,
+        );
+        assert_eq!(multi.block(FIRST + 1).await.unwrap(), honest[1]);
+
+        let mut multi = multi_source(vec![source(honest.clone(), 3)], 1, 2);
+        let error = multi.block(FIRST + 1).await.unwrap_err();
+        assert!(error.to_string().contains("after 3 attempts"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn quorum_outvotes_a_lying_endpoint() {
+        let honest = chain(0x11);
+        let lying = chain(0x66);
+
+        let mut multi = multi_source(
+            vec![
+                source(lying.clone(), 0),
+                source(honest.clone(), 0),
+                source(honest.clone(), 0),
+            ],
+            2,
+            0,
+        );
+        assert_eq!(multi.block(FIRST + 1).await.unwrap(), honest[1]);
+        assert_eq!(multi.endpoints[0].consecutive_failures, 1);
+        assert_eq!(multi.order(), vec![1, 2, 0]);
+
+        // without a quorum of the same block, nothing is accepted
+        let mut multi = multi_source(
+            vec![
+                source(lying, 0),
+                source(honest.clone(), 0),
+                source(honest, u32::MAX),
+            ],
+            2,
+            1,
+        );
+        assert!(multi.block(FIRST + 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn quorum_checks_ranges() {
+        let honest = chain(0x11);
+        let mut lying = honest.clone();
+        lying[2] = chain(0x66)[2].clone();
+
+        let mut multi = multi_source(
+            vec![
+                source(lying, 0),
+                source(honest.clone(), 0),
+                source(honest.clone(), 0),
+            ],
+            2,
+            0,
+        );
+        assert_eq!(multi.blocks(FIRST..FIRST + 3).await.unwrap(), honest);
+        assert_eq!(multi.endpoints[0].consecutive_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn quorum_head_is_the_lowest_agreed_block() {
+        let honest = chain(0x11);
+        let mut behind = MockSource::new(honest.clone()).unwrap();
+        behind.head = FIRST + 1;
+
+        let mut multi = multi_source(vec![source(honest.clone(), 0), Box::new(behind)], 2, 0);
+        assert_eq!(multi.now_block().await.unwrap(), honest[1]);
+    }
+
+    #[test]
+    fn quorum_must_be_reachable() {
+        let endpoints = || vec![("endpoint".to_string(), source(vec![], 0))];
+        assert!(MultiSource::new(endpoints(), 0, 0, Duration::ZERO).is_err());
+        assert!(MultiSource::new(endpoints(), 2, 0, Duration::ZERO).is_err());
+        assert!(MultiSource::new(endpoints(), 1, 0, Duration::ZERO).is_ok());
+    }
+}
@@ -0,0 +1,56 @@
+// views of the api.proto/Tron.proto messages that keep the embedded messages we hash as bytes.
+// on the wire, an embedded message and a bytes field are the same thing,
+// so decoding them as bytes gives us exactly what the node sent, without re-encoding them with prost
+// (which drops unknown fields and could encode differently than java-tron).
+// field numbers must match the original messages.
+
+// BlockExtention
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RawBlockExtention {
+    #[prost(message, repeated, tag = "1")]
+    pub transactions: Vec<RawTransactionExtention>,
+    #[prost(message, optional, tag = "2")]
+    pub block_header: Option<RawBlockHeader>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub blockid: Vec<u8>,
+}
+
+// BlockListExtention
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RawBlockListExtention {
+    #[prost(message, repeated, tag = "1")]
+    pub block: Vec<RawBlockExtention>,
+}
+
+// TransactionExtention
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RawTransactionExtention {
+    // Transaction
+    #[prost(bytes = "vec", tag = "1")]
+    pub transaction: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub txid: Vec<u8>,
+}
+
+// BlockHeader
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RawBlockHeader {
+    // BlockHeader.raw
+    #[prost(bytes = "vec", tag = "1")]
+    pub raw_data: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub witness_signature: Vec<u8>,
+}
+
+// Transaction, with raw_data kept as bytes. used to put the transactions
+// the HTTP API returns back together around their raw_data_hex (see tron/http.rs)
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RawTransaction {
+    // Transaction.raw
+    #[prost(bytes = "vec", tag = "1")]
+    pub raw_data: Vec<u8>,
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub signature: Vec<Vec<u8>>,
+    #[prost(message, repeated, tag = "5")]
+    pub ret: Vec<super::proto::transaction::Result>,
+}
@@ -0,0 +1,405 @@
+use super::{block_id, block_number, parent_id, TronSource};
+use crate::config::Follow;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{info, warn};
+use untron_program::{block_id_to_number, RawBlock};
+
+// RATIONALE:
+// after downtime, the relayer is thousands of blocks behind, and fetching them one by one
+// (with a head check before each) takes hours. so blocks are fetched by a separate task:
+// - while behind the head, it fetches ranges of `batch_size` blocks back to back, without polling;
+// - at the head, it polls the latest block every `poll_interval` until there's a new one.
+// the batches go through a channel of `prefetch` batches, so fetching runs concurrently with
+// processing, but never more than `prefetch` batches ahead of it.
+//
+// blocks at the head can be reorged out (solidified ones can't, see Follow).
+// the stream remembers the ids of the last MAX_REORG_DEPTH blocks it returned and checks that
+// every next block points to the previous one. when one doesn't, it finds the last block the
+// node still has, restarts fetching after it, and tells the caller to rewind to it.
+
+// how often to check for new blocks at the head of the chain
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// how many blocks back a reorg can be handled. a block is solidified once 19 of the 27 SRs
+// built on top of it, so reorgs never go deeper than that
+pub const MAX_REORG_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncStatus {
+    // the last block returned by the stream is this many blocks behind the latest known one
+    CatchingUp { behind: u32 },
+    AtTip,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Next {
+    Block(RawBlock),
+    // the blocks after `fork` were reorged out, and the stream continues from fork + 1
+    Reorg { fork: u32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StreamConfig {
+    pub batch_size: u32,
+    pub prefetch: usize,
+    pub poll_interval: Duration,
+    pub follow: Follow,
+}
+
+struct Batch {
+    blocks: Vec<RawBlock>,
+    // the latest block number known when the batch was fetched
+    head: u32,
+}
+
+type Source = Arc<Mutex<Box<dyn TronSource>>>;
+
+pub struct BlockStream {
+    source: Source,
+    config: StreamConfig,
+    // errors are sent as strings because Box<dyn Error> can't be sent between tasks
+    batches: mpsc::Receiver<Result<Batch, String>>,
+    buffer: VecDeque<RawBlock>,
+    // numbers and ids of the latest returned blocks, oldest first
+    recent: VecDeque<(u32, [u8; 32])>,
+    head: u32,
+    status: SyncStatus,
+    task: JoinHandle<()>,
+}
+
+impl BlockStream {
+    // spawn starts fetching the blocks after the one with `latest_id`
+    pub fn spawn(source: Box<dyn TronSource>, latest_id: [u8; 32], config: StreamConfig) -> Self {
+        let latest = block_id_to_number(latest_id);
+        let source = Arc::new(Mutex::new(source));
+        let (batches, task) = start(source.clone(), latest + 1, config);
+
+        Self {
+            source,
+            config,
+            batches,
+            buffer: VecDeque::new(),
+            recent: VecDeque::from([(latest, latest_id)]),
+            head: latest,
+            status: SyncStatus::AtTip,
+            task,
+        }
+    }
+
+    // next returns the next block, waiting for it if it's not fetched yet
+    pub async fn next(&mut self) -> Result<Next, Box<dyn Error>> {
+        if self.buffer.is_empty() {
+            let batch = self
+                .batches
+                .recv()
+                .await
+                .ok_or("Tron block fetcher stopped")??;
+            self.head = batch.head;
+            self.buffer.extend(batch.blocks);
+        }
+
+        let block = self.buffer.pop_front().unwrap();
+        let &(latest, latest_id) = self.recent.back().unwrap();
+        if parent_id(&block)? != latest_id {
+            let fork = self.rewind().await?;
+            return Ok(Next::Reorg { fork });
+        }
+
+        let number = latest + 1;
+        self.recent.push_back((number, block_id(&block)?));
+        if self.recent.len() > MAX_REORG_DEPTH {
+            self.recent.pop_front();
+        }
+
+        let status = if number < self.head {
+            SyncStatus::CatchingUp {
+                behind: self.head - number,
+            }
+        } else {
+            SyncStatus::AtTip
+        };
+        match (self.status, status) {
+            (SyncStatus::AtTip, SyncStatus::CatchingUp { behind }) => {
+                info!("Catching up with Tron: {} blocks behind", behind)
+            }
+            (SyncStatus::CatchingUp { .. }, SyncStatus::AtTip) => {
+                info!("Caught up with Tron at block {}", number)
+            }
+            _ => {}
+        }
+        self.status = status;
+
+        Ok(Next::Block(block))
+    }
+
+    // status tells whether the last returned block is the latest one
+    pub fn status(&self) -> SyncStatus {
+        self.status
+    }
+
+    // rewind finds the latest returned block that's still in the chain
+    // and restarts fetching after it
+    async fn rewind(&mut self) -> Result<u32, Box<dyn Error>> {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+        self.buffer.clear();
+
+        let reorged = self.recent.back().unwrap().0;
+        let mut source = self.source.lock().await;
+        loop {
+            let &(number, id) = self
+                .recent
+                .back()
+                .ok_or(format!("Tron reorg at block {} is too deep", reorged))?;
+            if block_id(&source.block(number).await?)? == id {
+                break;
+            }
+            self.recent.pop_back();
+        }
+        drop(source);
+
+        let fork = self.recent.back().unwrap().0;
+        warn!(
+            "Tron reorg: blocks {}..={} were replaced, continuing from block {}",
+            fork + 1,
+            reorged,
+            fork + 1
+        );
+
+        (self.batches, self.task) = start(self.source.clone(), fork + 1, self.config);
+        Ok(fork)
+    }
+}
+
+impl Drop for BlockStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn start(
+    source: Source,
+    next: u32,
+    config: StreamConfig,
+) -> (mpsc::Receiver<Result<Batch, String>>, JoinHandle<()>) {
+    let (tx, batches) = mpsc::channel(config.prefetch.max(1));
+    let task = tokio::spawn(fetch(source, next, config, tx));
+    (batches, task)
+}
+
+// fetch sends batches of blocks from `next` on until the stream is dropped or the source fails
+async fn fetch(
+    source: Source,
+    mut next: u32,
+    config: StreamConfig,
+    tx: mpsc::Sender<Result<Batch, String>>,
+) {
+    let mut head = next.saturating_sub(1);
+
+    loop {
+        let batch = next_batch(&source, next, &mut head, &config)
+            .await
+            .map(|blocks| {
+                next += blocks.len() as u32;
+                Batch { blocks, head }
+            })
+            .map_err(|e| e.to_string());
+        let failed = batch.is_err();
+
+        if tx.send(batch).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+// next_batch returns the blocks from `next` on, up to `batch_size` of them,
+// polling for new blocks if `next` is after the `head` we know of
+async fn next_batch(
+    source: &Source,
+    next: u32,
+    head: &mut u32,
+    config: &StreamConfig,
+) -> Result<Vec<RawBlock>, Box<dyn Error>> {
+    while next > *head {
+        let latest = match config.follow {
+            Follow::Head => source.lock().await.now_block().await?,
+            Follow::Solidified => source.lock().await.solid_block().await?,
+        };
+        *head = block_number(&latest)?;
+
+        if *head == next {
+            // that's the block we need, so don't fetch it again
+            return Ok(vec![latest]);
+        }
+        if *head < next {
+            sleep(config.poll_interval).await;
+        }
+    }
+
+    let end = (*head + 1).min(next + config.batch_size.max(1));
+    source.lock().await.blocks(next..end).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tron::mock::MockSource;
+    use async_trait::async_trait;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use untron_program::testing::*;
+
+    // Shared lets the test add blocks to the source the stream is reading from
+    struct Shared(Arc<Mutex<MockSource>>);
+
+    #[async_trait]
+    impl TronSource for Shared {
+        async fn now_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+            self.0.lock().await.now_block().await
+        }
+
+        async fn solid_block(&mut self) -> Result<RawBlock, Box<dyn Error>> {
+            self.0.lock().await.solid_block().await
+        }
+
+        async fn block(&mut self, number: u32) -> Result<RawBlock, Box<dyn Error>> {
+            self.0.lock().await.block(number).await
+        }
+    }
+
+    fn config(follow: Follow) -> StreamConfig {
+        StreamConfig {
+            batch_size: 100,
+            prefetch: 2,
+            poll_interval: Duration::from_millis(10),
+            follow,
+        }
+    }
+
+    async fn next_block(stream: &mut BlockStream) -> RawBlock {
+        match stream.next().await.unwrap() {
+            Next::Block(block) => block,
+            next => panic!("expected a block, got {:?}", next),
+        }
+    }
+
+    #[tokio::test]
+    async fn catches_up_then_follows_the_tip() {
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        let genesis = chain.latest_block_id;
+        let blocks = chain.blocks(250);
+        let source = Arc::new(Mutex::new(MockSource::new(blocks.clone()).unwrap()));
+
+        let mut stream = BlockStream::spawn(
+            Box::new(Shared(source.clone())),
+            genesis,
+            config(Follow::Head),
+        );
+
+        assert_eq!(next_block(&mut stream).await, blocks[0]);
+        assert_eq!(stream.status(), SyncStatus::CatchingUp { behind: 249 });
+        for block in &blocks[1..] {
+            assert_eq!(&next_block(&mut stream).await, block);
+        }
+        assert_eq!(stream.status(), SyncStatus::AtTip);
+
+        // new blocks are picked up by polling
+        for _ in 0..3 {
+            let block = chain.block(vec![]);
+            let id = chain.latest_block_id;
+            source.lock().await.push(block.clone(), id).unwrap();
+
+            assert_eq!(next_block(&mut stream).await, block);
+            assert_eq!(stream.status(), SyncStatus::AtTip);
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_solidified_blocks() {
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        let genesis = chain.latest_block_id;
+        let blocks = chain.blocks(30);
+        let mut mock = MockSource::new(blocks.clone()).unwrap();
+        mock.solid = Some(TEST_START_BLOCK as u32 + 10);
+        let source = Arc::new(Mutex::new(mock));
+
+        let mut stream = BlockStream::spawn(
+            Box::new(Shared(source.clone())),
+            genesis,
+            config(Follow::Solidified),
+        );
+        for block in &blocks[..10] {
+            assert_eq!(&next_block(&mut stream).await, block);
+        }
+        assert_eq!(stream.status(), SyncStatus::AtTip);
+
+        // the next block is only returned once it's solidified
+        let next = tokio::time::timeout(Duration::from_millis(100), stream.next()).await;
+        assert!(next.is_err());
+        source.lock().await.solid = Some(TEST_START_BLOCK as u32 + 11);
+        assert_eq!(next_block(&mut stream).await, blocks[10]);
+    }
+
+    #[tokio::test]
+    async fn rewinds_on_reorg() {
+        // two chains that share the first 7 blocks
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        let genesis = chain.latest_block_id;
+        let mut fork = TestChain::new(TEST_START_BLOCK);
+        let shared = chain.blocks(7);
+        assert_eq!(fork.blocks(7), shared);
+        let a = chain.blocks(3);
+        let b: Vec<_> = (0..5)
+            .map(|_| fork.block(vec![usdt_transfer([0x11; 20], [0x22; 20], 1)]))
+            .collect();
+
+        let source = Arc::new(Mutex::new(
+            MockSource::new([shared.clone(), a.clone()].concat()).unwrap(),
+        ));
+        let mut stream = BlockStream::spawn(
+            Box::new(Shared(source.clone())),
+            genesis,
+            config(Follow::Head),
+        );
+        for block in shared.iter().chain(&a) {
+            assert_eq!(&next_block(&mut stream).await, block);
+        }
+
+        // the node switches to the fork, which replaces blocks 8..=10 and goes on
+        *source.lock().await = MockSource::new([shared.clone(), b.clone()].concat()).unwrap();
+
+        assert_eq!(
+            stream.next().await.unwrap(),
+            Next::Reorg {
+                fork: TEST_START_BLOCK as u32 + 7
+            }
+        );
+        for block in &b {
+            assert_eq!(&next_block(&mut stream).await, block);
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_source_fails() {
+        let mut chain = TestChain::new(TEST_START_BLOCK);
+        let genesis = chain.latest_block_id;
+        let mut blocks: Vec<_> = (0..3)
+            .map(|_| chain.block(vec![usdt_transfer([0x11; 20], [0x22; 20], 1)]))
+            .collect();
+        blocks[1].txs[0].push(0);
+
+        let mut stream = BlockStream::spawn(
+            Box::new(MockSource::new(blocks.clone()).unwrap()),
+            genesis,
+            config(Follow::Head),
+        );
+        assert!(stream.next().await.is_err());
+        assert!(stream.next().await.is_err());
+    }
+}
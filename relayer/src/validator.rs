@@ -0,0 +1,221 @@
+use alloy_sol_types::SolType;
+use async_trait::async_trait;
+use std::error::Error;
+use std::fmt;
+use tracing::info;
+use untron_program::UntronPublicValues;
+
+// RATIONALE:
+// a closeOrders transaction that reverts still costs gas, and it reverts for reasons the relayer
+// can tell in advance: the Core's state changed since the proof started (another relayer, or a
+// restart from an old store), the proof is on top of another Tron block or includes an action the
+// Core doesn't have, or the verifier rejects it. so before sending a proof, its public values are
+// checked against the Core one by one, which tells exactly what's wrong, and then closeOrders is
+// simulated with eth_call, which catches the rest (the proof itself, the vkey, the closed orders).
+// the Core doesn't store its latest Tron block, so it's read from the public values of the
+// latest relay (see zksync.rs).
+
+// CoreView is what the validator reads from the Core (see zksync.rs)
+#[async_trait]
+pub trait CoreView: Send + Sync {
+    async fn state_hash(&self) -> Result<[u8; 32], Box<dyn Error>>;
+
+    // latest_block_id returns the id of the latest Tron block proven to the Core
+    async fn latest_block_id(&self) -> Result<[u8; 32], Box<dyn Error>>;
+
+    // is_action tells whether `action` is in the Core's action chain
+    async fn is_action(&self, action: [u8; 32]) -> Result<bool, Box<dyn Error>>;
+
+    // simulate calls closeOrders without sending a transaction, and fails if it reverts
+    async fn simulate(&self, proof: &[u8], public_values: &[u8]) -> Result<(), Box<dyn Error>>;
+}
+
+// Rejection is why a proof wasn't sent
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejection {
+    Malformed(String),
+    StateHash { proof: [u8; 32], core: [u8; 32] },
+    BlockId { proof: [u8; 32], core: [u8; 32] },
+    UnknownAction([u8; 32]),
+    Reverted(String),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::Malformed(e) => write!(f, "the public values can't be decoded: {}", e),
+            Rejection::StateHash { proof, core } => write!(
+                f,
+                "the proof starts from state 0x{}, but the Core has 0x{}",
+                hex::encode(proof),
+                hex::encode(core)
+            ),
+            Rejection::BlockId { proof, core } => write!(
+                f,
+                "the proof starts from Tron block 0x{}, but the Core is at 0x{}",
+                hex::encode(proof),
+                hex::encode(core)
+            ),
+            Rejection::UnknownAction(action) => write!(
+                f,
+                "the proof includes action 0x{}, which the Core doesn't have",
+                hex::encode(action)
+            ),
+            Rejection::Reverted(e) => write!(f, "closeOrders reverts: {}", e),
+        }
+    }
+}
+
+impl Error for Rejection {}
+
+// validate checks a proof against the Core before it's sent, and fails with a Rejection
+// if closeOrders would revert
+pub async fn validate(
+    core: &dyn CoreView,
+    proof: &[u8],
+    public_values: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let (old_block_id, _, _, new_action_chain, old_state_hash, _, closed_orders) =
+        UntronPublicValues::abi_decode(public_values, true)
+            .map_err(|e| Rejection::Malformed(e.to_string()))?;
+
+    let state_hash = core.state_hash().await?;
+    if old_state_hash.0 != state_hash {
+        return Err(Rejection::StateHash {
+            proof: old_state_hash.0,
+            core: state_hash,
+        }
+        .into());
+    }
+
+    let latest_block_id = core.latest_block_id().await?;
+    if old_block_id.0 != latest_block_id {
+        return Err(Rejection::BlockId {
+            proof: old_block_id.0,
+            core: latest_block_id,
+        }
+        .into());
+    }
+
+    if !core.is_action(new_action_chain.0).await? {
+        return Err(Rejection::UnknownAction(new_action_chain.0).into());
+    }
+
+    core.simulate(proof, public_values)
+        .await
+        .map_err(|e| Rejection::Reverted(e.to_string()))?;
+
+    info!(
+        "The proof passed the checks against the Core, closing {} orders",
+        closed_orders.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const STATE_HASH: [u8; 32] = [1; 32];
+    const BLOCK_ID: [u8; 32] = [2; 32];
+    const ACTION: [u8; 32] = [3; 32];
+
+    // MockCore is a Core at STATE_HASH and BLOCK_ID that has ACTION
+    #[derive(Default)]
+    struct MockCore {
+        reverts: bool,
+        simulated: AtomicBool,
+    }
+
+    #[async_trait]
+    impl CoreView for MockCore {
+        async fn state_hash(&self) -> Result<[u8; 32], Box<dyn Error>> {
+            Ok(STATE_HASH)
+        }
+
+        async fn latest_block_id(&self) -> Result<[u8; 32], Box<dyn Error>> {
+            Ok(BLOCK_ID)
+        }
+
+        async fn is_action(&self, action: [u8; 32]) -> Result<bool, Box<dyn Error>> {
+            Ok(action == ACTION)
+        }
+
+        async fn simulate(&self, _: &[u8], _: &[u8]) -> Result<(), Box<dyn Error>> {
+            self.simulated.store(true, Ordering::SeqCst);
+            if self.reverts {
+                return Err("invalid proof".into());
+            }
+            Ok(())
+        }
+    }
+
+    fn public_values(state_hash: [u8; 32], block_id: [u8; 32], action: [u8; 32]) -> Vec<u8> {
+        let closed_orders: Vec<([u8; 32], u64)> = vec![([4; 32], 100)];
+        UntronPublicValues::abi_encode(&(
+            block_id,
+            [5; 32],
+            [0; 32],
+            action,
+            state_hash,
+            [6; 32],
+            closed_orders,
+        ))
+    }
+
+    async fn rejection(core: &MockCore, public_values: &[u8]) -> Rejection {
+        *validate(core, &[], public_values)
+            .await
+            .unwrap_err()
+            .downcast::<Rejection>()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn passes_proofs_on_top_of_the_core() {
+        let core = MockCore::default();
+        validate(&core, &[], &public_values(STATE_HASH, BLOCK_ID, ACTION))
+            .await
+            .unwrap();
+        assert!(core.simulated.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn rejects_proofs_that_would_revert() {
+        let core = MockCore::default();
+        assert!(matches!(
+            rejection(&core, &[1, 2, 3]).await,
+            Rejection::Malformed(_)
+        ));
+        assert_eq!(
+            rejection(&core, &public_values([9; 32], BLOCK_ID, ACTION)).await,
+            Rejection::StateHash {
+                proof: [9; 32],
+                core: STATE_HASH
+            }
+        );
+        assert_eq!(
+            rejection(&core, &public_values(STATE_HASH, [9; 32], ACTION)).await,
+            Rejection::BlockId {
+                proof: [9; 32],
+                core: BLOCK_ID
+            }
+        );
+        assert_eq!(
+            rejection(&core, &public_values(STATE_HASH, BLOCK_ID, [9; 32])).await,
+            Rejection::UnknownAction([9; 32])
+        );
+        // the checks fail before anything is simulated
+        assert!(!core.simulated.load(Ordering::SeqCst));
+
+        let core = MockCore {
+            reverts: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            rejection(&core, &public_values(STATE_HASH, BLOCK_ID, ACTION)).await,
+            Rejection::Reverted("invalid proof".to_string())
+        );
+    }
+}
@@ -1,10 +1,17 @@
+use crate::actions::{missed_actions, ActionSource, ActionStream};
+use crate::prover::VkeySource;
+use crate::reconstruct::Relay;
+use crate::validator::{validate, CoreView};
+use async_trait::async_trait;
+use ethers::abi::AbiDecode;
 use ethers::contract::abigen;
+use ethers::contract::parse_log;
 use ethers::middleware::SignerMiddleware;
 use ethers::prelude::*;
 use k256::ecdsa::SigningKey;
 use std::str::FromStr;
-use tokio::sync::mpsc::Sender;
-use untron_program::Action;
+use untron_program::migration::decode_state;
+use untron_program::{crypto, Action};
 use zksync_web3_rs::providers::{Middleware, Provider};
 use zksync_web3_rs::signers::{LocalWallet, Signer};
 use zksync_web3_rs::types::H160;
@@ -21,6 +28,8 @@ pub struct ZkSyncClient {
     pub wallet: ZKSWallet<Provider<Ws>, SigningKey>,
     pub contract: UntronCore<SignerMiddleware<Provider<Ws>, LocalWallet>>,
     pub usdt: USDT<SignerMiddleware<Provider<Ws>, LocalWallet>>,
+    // most blocks per eth_getLogs request
+    log_range: u64,
 }
 
 impl ZkSyncClient {
@@ -50,37 +59,115 @@ impl ZkSyncClient {
             wallet,
             contract,
             usdt,
+            log_range: config.log_range.max(1),
         })
     }
 
-    pub async fn start_listener(
+    // missed_actions returns the actions after `tip` up to `to_block`,
+    // reading the logs back from `to_block` until the action after `tip` is found
+    async fn missed_actions(
         &self,
-        pending_actions: Sender<Action>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let event = self.contract.event::<ActionChainUpdatedFilter>();
-        let mut action_chain_updates = event.subscribe().await?;
-
-        while let Some(Ok(event)) = action_chain_updates.next().await {
-            tracing::info!("Received ActionChainUpdated event: {:?}", event);
-
-            let action = Action {
-                prev: event.prev_order_id,
-                address: event.receiver.into(),
-                timestamp: event.timestamp.as_u64(),
-                min_deposit: event.min_deposit.as_u64(),
-                size: event.size.as_u64(),
-            };
-
-            if let Err(e) = pending_actions.send(action).await {
-                tracing::error!("Failed to send Action to pending_actions: {}", e);
+        tip: [u8; 32],
+        to_block: u64,
+    ) -> Result<Vec<Action>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut actions = vec![];
+        let mut end = to_block;
+        loop {
+            let start = end.saturating_sub(self.log_range - 1);
+            let mut range = self.action_logs(start, end).await?;
+            range.extend(actions);
+            actions = range;
+
+            if let Some(missed) = missed_actions(&actions, tip) {
+                return Ok(missed.to_vec());
             }
+            if start == 0 {
+                return Err(format!(
+                    "Action {} isn't in the Core's action chain",
+                    hex::encode(tip)
+                )
+                .into());
+            }
+            end = start - 1;
         }
+    }
 
-        Ok(())
+    // action_logs returns the actions emitted in blocks from..=to, in log_range requests
+    async fn action_logs(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<Action>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut actions = vec![];
+        let mut start = from;
+        while start <= to {
+            let end = to.min(start + self.log_range - 1);
+            let events = self
+                .contract
+                .event::<ActionChainUpdatedFilter>()
+                .from_block(start)
+                .to_block(end)
+                .query()
+                .await?;
+            actions.extend(events.iter().map(action));
+            start = end + 1;
+        }
+        Ok(actions)
+    }
+
+    pub async fn genesis_state(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(self.contract.genesis_state().call().await?.to_vec())
+    }
+
+    pub async fn state_hash(&self) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        Ok(self.contract.state_hash().call().await?)
+    }
+
+    // actions returns all actions of the Core, in order
+    pub async fn actions(&self) -> Result<Vec<Action>, Box<dyn std::error::Error>> {
+        let to_block = self.contract.client().get_block_number().await?.as_u64();
+        Ok(self.action_logs(0, to_block).await?)
+    }
+
+    // relays returns all relays (closeOrders calls) to the Core, in order,
+    // reading what they proved from the public values in their calldata, in log_range requests
+    pub async fn relays(&self) -> Result<Vec<Relay>, Box<dyn std::error::Error>> {
+        let to_block = self.contract.client().get_block_number().await?.as_u64();
+        let mut relays = vec![];
+        let mut start = 0;
+        while start <= to_block {
+            let end = to_block.min(start + self.log_range - 1);
+            let events = self
+                .contract
+                .event::<RelayUpdatedFilter>()
+                .from_block(start)
+                .to_block(end)
+                .query_with_meta()
+                .await?;
+            for (event, meta) in events {
+                let mut relay = self.relay(meta.transaction_hash).await?;
+                relay.state_hash = event.state_hash;
+                relays.push(relay);
+            }
+            start = end + 1;
+        }
+        Ok(relays)
+    }
+
+    // relay reads a relay from the calldata of its transaction
+    async fn relay(&self, tx_hash: H256) -> Result<Relay, Box<dyn std::error::Error>> {
+        let tx = self
+            .contract
+            .client()
+            .get_transaction(tx_hash)
+            .await?
+            .ok_or(format!("Relay transaction {:?} not found", tx_hash))?;
+        let call = CloseOrdersCall::decode(&tx.input)?;
+        Relay::from_public_values(&call.public_values)
     }
 
-    pub async fn vkey(&self) -> [u8; 32] {
-        self.contract.vkey().call().await.unwrap()
+    pub async fn gas_price(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(self.contract.client().get_gas_price().await?.as_u64())
     }
 
     pub async fn get_usdt_balance(&self) -> Result<u64, Box<dyn std::error::Error>> {
@@ -117,11 +204,14 @@ impl ZkSyncClient {
         Ok(())
     }
 
+    // close_orders sends a proof to the Core, once it passes the checks of validator.rs
     pub async fn close_orders(
         &self,
         proof: Vec<u8>,
         public_inputs: Vec<u8>,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        validate(self, &proof, &public_inputs).await?;
+
         self.contract
             .close_orders(Bytes::from(proof), Bytes::from(public_inputs))
             .send()
@@ -129,3 +219,115 @@ impl ZkSyncClient {
         Ok(())
     }
 }
+
+#[async_trait]
+impl CoreView for ZkSyncClient {
+    async fn state_hash(&self) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        ZkSyncClient::state_hash(self).await
+    }
+
+    // the latest relay is in the stateUpgradeBlock() block,
+    // which is the block of initialize() if there's no relay yet
+    async fn latest_block_id(&self) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        let block = self.contract.state_upgrade_block().call().await?.as_u64();
+        let events = self
+            .contract
+            .event::<RelayUpdatedFilter>()
+            .from_block(block)
+            .to_block(block)
+            .query_with_meta()
+            .await?;
+        match events.last() {
+            Some((_, meta)) => Ok(self.relay(meta.transaction_hash).await?.latest_block_id),
+            None => Ok(decode_state(&self.genesis_state().await?)?.latest_block_id),
+        }
+    }
+
+    async fn is_action(&self, action: [u8; 32]) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.contract.actions(action).call().await?)
+    }
+
+    async fn simulate(
+        &self,
+        proof: &[u8],
+        public_values: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.contract
+            .close_orders(
+                Bytes::from(proof.to_vec()),
+                Bytes::from(public_values.to_vec()),
+            )
+            .call()
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl VkeySource for ZkSyncClient {
+    async fn vkey(&self) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        Ok(self.contract.vkey().call().await?)
+    }
+}
+
+#[async_trait]
+impl ActionSource for ZkSyncClient {
+    async fn subscribe(
+        &self,
+    ) -> Result<ActionStream<'_>, Box<dyn std::error::Error + Send + Sync>> {
+        let filter = self.contract.event::<ActionChainUpdatedFilter>().filter;
+        let logs = self.contract.client_ref().subscribe_logs(&filter).await?;
+        Ok(logs
+            .map(
+                |log| -> Result<Action, Box<dyn std::error::Error + Send + Sync>> {
+                    let event = parse_log::<ActionChainUpdatedFilter>(log)?;
+                    Ok(action(&event))
+                },
+            )
+            .boxed())
+    }
+
+    // the missed actions are read from the logs back from the latest block,
+    // and must lead to the Core's actionChainTip() at that block
+    async fn missed(
+        &self,
+        tip: [u8; 32],
+    ) -> Result<Vec<Action>, Box<dyn std::error::Error + Send + Sync>> {
+        let to_block = self.contract.client().get_block_number().await?.as_u64();
+        let chain_tip = self
+            .contract
+            .action_chain_tip()
+            .block(to_block)
+            .call()
+            .await?;
+        if chain_tip == tip {
+            return Ok(vec![]);
+        }
+
+        let missed = self.missed_actions(tip, to_block).await?;
+        let missed_tip = missed
+            .last()
+            .map_or(tip, |action| crypto::hash(&action.abi_encode()));
+        if missed_tip != chain_tip {
+            return Err(format!(
+                "Action chain tip {} doesn't match the Core's {} at block {}",
+                hex::encode(missed_tip),
+                hex::encode(chain_tip),
+                to_block
+            )
+            .into());
+        }
+        Ok(missed)
+    }
+}
+
+// action converts an ActionChainUpdated event into the action for the program
+fn action(event: &ActionChainUpdatedFilter) -> Action {
+    Action {
+        prev: event.prev_order_id,
+        address: event.receiver.into(),
+        timestamp: event.timestamp.as_u64(),
+        min_deposit: event.min_deposit.as_u64(),
+        size: event.size.as_u64(),
+    }
+}